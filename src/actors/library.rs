@@ -0,0 +1,115 @@
+use std::collections::VecDeque;
+
+use chrono::Utc;
+
+use crate::identity::Identity;
+use crate::models::{ArtistData, CollectionDelta, Manifest};
+use crate::organizer::get_collection;
+
+/// Our own music collection on disk.
+pub struct Library {
+    dir: String,
+    /// manifest version, bumped whenever the collection changes. Starts at
+    /// the launch time so it keeps growing across restarts.
+    version: u64,
+    last: Option<Vec<ArtistData>>,
+    /// earlier versions, oldest first, to answer `changes_since`
+    history: VecDeque<(u64, Vec<ArtistData>)>,
+}
+
+/// versions kept for deltas, peers further behind get a snapshot
+const HISTORY_LEN: usize = 8;
+
+impl Library {
+    pub fn new(dir: &str) -> Self {
+        Library {
+            dir: dir.to_string(),
+            version: Utc::now().timestamp() as u64,
+            last: None,
+            history: VecDeque::new(),
+        }
+    }
+
+    pub fn get_collection(&self, track_data: bool, artist_filter: Option<&str>, album_filter: Option<&str>) -> Vec<ArtistData> {
+        get_collection(&self.dir, track_data, artist_filter, album_filter)
+    }
+
+    /// Our catalogue signed by `identity`.
+    pub fn manifest(&mut self, identity: &Identity) -> Manifest {
+        let artists = self.get_collection(false, None, None);
+        self.manifest_of(identity, artists)
+    }
+
+    fn manifest_of(&mut self, identity: &Identity, artists: Vec<ArtistData>) -> Manifest {
+        self.refresh(artists.clone());
+        Manifest::signed(identity, self.version, artists)
+    }
+
+    /// What changed in our catalogue since `version`, see `CollectionDelta`.
+    pub fn changes_since(&mut self, version: u64) -> CollectionDelta {
+        let artists = self.get_collection(false, None, None);
+        self.delta_of(version, artists)
+    }
+
+    fn delta_of(&mut self, since: u64, artists: Vec<ArtistData>) -> CollectionDelta {
+        self.refresh(artists.clone());
+        if since == self.version {
+            return CollectionDelta::between(since, &artists, since, &artists);
+        }
+        match self.history.iter().find(|(version, _)| *version == since) {
+            Some((_, old)) => CollectionDelta::between(since, old, self.version, &artists),
+            None => CollectionDelta::snapshot(self.version, artists),
+        }
+    }
+
+    /// bump the version if the collection changed
+    fn refresh(&mut self, artists: Vec<ArtistData>) {
+        if self.last.as_ref() == Some(&artists) {
+            return;
+        }
+        if let Some(last) = self.last.take() {
+            self.history.push_back((self.version, last));
+            if self.history.len() > HISTORY_LEN {
+                self.history.pop_front();
+            }
+        }
+        self.version += 1;
+        self.last = Some(artists);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest_version() {
+        let identity = Identity::from_pkcs8(&crate::signature::generate_bytes()).unwrap();
+        let mut library = Library::new("/nonexistent");
+        let first = library.manifest_of(&identity, vec![]);
+        assert_eq!(library.manifest_of(&identity, vec![]).version, first.version);
+        let changed = library.manifest_of(&identity, vec![ArtistData::new("a".to_string(), None)]);
+        assert_eq!(changed.version, first.version + 1);
+    }
+
+    #[test]
+    fn test_changes_since() {
+        let mut library = Library::new("/nonexistent");
+        let a = ArtistData::new("a".to_string(), None);
+        let b = ArtistData::new("b".to_string(), None);
+        let first = library.delta_of(0, vec![a.clone()]);
+        assert!(first.snapshot);
+
+        let delta = library.delta_of(first.version, vec![a.clone(), b.clone()]);
+        assert!(!delta.snapshot);
+        assert_eq!(delta.added, vec![b.clone()]);
+        assert_eq!(delta.since, first.version);
+        assert!(library.delta_of(delta.version, vec![a.clone(), b.clone()]).is_empty());
+
+        // too far behind
+        for i in 0..HISTORY_LEN {
+            library.delta_of(0, vec![ArtistData::new(i.to_string(), None)]);
+        }
+        assert!(library.delta_of(first.version, vec![a]).snapshot);
+    }
+}
@@ -0,0 +1,117 @@
+//! The node core is a handful of actors, each owning one piece of state
+//! on its own task. Handlers send closures to an actor and await the
+//! reply instead of locking shared state.
+
+mod registry;
+mod library;
+mod transfers;
+
+use std::error::Error;
+use std::fmt;
+use std::panic::{self, AssertUnwindSafe};
+
+use tokio::sync::{mpsc, oneshot};
+
+pub use self::registry::{Registry, Rx, Tx};
+pub use self::library::Library;
+pub use self::transfers::{Transfer, Transfers};
+
+type Job<S> = Box<dyn FnOnce(&mut S) + Send>;
+
+#[derive(Debug)]
+pub struct ActorError;
+
+impl fmt::Display for ActorError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "actor stopped")
+    }
+}
+
+impl Error for ActorError {}
+
+pub struct Actor<S> {
+    tx: mpsc::UnboundedSender<Job<S>>,
+}
+
+impl<S> Clone for Actor<S> {
+    fn clone(&self) -> Self {
+        Actor { tx: self.tx.clone() }
+    }
+}
+
+fn run_job<S>(job: Job<S>, state: &mut S) {
+    if panic::catch_unwind(AssertUnwindSafe(|| job(state))).is_err() {
+        println!("an actor job panicked, the caller gets an error");
+    }
+}
+
+impl<S: Send + 'static> Actor<S> {
+    /// Run the actor on a task, jobs should not block.
+    pub fn spawn(mut state: S) -> Self {
+        let (tx, mut rx) = mpsc::unbounded_channel::<Job<S>>();
+        tokio::spawn(async move {
+            while let Some(job) = rx.recv().await {
+                run_job(job, &mut state);
+            }
+        });
+        Actor { tx }
+    }
+
+    /// Run the actor on its own thread, for state that does slow file IO.
+    pub fn spawn_blocking(mut state: S) -> Self {
+        let (tx, mut rx) = mpsc::unbounded_channel::<Job<S>>();
+        tokio::task::spawn_blocking(move || {
+            while let Some(job) = futures::executor::block_on(rx.recv()) {
+                run_job(job, &mut state);
+            }
+        });
+        Actor { tx }
+    }
+
+    /// fire and forget
+    pub fn cast<F>(&self, f: F)
+    where
+        F: FnOnce(&mut S) + Send + 'static,
+    {
+        let _ = self.tx.send(Box::new(f));
+    }
+
+    /// run `f` on the actor's state and wait for the result
+    pub async fn call<F, R>(&self, f: F) -> Result<R, ActorError>
+    where
+        F: FnOnce(&mut S) -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (tx, rx) = oneshot::channel();
+        self.tx.send(Box::new(move |state: &mut S| {
+            let _ = tx.send(f(state));
+        })).map_err(|_| ActorError)?;
+        rx.await.map_err(|_| ActorError)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_call_and_cast() {
+        let actor = Actor::spawn(0u32);
+        actor.cast(|n| *n += 1);
+        assert_eq!(actor.call(|n| { *n += 1; *n }).await.unwrap(), 2);
+    }
+
+    #[tokio::test]
+    async fn test_panic_keeps_actor_alive() {
+        let actor = Actor::spawn(vec![1u32]);
+        assert!(actor.call(|v| v[10]).await.is_err());
+        assert_eq!(actor.call(|v| v[0]).await.unwrap(), 1);
+    }
+
+    #[tokio::test(threaded_scheduler)]
+    async fn test_blocking_actor() {
+        let actor = Actor::spawn_blocking(String::from("a"));
+        actor.cast(|s| s.push('b'));
+        assert_eq!(actor.call(|s| s.clone()).await.unwrap(), "ab");
+    }
+}
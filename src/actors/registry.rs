@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use tokio::sync::mpsc;
+
+use crate::codec::MessageEvent;
+
+pub type Tx = mpsc::UnboundedSender<MessageEvent>;
+pub type Rx = mpsc::UnboundedReceiver<MessageEvent>;
+
+/// Open connections, messages sent to a peer's `Tx` go out on its socket.
+/// A peer may hold several connections, they are counted by the address
+/// it advertises.
+pub struct Registry {
+    peers: HashMap<SocketAddr, Tx>,
+    /// socket address -> advertised address, once the peer said it
+    remotes: HashMap<SocketAddr, SocketAddr>,
+    /// open connections per advertised address
+    open: HashMap<SocketAddr, usize>,
+}
+
+impl Registry {
+    pub fn new() -> Self {
+        Registry {
+            peers: HashMap::new(),
+            remotes: HashMap::new(),
+            open: HashMap::new(),
+        }
+    }
+
+    pub fn register(&mut self, addr: SocketAddr, tx: Tx) {
+        self.peers.insert(addr, tx);
+    }
+
+    /// the connection from `addr` belongs to the peer at `remote`
+    pub fn identify(&mut self, addr: SocketAddr, remote: SocketAddr) {
+        match self.remotes.insert(addr, remote) {
+            Some(previous) if previous == remote => return,
+            Some(previous) => {
+                self.close(&previous);
+            },
+            None => {},
+        }
+        *self.open.entry(remote).or_insert(0) += 1;
+    }
+
+    /// Forget the connection from `addr`. Returns the peer's advertised
+    /// address if this was its last open connection.
+    pub fn deregister(&mut self, addr: &SocketAddr) -> Option<SocketAddr> {
+        self.peers.remove(addr);
+        let remote = self.remotes.remove(addr)?;
+        match self.close(&remote) {
+            true => Some(remote),
+            false => None,
+        }
+    }
+
+    /// one less connection to `remote`, true if none are left
+    fn close(&mut self, remote: &SocketAddr) -> bool {
+        match self.open.get_mut(remote) {
+            Some(count) if *count > 1 => {
+                *count -= 1;
+                false
+            },
+            _ => {
+                self.open.remove(remote);
+                true
+            },
+        }
+    }
+
+    pub fn send(&mut self, addr: &SocketAddr, message: MessageEvent) -> bool {
+        match self.peers.get(addr) {
+            Some(tx) => tx.send(message).is_ok(),
+            None => false,
+        }
+    }
+
+    pub fn broadcast(&mut self, message: &MessageEvent) {
+        for peer in self.peers.iter_mut() {
+            let _ = peer.1.send(message.clone());
+        }
+    }
+
+    pub fn addrs(&self) -> Vec<SocketAddr> {
+        self.peers.keys().cloned().collect()
+    }
+
+    pub fn len(&self) -> usize {
+        self.peers.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.peers.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv6Addr};
+
+    #[test]
+    fn test_broadcast() {
+        let addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 8000);
+        let mut registry = Registry::new();
+        let (tx, mut rx) = mpsc::unbounded_channel();
+        registry.register(addr, tx);
+        registry.broadcast(&MessageEvent::PeersRequest);
+        assert!(registry.send(&addr, MessageEvent::ArtistsRequest));
+        assert_eq!(rx.try_recv().unwrap(), MessageEvent::PeersRequest);
+        assert_eq!(rx.try_recv().unwrap(), MessageEvent::ArtistsRequest);
+
+        assert_eq!(registry.deregister(&addr), None);
+        assert!(registry.is_empty());
+        assert!(!registry.send(&addr, MessageEvent::ArtistsRequest));
+    }
+
+    #[test]
+    fn test_connection_counts() {
+        let socket = |i| SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, i)), 40000);
+        let remote = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 9)), 8000);
+        let mut registry = Registry::new();
+        for i in 1..=2 {
+            let (tx, _rx) = mpsc::unbounded_channel();
+            registry.register(socket(i), tx);
+            registry.identify(socket(i), remote);
+        }
+        // said again on every ping
+        registry.identify(socket(1), remote);
+
+        assert_eq!(registry.deregister(&socket(1)), None);
+        assert_eq!(registry.deregister(&socket(2)), Some(remote));
+        assert_eq!(registry.deregister(&socket(2)), None);
+    }
+}
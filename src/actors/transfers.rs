@@ -0,0 +1,126 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::Instant;
+
+use crate::storage::Db;
+use super::Actor;
+
+#[derive(Clone, Debug)]
+pub struct Transfer {
+    pub id: u64,
+    pub peer: SocketAddr,
+    pub artist: String,
+    pub album: String,
+    pub bytes: u64,
+    pub started: Instant,
+}
+
+/// Downloads in flight. Progress is reported back to the database so it
+/// counts towards the peer's reputation.
+///
+/// Nothing downloads files yet (`protocols` holds only stubs), so until
+/// that lands only tests drive this, no `ActiveTransfer` is ever tracked
+/// and `Reputation::score` leaves throughput and bad chunks out.
+pub struct Transfers {
+    active: HashMap<u64, Transfer>,
+    next_id: u64,
+    database: Actor<Db>,
+}
+
+impl Transfers {
+    pub fn new(database: Actor<Db>) -> Self {
+        Transfers {
+            active: HashMap::new(),
+            next_id: 0,
+            database,
+        }
+    }
+
+    pub fn start(&mut self, peer: SocketAddr, artist: &str, album: &str) -> u64 {
+        self.next_id += 1;
+        let (id, artist_name, album_name) = (self.next_id, artist.to_string(), album.to_string());
+        self.database.cast(move |db| {
+            if let Err(e) = db.transfer_started(id, peer, &artist_name, &album_name) {
+                println!("could not record transfer {}: {:?}", id, e);
+            }
+        });
+        self.active.insert(self.next_id, Transfer {
+            id: self.next_id,
+            peer,
+            artist: artist.to_string(),
+            album: album.to_string(),
+            bytes: 0,
+            started: Instant::now(),
+        });
+        self.next_id
+    }
+
+    pub fn progress(&mut self, id: u64, bytes: u64) {
+        if let Some(transfer) = self.active.get_mut(&id) {
+            transfer.bytes += bytes;
+            self.database.cast(move |db| db.transfer_progress(id, bytes));
+        }
+    }
+
+    pub fn bad_chunk(&mut self, id: u64) {
+        if let Some(transfer) = self.active.get(&id) {
+            let peer = transfer.peer;
+            self.database.cast(move |db| db.record_bad_chunk(&peer));
+        }
+    }
+
+    pub fn finish(&mut self, id: u64) -> Option<Transfer> {
+        let transfer = self.active.remove(&id)?;
+        let peer = transfer.peer;
+        let bytes = transfer.bytes;
+        let millis = transfer.started.elapsed().as_millis() as u64;
+        self.database.cast(move |db| {
+            db.record_transfer(&peer, bytes, millis);
+            if let Err(e) = db.transfer_finished(id) {
+                println!("could not record transfer {}: {:?}", id, e);
+            }
+        });
+        Some(transfer)
+    }
+
+    pub fn active(&self) -> Vec<Transfer> {
+        self.active.values().cloned().collect()
+    }
+
+    /// Finish the transfers still in progress so their partial progress
+    /// is recorded, returns how many there were. Nothing is kept to
+    /// resume them.
+    pub fn flush(&mut self) -> usize {
+        let ids: Vec<u64> = self.active.keys().cloned().collect();
+        for id in &ids {
+            self.finish(*id);
+        }
+        ids.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv6Addr};
+    use crate::models::Peer;
+
+    #[tokio::test]
+    async fn test_transfer_updates_reputation() {
+        let addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 8000);
+        let database = Actor::spawn(Db::new());
+        database.cast(move |db| db.add_peers(vec![Peer::new(addr, false, None, None, None)]));
+
+        let transfers = Actor::spawn(Transfers::new(database.clone()));
+        let id = transfers.call(move |t| t.start(addr, "artist", "album")).await.unwrap();
+        transfers.cast(move |t| t.progress(id, 1024));
+        transfers.cast(move |t| t.bad_chunk(id));
+        assert_eq!(transfers.call(|t| t.flush()).await.unwrap(), 1);
+        assert_eq!(transfers.call(|t| t.active().len()).await.unwrap(), 0);
+
+        // flush has queued its updates before we ask
+        let reputation = database.call(move |db| db.get_reputation(&addr)).await.unwrap();
+        assert_eq!(reputation.bytes_received, 1024);
+        assert_eq!(reputation.bad_chunks, 1);
+    }
+}
@@ -1,15 +1,79 @@
-use clap::{App, Arg};
+use std::time::Duration;
+
+use clap::{App, Arg, SubCommand};
 
 // TODO: pass initial peers list comma separated
 
+/// `trust` subcommand, edits the trust store and exits
+#[derive(Debug, Clone, PartialEq)]
+pub enum TrustCommand {
+    Add { public_key: String, petname: String },
+    Remove(String),
+    List,
+}
+
+/// `identity` subcommand, retires our key for a new one and exits
+#[derive(Debug, Clone, PartialEq)]
+pub enum IdentityCommand {
+    Rotate,
+    Revoke,
+}
+
+/// `invite` subcommand. Creating prints a code and exits, redeeming
+/// carries on to start the node and dial the invited peer.
+#[derive(Debug, Clone, PartialEq)]
+pub enum InviteCommand {
+    Create { addresses: Vec<String>, name: Option<String>, expires_hours: Option<i64> },
+    Redeem { code: String, petname: Option<String> },
+}
+
+/// `collection` subcommand, moves catalogues in and out of JSON or CSV
+/// files and exits
+#[derive(Debug, Clone, PartialEq)]
+pub enum CollectionCommand {
+    /// ours when no peer is given
+    Export { file: String, peer: Option<String> },
+    /// kept as the peer's collection until it sends a newer one
+    Import { file: String, peer: String },
+}
+
+/// How often the database's maintenance jobs run, see
+/// `storage::maintenance`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MaintenanceIntervals {
+    pub liveness: Duration,
+    pub reputation_decay: Duration,
+    pub index_rebuild: Duration,
+    pub checkpoint: Duration,
+}
+
+impl Default for MaintenanceIntervals {
+    fn default() -> Self {
+        MaintenanceIntervals {
+            liveness: Duration::from_secs(30),
+            reputation_decay: Duration::from_secs(24 * 60 * 60),
+            index_rebuild: Duration::from_secs(60 * 60),
+            checkpoint: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct Config {
     pub port: u16,
     pub config: String,
     pub peers: String,
     pub music: String,
+    pub identity: String,
     pub initial_peer: String,
     pub tui: bool,
+    /// only talk to peers in the trust store
+    pub friends_only: bool,
+    pub trust_command: Option<TrustCommand>,
+    pub identity_command: Option<IdentityCommand>,
+    pub invite_command: Option<InviteCommand>,
+    pub collection_command: Option<CollectionCommand>,
+    pub maintenance: MaintenanceIntervals,
 }
 
 impl Config {
@@ -19,8 +83,15 @@ impl Config {
             config: config.to_string(),
             peers: peers.to_string(),
             music: music.to_string(),
+            identity: "/tmp/identity.pk8".to_string(),
             initial_peer: "127.0.0.1:8081".to_string(),
             tui: false,
+            friends_only: false,
+            trust_command: None,
+            identity_command: None,
+            invite_command: None,
+            collection_command: None,
+            maintenance: MaintenanceIntervals::default(),
         }
     }
 }
@@ -51,12 +122,165 @@ pub fn get_args() -> Config {
             .value_name("DIRECTORY")
             .help("where your music collection lives")
             .takes_value(true))
+        .arg(Arg::with_name("identity")
+            .short("i")
+            .long("identity")
+            .value_name("FILE")
+            .help("PKCS#8 key file holding this node's identity, created if missing")
+            .takes_value(true))
+        .arg(Arg::with_name("friends-only")
+            .long("friends-only")
+            .help("only accept, serve and share peers in the trust store"))
+        .arg(Arg::with_name("liveness-secs")
+            .long("liveness-secs")
+            .value_name("SECONDS")
+            .help("how often quiet peers are marked offline, defaults to 30")
+            .takes_value(true))
+        .arg(Arg::with_name("reputation-decay-secs")
+            .long("reputation-decay-secs")
+            .value_name("SECONDS")
+            .help("how often peer reputations decay, defaults to a day")
+            .takes_value(true))
+        .arg(Arg::with_name("index-rebuild-secs")
+            .long("index-rebuild-secs")
+            .value_name("SECONDS")
+            .help("how often the index of who holds what is rebuilt, defaults to an hour")
+            .takes_value(true))
+        .arg(Arg::with_name("checkpoint-secs")
+            .long("checkpoint-secs")
+            .value_name("SECONDS")
+            .help("how often the database is saved, defaults to 5 minutes")
+            .takes_value(true))
+        .subcommand(SubCommand::with_name("trust")
+            .about("manage the trust store used by --friends-only, restart the node to apply")
+            .subcommand(SubCommand::with_name("add")
+                .about("trust a public key")
+                .arg(Arg::with_name("public_key").required(true).help("hex encoded Ed25519 public key"))
+                .arg(Arg::with_name("petname").required(true).help("your name for this contact")))
+            .subcommand(SubCommand::with_name("remove")
+                .about("stop trusting a contact")
+                .arg(Arg::with_name("name").required(true).help("petname, peer id or public key")))
+            .subcommand(SubCommand::with_name("list")
+                .about("list trusted contacts")))
+        .subcommand(SubCommand::with_name("identity")
+            .about("replace this node's key, peers learn of it when the node next starts")
+            .subcommand(SubCommand::with_name("rotate")
+                .about("move to a new key, signed off by the current one"))
+            .subcommand(SubCommand::with_name("revoke")
+                .about("the current key leaked, revoke it and move to a new one that contacts trust by hand")))
+        .subcommand(SubCommand::with_name("invite")
+            .about("introduce peers to each other with signed invite codes")
+            .subcommand(SubCommand::with_name("create")
+                .about("print an invite code for this node")
+                .arg(Arg::with_name("address")
+                    .short("a")
+                    .long("address")
+                    .value_name("IP:PORT")
+                    .help("where friends can reach us, may be repeated, defaults to 127.0.0.1:<port>")
+                    .takes_value(true)
+                    .multiple(true)
+                    .number_of_values(1))
+                .arg(Arg::with_name("name")
+                    .short("n")
+                    .long("name")
+                    .value_name("NAME")
+                    .help("name suggested to the friend")
+                    .takes_value(true))
+                .arg(Arg::with_name("expires")
+                    .short("e")
+                    .long("expires")
+                    .value_name("HOURS")
+                    .help("how long the code stays valid")
+                    .takes_value(true)))
+            .subcommand(SubCommand::with_name("redeem")
+                .about("trust and dial the peer behind an invite code, then run the node")
+                .arg(Arg::with_name("code").required(true))
+                .arg(Arg::with_name("petname")
+                    .long("petname")
+                    .value_name("NAME")
+                    .help("your name for this contact, defaults to the one in the code")
+                    .takes_value(true))))
+        .subcommand(SubCommand::with_name("collection")
+            .about("export or import catalogues as .json or .csv files")
+            .subcommand(SubCommand::with_name("export")
+                .about("write our library, or a peer's stored collection")
+                .arg(Arg::with_name("file").required(true).help("where to write, the extension picks the format"))
+                .arg(Arg::with_name("peer")
+                    .long("peer")
+                    .value_name("IP:PORT")
+                    .help("export this peer's collection instead of ours")
+                    .takes_value(true)))
+            .subcommand(SubCommand::with_name("import")
+                .about("store a file as the collection of a peer, e.g. one that is offline")
+                .arg(Arg::with_name("file").required(true).help(".json or .csv file to read"))
+                .arg(Arg::with_name("peer").required(true).help("IP:PORT of the peer it belongs to"))))
         .get_matches();
 
-    Config::new(
+    let mut config = Config::new(
         value_t!(matches, "port", u16).unwrap_or(8081u16),
         matches.value_of("config").unwrap_or("/tmp/thing.bin"),
         matches.value_of("peers").unwrap_or("/tmp/peers.bin"),
         matches.value_of("music").unwrap_or("/Users/user2/Documents/music"),
-    )
+    );
+    if let Some(identity) = matches.value_of("identity") {
+        config.identity = identity.to_string();
+    }
+    config.friends_only = matches.is_present("friends-only");
+    let intervals = &mut config.maintenance;
+    for (arg, interval) in vec![
+        ("liveness-secs", &mut intervals.liveness),
+        ("reputation-decay-secs", &mut intervals.reputation_decay),
+        ("index-rebuild-secs", &mut intervals.index_rebuild),
+        ("checkpoint-secs", &mut intervals.checkpoint),
+    ] {
+        if let Ok(secs) = value_t!(matches, arg, u64) {
+            *interval = Duration::from_secs(secs);
+        }
+    }
+    config.trust_command = matches.subcommand_matches("trust").map(|trust| {
+        match trust.subcommand() {
+            ("add", Some(add)) => TrustCommand::Add {
+                public_key: add.value_of("public_key").unwrap().to_string(),
+                petname: add.value_of("petname").unwrap().to_string(),
+            },
+            ("remove", Some(remove)) => TrustCommand::Remove(remove.value_of("name").unwrap().to_string()),
+            _ => TrustCommand::List,
+        }
+    });
+    config.invite_command = matches.subcommand_matches("invite").and_then(|invite| {
+        match invite.subcommand() {
+            ("create", Some(create)) => Some(InviteCommand::Create {
+                addresses: create.values_of("address")
+                    .map_or(vec![], |values| values.map(|v| v.to_string()).collect()),
+                name: create.value_of("name").map(|n| n.to_string()),
+                expires_hours: value_t!(create, "expires", i64).ok(),
+            }),
+            ("redeem", Some(redeem)) => Some(InviteCommand::Redeem {
+                code: redeem.value_of("code").unwrap().to_string(),
+                petname: redeem.value_of("petname").map(|n| n.to_string()),
+            }),
+            _ => None,
+        }
+    });
+    config.collection_command = matches.subcommand_matches("collection").and_then(|collection| {
+        match collection.subcommand() {
+            ("export", Some(export)) => Some(CollectionCommand::Export {
+                file: export.value_of("file").unwrap().to_string(),
+                peer: export.value_of("peer").map(|p| p.to_string()),
+            }),
+            ("import", Some(import)) => Some(CollectionCommand::Import {
+                file: import.value_of("file").unwrap().to_string(),
+                peer: import.value_of("peer").unwrap().to_string(),
+            }),
+            _ => None,
+        }
+    });
+    config.identity_command = matches.subcommand_matches("identity").and_then(|identity| {
+        match identity.subcommand_name() {
+            Some("rotate") => Some(IdentityCommand::Rotate),
+            Some("revoke") => Some(IdentityCommand::Revoke),
+            _ => None,
+        }
+    });
+    config
 }
@@ -1,4 +1,4 @@
-use bytes::{BytesMut, BufMut};
+use bytes::{Buf, BytesMut, BufMut};
 use std::str;
 use serde::{Deserialize, Serialize};
 use tokio_util::codec::{Decoder, Encoder};
@@ -6,16 +6,26 @@ use tokio_util::codec::{Decoder, Encoder};
 use crate::models::{
     ArtistData,
     AlbumData,
+    CollectionDelta,
+    Heartbeat,
+    KeyCertificate,
+    Manifest,
     Peer,
+    PeerId,
+    take_nstring,
     take_u64,
-    get_nstring,
 };
 use crate::consts::*;
 
+/// a type byte and the body length as a u32
+pub const FRAME_HEADER_LEN: usize = 5;
+/// longest frame body we buffer, anything longer is refused
+pub const MAX_FRAME_LEN: usize = 64 * 1024 * 1024;
+
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub enum MessageEvent {
-    Ping(Peer), // add user data
-    Pong(Peer), // add user data
+    Ping(Heartbeat, Peer),
+    Pong(Heartbeat, Peer, Vec<u8>), // echoes the Ping's heartbeat, signed, see `Heartbeat::challenge`
     Payload(String),
     Broadcast(String),
     RequestFile(ArtistData),
@@ -25,8 +35,40 @@ pub enum MessageEvent {
     AlbumResponse(AlbumData),
     PeersRequest,
     PeersResponse(Vec<Peer>),
+    ManifestRequest(PeerId), // the owner whose manifest we want
+    Manifest(Manifest),
+    KeyCertificate(KeyCertificate), // flooded to every peer
+    CollectionRequest(u64), // changes since this version, 0 for everything
+    CollectionDelta(CollectionDelta),
     Err(MessageCodecError),
     Ok,
+    Goodbye,
+}
+
+impl MessageEvent {
+    /// the message type byte used on the wire
+    pub fn kind(&self) -> Option<u8> {
+        match self {
+            MessageEvent::Ping(..) => Some(PING),
+            MessageEvent::Pong(..) => Some(PONG),
+            MessageEvent::Payload(_) => Some(PAYLOAD),
+            MessageEvent::RequestFile(_) => Some(REQUEST_FILE),
+            MessageEvent::ArtistsRequest => Some(ARTISTS_REQUEST),
+            MessageEvent::ArtistsResponse(_) => Some(ARTISTS_RESPONSE),
+            MessageEvent::AlbumRequest(_) => Some(ALBUM_REQUEST),
+            MessageEvent::AlbumResponse(_) => Some(ALBUM_RESPONSE),
+            MessageEvent::PeersRequest => Some(PEERS_REQUEST),
+            MessageEvent::PeersResponse(_) => Some(PEERS_RESPONSE),
+            MessageEvent::ManifestRequest(_) => Some(MANIFEST_REQUEST),
+            MessageEvent::Manifest(_) => Some(MANIFEST),
+            MessageEvent::KeyCertificate(_) => Some(KEY_CERTIFICATE),
+            MessageEvent::CollectionRequest(_) => Some(COLLECTION_REQUEST),
+            MessageEvent::CollectionDelta(_) => Some(COLLECTION_DELTA),
+            MessageEvent::Ok => Some(OK),
+            MessageEvent::Goodbye => Some(GOODBYE),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Clone, Debug, Deserialize, Serialize)]
@@ -42,6 +84,14 @@ impl From<std::io::Error> for MessageCodecError {
   }
 }
 
+impl std::fmt::Display for MessageCodecError {
+  fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+    write!(f, "{:?}", self)
+  }
+}
+
+impl std::error::Error for MessageCodecError {}
+
 impl PartialEq for MessageCodecError {
   fn eq(&self, other: &Self) -> bool {
     match (&self, &other) {
@@ -51,62 +101,110 @@ impl PartialEq for MessageCodecError {
   }
 }
 
-pub struct MessageCodec {}
+pub struct MessageCodec {
+    sent: u64,
+    received: u64,
+}
 
 impl MessageCodec {
     pub fn new() -> MessageCodec {
-        MessageCodec {}
+        MessageCodec {
+            sent: 0,
+            received: 0,
+        }
+    }
+
+    /// bytes written and read since the last call
+    pub fn take_traffic(&mut self) -> (u64, u64) {
+        let traffic = (self.sent, self.received);
+        self.sent = 0;
+        self.received = 0;
+        traffic
     }
 }
 
 impl Encoder for MessageCodec {
-  type Item = MessageEvent;
-  type Error = MessageCodecError;
+    type Item = MessageEvent;
+    type Error = MessageCodecError;
+
+    fn encode(&mut self, event: Self::Item, buf: &mut BytesMut) -> Result<(), Self::Error> {
+        let before = buf.len();
+        MessageCodec::encode_event(event, buf)?;
+        self.sent += (buf.len() - before) as u64;
+        Ok(())
+    }
+}
+
+impl Decoder for MessageCodec {
+    type Item = MessageEvent;
+    type Error = MessageCodecError;
+
+    fn decode(&mut self, src: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        let before = src.len();
+        let event = MessageCodec::decode_event(src)?;
+        self.received += (before - src.len()) as u64;
+        Ok(event)
+    }
+}
 
-  fn encode(&mut self, event: Self::Item, buf: &mut BytesMut) ->
-    Result<(), Self::Error> {
+impl MessageCodec {
+  /// Write `event` as its type byte, the body length and the body.
+  fn encode_event(event: MessageEvent, buf: &mut BytesMut) ->
+    Result<(), MessageCodecError> {
+        let kind = match event.kind() {
+            Some(kind) => kind,
+            None => {
+                println!("UNKNOWN!!!");
+                return Ok(());
+            },
+        };
+        let mut body = BytesMut::new();
+        MessageCodec::encode_body(event, &mut body);
+        if body.len() > MAX_FRAME_LEN {
+            return Err(MessageCodecError::DataLengthMismatch);
+        }
+        buf.reserve(FRAME_HEADER_LEN + body.len());
+        buf.put_u8(kind);
+        buf.put_u32(body.len() as u32);
+        buf.put(body);
+        Ok(())
+    }
+
+  fn encode_body(event: MessageEvent, buf: &mut BytesMut) {
         match event {
-            MessageEvent::Ping(peer) => {
-                buf.put_u8(PING);
+            MessageEvent::Ping(heartbeat, peer) => {
+                buf.extend_from_slice(&heartbeat.to_bytes()[..]);
                 buf.extend_from_slice(&peer.to_bytes()[..])
             },
-            MessageEvent::Pong(peer) => {
-                buf.put_u8(PONG);
-                buf.extend_from_slice(&peer.to_bytes()[..])
+            MessageEvent::Pong(heartbeat, peer, proof) => {
+                buf.extend_from_slice(&heartbeat.to_bytes()[..]);
+                buf.extend_from_slice(&peer.to_bytes()[..]);
+                buf.put_u64(proof.len() as u64);
+                buf.put(&proof[..]);
             },
             MessageEvent::Payload(message) => {
-                buf.put_u8(PAYLOAD);
                 let bytes = message.as_bytes();
                 buf.put_u64(bytes.clone().len() as u64);
                 buf.put(bytes);
             },
             MessageEvent::RequestFile(artist_data) => {
-                buf.put_u8(REQUEST_FILE);
                 buf.extend_from_slice(&artist_data.to_bytes()[..])
             },
-            MessageEvent::ArtistsRequest => {
-                buf.put_u8(ARTISTS_REQUEST);
-            },
+            MessageEvent::ArtistsRequest => {},
             MessageEvent::ArtistsResponse(artists) => {
-                buf.put_u8(ARTISTS_RESPONSE);
                 buf.put_u64(artists.len() as u64);
                 for artist in artists {
                     buf.extend_from_slice(&artist.to_bytes()[..]);
                 }
             },
             MessageEvent::AlbumRequest(album) => {
-                buf.put_u8(ALBUM_REQUEST);
                 buf.extend_from_slice(&mut album.to_bytes()[..]);
             },
             MessageEvent::AlbumResponse(album) => {
-                buf.put_u8(ALBUM_RESPONSE);
                 buf.extend_from_slice(&mut album.to_bytes()[..]);
             },
-            MessageEvent::PeersRequest => {
-                buf.put_u8(PEERS_REQUEST);
-            },
+            MessageEvent::PeersRequest => {},
             MessageEvent::PeersResponse(peers) => {
-                buf.put_u8(PEERS_RESPONSE);
                 buf.put_u64(peers.len() as u64);
                 for peer in peers {
                     let bytes = peer.to_bytes();
@@ -114,90 +212,176 @@ impl Encoder for MessageCodec {
                     buf.extend_from_slice(&bytes[..]);
                 };
             },
-            _ => println!("UNKNOWN!!!"),
+            MessageEvent::ManifestRequest(owner) => {
+                buf.put(owner.as_bytes());
+            },
+            MessageEvent::Manifest(manifest) => {
+                buf.extend_from_slice(&manifest.to_bytes()[..]);
+            },
+            MessageEvent::KeyCertificate(certificate) => {
+                buf.extend_from_slice(&certificate.to_bytes()[..]);
+            },
+            MessageEvent::CollectionRequest(since) => {
+                buf.put_u64(since);
+            },
+            MessageEvent::CollectionDelta(delta) => {
+                buf.extend_from_slice(&delta.to_bytes()[..]);
+            },
+            _ => {},
         }
-        Ok(())
     }
 }
 
 
-impl Decoder for MessageCodec {
-    type Item = MessageEvent;
-    type Error = MessageCodecError;
-
-    fn decode(&mut self, src: &mut BytesMut) ->
-        Result<Option<Self::Item>, Self::Error> {
-            let len = src.len();
-            if len == 0 {
+impl MessageCodec {
+    /// Nothing is consumed until the whole frame is buffered, a frame that
+    /// does not decode is dropped so the next one can be read.
+    fn decode_event(src: &mut BytesMut) ->
+        Result<Option<MessageEvent>, MessageCodecError> {
+            if src.len() < FRAME_HEADER_LEN {
                 return Ok(None);
             }
+            let body_len = u32::from_be_bytes([src[1], src[2], src[3], src[4]]) as usize;
+            if body_len > MAX_FRAME_LEN {
+                src.clear();
+                return Err(MessageCodecError::DataLengthMismatch);
+            }
+            if src.len() < FRAME_HEADER_LEN + body_len {
+                src.reserve(FRAME_HEADER_LEN + body_len - src.len());
+                return Ok(None);
+            }
+            let byte = src[0];
+            src.advance(FRAME_HEADER_LEN);
+            let mut body = src.split_to(body_len);
+            let event = MessageCodec::decode_body(byte, &mut body)?;
+            if !body.is_empty() {
+                return Err(MessageCodecError::DataLengthMismatch);
+            }
+            Ok(Some(event))
+    }
 
-            // TODO: validate data length
-            let byte = src.split_to(1)[0];
-
+    fn decode_body(byte: u8, src: &mut BytesMut) ->
+        Result<MessageEvent, MessageCodecError> {
             match byte {
                 PING => {
-                    let peer = Peer::from_bytes(src);
-                    return Ok(Some(MessageEvent::Ping(peer)));
+                    let heartbeat = Heartbeat::from_bytes(src)
+                        .map_err(|_| MessageCodecError::SerializationError)?;
+                    let peer = Peer::from_bytes(src)
+                        .map_err(|_| MessageCodecError::SerializationError)?;
+                    return Ok(MessageEvent::Ping(heartbeat, peer));
                 },
                 PONG => {
-                    let peer = Peer::from_bytes(src);
-                    return Ok(Some(MessageEvent::Pong(peer)));
+                    let heartbeat = Heartbeat::from_bytes(src)
+                        .map_err(|_| MessageCodecError::SerializationError)?;
+                    let peer = Peer::from_bytes(src)
+                        .map_err(|_| MessageCodecError::SerializationError)?;
+                    let proof_len = take_u64(src)
+                        .map_err(|_| MessageCodecError::SerializationError)? as usize;
+                    if src.len() < proof_len {
+                        return Err(MessageCodecError::DataLengthMismatch);
+                    }
+                    let proof = src.split_to(proof_len).to_vec();
+                    return Ok(MessageEvent::Pong(heartbeat, peer, proof));
                 },
                 PAYLOAD => {
-                    let data_len = take_u64(src).unwrap() as usize;
-                    let message = get_nstring(src, data_len).unwrap();
-                    return Ok(Some(MessageEvent::Payload(message)));
+                    let data_len = take_u64(src)
+                        .map_err(|_| MessageCodecError::SerializationError)? as usize;
+                    let message = take_nstring(src, data_len)
+                        .map_err(|_| MessageCodecError::DataLengthMismatch)?
+                        .unwrap_or_default();
+                    return Ok(MessageEvent::Payload(message));
                 },
                 REQUEST_FILE => {
-                    return Ok(Some(MessageEvent::RequestFile(
+                    return Ok(MessageEvent::RequestFile(
                         ArtistData::from_bytes(src)
-                    )));
+                            .map_err(|_| MessageCodecError::SerializationError)?
+                    ));
                 },
                 ARTISTS_REQUEST => {
-                    return Ok(Some(MessageEvent::ArtistsRequest));
+                    return Ok(MessageEvent::ArtistsRequest);
                 },
                 ARTISTS_RESPONSE => {
-                    let mut artist_count = take_u64(src).unwrap() as usize;
+                    let mut artist_count = take_u64(src)
+                        .map_err(|_| MessageCodecError::SerializationError)? as usize;
                     let mut artist_vec: Vec<ArtistData> = vec![];
                     while artist_count > 0 {
-                        let artist = ArtistData::from_bytes(src);
+                        let artist = ArtistData::from_bytes(src)
+                            .map_err(|_| MessageCodecError::SerializationError)?;
                         artist_vec.push(artist);
                         artist_count -= 1;
                     }
-                    return Ok(Some(MessageEvent::ArtistsResponse(artist_vec)));
+                    return Ok(MessageEvent::ArtistsResponse(artist_vec));
                 },
                 ALBUM_REQUEST => {
-                    return Ok(Some(MessageEvent::AlbumRequest(
+                    return Ok(MessageEvent::AlbumRequest(
                         AlbumData::from_bytes(src)
-                    )));
+                            .map_err(|_| MessageCodecError::SerializationError)?
+                    ));
                 },
                 ALBUM_RESPONSE => {
-                    return Ok(Some(MessageEvent::AlbumRequest(
+                    return Ok(MessageEvent::AlbumRequest(
                         AlbumData::from_bytes(src)
-                    )));
+                            .map_err(|_| MessageCodecError::SerializationError)?
+                    ));
                 },
                 PEERS_REQUEST => {
-                    return Ok(Some(MessageEvent::PeersRequest));
+                    return Ok(MessageEvent::PeersRequest);
                 },
                 PEERS_RESPONSE => {
                     // TODO: parse vector into bytes
-                    let mut peer_count = take_u64(src).unwrap() as usize;
+                    let mut peer_count = take_u64(src)
+                        .map_err(|_| MessageCodecError::SerializationError)? as usize;
                     let mut peer_vec: Vec<Peer> = vec![];
                     while peer_count > 0 {
-                        let peer: Peer = Peer::from_bytes(src);
+                        // each record is prefixed with its length, see `encode`
+                        let _peer_len = take_u64(src)
+                            .map_err(|_| MessageCodecError::SerializationError)?;
+                        let peer: Peer = Peer::from_bytes(src)
+                            .map_err(|_| MessageCodecError::SerializationError)?;
                         peer_vec.push(peer);
                         peer_count -= 1;
                     }
 
-                    return Ok(Some(MessageEvent::PeersResponse(peer_vec)));
+                    return Ok(MessageEvent::PeersResponse(peer_vec));
                 },
                 OK => {
-                    return Ok(Some(MessageEvent::Ok))
+                    return Ok(MessageEvent::Ok)
+                },
+                GOODBYE => {
+                    return Ok(MessageEvent::Goodbye)
+                },
+                MANIFEST_REQUEST => {
+                    if src.len() < 16 {
+                        return Err(MessageCodecError::DataLengthMismatch);
+                    }
+                    let owner = PeerId::from_bytes(&src.split_to(16))
+                        .ok_or(MessageCodecError::SerializationError)?;
+                    return Ok(MessageEvent::ManifestRequest(owner))
+                },
+                MANIFEST => {
+                    let manifest = Manifest::from_bytes(src)
+                        .map_err(|_| MessageCodecError::SerializationError)?;
+                    return Ok(MessageEvent::Manifest(manifest))
+                },
+                KEY_CERTIFICATE => {
+                    let certificate = KeyCertificate::from_bytes(src)
+                        .map_err(|_| MessageCodecError::SerializationError)?;
+                    return Ok(MessageEvent::KeyCertificate(certificate))
                 },
-                _ => {}
+                COLLECTION_REQUEST => {
+                    let since = take_u64(src)
+                        .map_err(|_| MessageCodecError::SerializationError)?;
+                    return Ok(MessageEvent::CollectionRequest(since))
+                },
+                COLLECTION_DELTA => {
+                    let delta = CollectionDelta::from_bytes(src)
+                        .map_err(|_| MessageCodecError::SerializationError)?;
+                    return Ok(MessageEvent::CollectionDelta(delta))
+                },
+                _ => {
+                    return Err(MessageCodecError::SerializationError);
+                }
             }
-            Ok(None)
     }
 }
 
@@ -208,6 +392,72 @@ mod tests {
     use crate::models::TrackData;
     use std::net::{IpAddr, Ipv6Addr, SocketAddr};
 
+    fn frame(kind: u8, body: BytesMut) -> BytesMut {
+        let mut b = BytesMut::new();
+        b.put_u8(kind);
+        b.put_u32(body.len() as u32);
+        b.put(body);
+        b
+    }
+
+    #[test]
+    fn test_traffic() {
+        let mut codec = MessageCodec::new();
+        let mut buf = BytesMut::new();
+        codec.encode(MessageEvent::Goodbye, &mut buf).unwrap();
+        codec.encode(MessageEvent::CollectionRequest(3), &mut buf).unwrap();
+        let sent = buf.len() as u64;
+        codec.decode(&mut buf).unwrap().unwrap();
+        assert_eq!(codec.take_traffic(), (sent, FRAME_HEADER_LEN as u64));
+        assert_eq!(codec.take_traffic(), (0, 0));
+    }
+
+    #[test]
+    fn test_truncated_frames() {
+        let artists = vec![ArtistData::new("a".to_string(), None)];
+        let messages = vec![
+            MessageEvent::Payload(String::from("hello world")),
+            MessageEvent::ArtistsResponse(artists),
+        ];
+        for message in messages {
+            let mut full = BytesMut::new();
+            MessageCodec::new().encode(message, &mut full).unwrap();
+            // a frame split across reads waits for the rest
+            for cut in 0..full.len() {
+                let mut truncated = BytesMut::from(&full[..cut]);
+                assert_eq!(MessageCodec::new().decode(&mut truncated).unwrap(), None, "cut at {}", cut);
+                assert_eq!(truncated[..], full[..cut]);
+            }
+            let mut codec = MessageCodec::new();
+            let mut partial = BytesMut::from(&full[..full.len() / 2]);
+            assert_eq!(codec.decode(&mut partial).unwrap(), None);
+            partial.extend_from_slice(&full[full.len() / 2..]);
+            assert!(codec.decode(&mut partial).unwrap().is_some());
+            assert!(partial.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_bad_frames() {
+        // a body that does not decode drops only its own frame
+        let mut b = frame(COLLECTION_REQUEST, BytesMut::from(&[1, 2, 3][..]));
+        MessageCodec::new().encode(MessageEvent::Goodbye, &mut b).unwrap();
+        assert!(MessageCodec::new().decode(&mut b).is_err());
+        assert_eq!(MessageCodec::new().decode(&mut b).unwrap(), Some(MessageEvent::Goodbye));
+
+        // as does one with bytes left over
+        let mut body = BytesMut::new();
+        body.put_u64(3);
+        body.put_u8(0);
+        assert!(MessageCodec::new().decode(&mut frame(COLLECTION_REQUEST, body)).is_err());
+
+        let mut b = BytesMut::new();
+        b.put_u8(PAYLOAD);
+        b.put_u32(MAX_FRAME_LEN as u32 + 1);
+        assert!(MessageCodec::new().decode(&mut b).is_err());
+        assert!(b.is_empty());
+    }
+
     #[test]
     fn test_serialize_album_request() {
         let mut res = BytesMut::new();
@@ -217,8 +467,8 @@ mod tests {
             0,
             Some(vec![TrackData::new("test".to_string(), 12_000, 250)]),
         ));
-        MessageCodec{}.encode(album_request.clone(), &mut res).unwrap();
-        let left = MessageCodec{}.decode(&mut res).unwrap().unwrap();
+        MessageCodec::new().encode(album_request.clone(), &mut res).unwrap();
+        let left = MessageCodec::new().decode(&mut res).unwrap().unwrap();
         assert_eq!(left, album_request);
     }
 
@@ -237,48 +487,99 @@ mod tests {
     #[test]
     fn test_encode_ping() {
         let localhost_v6 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 8000);
+        let heartbeat = Heartbeat { nonce: 42, sent_at: 1_500_000_000_000 };
         let mut b = BytesMut::new();
-        b.put_u8(PING);
+        b.put_u64(42);
+        b.put_u64(1_500_000_000_000);
         b.put_u64(18);
         b.put_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
         b.put_u16(8000);
         b.put_u8(0);
-        b.put_u8(0);
-        b.put_u8(0);
-        b.put_u8(0);
-        assert_eq!(MessageCodec{}.decode(&mut b).unwrap(), Some(MessageEvent::Ping(Peer::new(localhost_v6, false, None, None, None))));
+        b.put_u64(0);
+        b.put_u32(0);
+        b.put_u32(0);
+        b.put_u32(0);
+        let mut b = frame(PING, b);
+        assert_eq!(MessageCodec::new().decode(&mut b).unwrap(), Some(MessageEvent::Ping(heartbeat, Peer::new(localhost_v6, false, None, None, None))));
     }
 
     #[test]
     fn test_encode_pong() {
         let localhost_v6 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 8000);
+        let heartbeat = Heartbeat { nonce: 42, sent_at: 1_500_000_000_000 };
         let mut b = BytesMut::new();
-        b.put_u8(PONG);
+        b.put_u64(42);
+        b.put_u64(1_500_000_000_000);
         b.put_u64(18);
         b.put_slice(&[0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
         b.put_u16(8000);
         b.put_u8(0);
-        b.put_u8(0);
-        b.put_u8(0);
-        b.put_u8(0);
-        assert_eq!(MessageCodec{}.decode(&mut b).unwrap(), Some(MessageEvent::Pong(Peer::new(localhost_v6, false, None, None, None))));
+        b.put_u64(0);
+        b.put_u32(0);
+        b.put_u32(0);
+        b.put_u32(0);
+        b.put_u64(2);
+        b.put_slice(&[7, 8]);
+        let mut b = frame(PONG, b);
+        assert_eq!(MessageCodec::new().decode(&mut b).unwrap(), Some(MessageEvent::Pong(heartbeat, Peer::new(localhost_v6, false, None, None, None), vec![7, 8])));
     }
 
     #[test]
     fn test_encode_payload() {
         let mut b = BytesMut::new();
-        b.put_u8(PAYLOAD);
         b.put_u64(12);
         b.put(&b"hello world\0"[..]);
-        assert_eq!(MessageCodec{}.decode(&mut b).unwrap(), Some(MessageEvent::Payload(String::from("hello world"))));
+        let mut b = frame(PAYLOAD, b);
+        assert_eq!(MessageCodec::new().decode(&mut b).unwrap(), Some(MessageEvent::Payload(String::from("hello world"))));
 
         let mut res = BytesMut::new();
-        MessageCodec{}.encode(MessageEvent::Payload(String::from("hello world\0")), &mut res).unwrap();
+        MessageCodec::new().encode(MessageEvent::Payload(String::from("hello world\0")), &mut res).unwrap();
 
         let mut b = BytesMut::new();
         b.put_u8(PAYLOAD);
+        b.put_u32(20);
         b.put_u64(12);
         b.put(&b"hello world\0"[..]);
         assert_eq!(res[..], b[..]);
     }
+
+    #[test]
+    fn test_unknown_message_type() {
+        let mut body = BytesMut::new();
+        body.put_u64(12);
+        let mut b = frame(0x01, body);
+        assert!(MessageCodec::new().decode(&mut b).is_err());
+        assert_eq!(b.len(), 0);
+    }
+
+    #[test]
+    fn test_peers_response() {
+        let addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 8000);
+        let peers = vec![
+            Peer::new(addr, true, Some("a".to_string()), None, None),
+            Peer::new(addr, false, None, None, None),
+        ];
+        let mut b = BytesMut::new();
+        MessageCodec::new().encode(MessageEvent::PeersResponse(peers.clone()), &mut b).unwrap();
+        assert_eq!(MessageCodec::new().decode(&mut b).unwrap(), Some(MessageEvent::PeersResponse(peers)));
+        assert_eq!(b.len(), 0);
+    }
+
+    #[test]
+    fn test_collection_delta() {
+        let delta = CollectionDelta::snapshot(7, vec![ArtistData::new("a".to_string(), None)]);
+        let mut b = BytesMut::new();
+        MessageCodec::new().encode(MessageEvent::CollectionRequest(6), &mut b).unwrap();
+        MessageCodec::new().encode(MessageEvent::CollectionDelta(delta.clone()), &mut b).unwrap();
+        assert_eq!(MessageCodec::new().decode(&mut b).unwrap(), Some(MessageEvent::CollectionRequest(6)));
+        assert_eq!(MessageCodec::new().decode(&mut b).unwrap(), Some(MessageEvent::CollectionDelta(delta)));
+    }
+
+    #[test]
+    fn test_manifest_request() {
+        let owner = PeerId::from_public_key(b"owner");
+        let mut b = BytesMut::new();
+        MessageCodec::new().encode(MessageEvent::ManifestRequest(owner), &mut b).unwrap();
+        assert_eq!(MessageCodec::new().decode(&mut b).unwrap(), Some(MessageEvent::ManifestRequest(owner)));
+    }
 }
@@ -10,3 +10,9 @@ pub const ARTISTS_RESPONSE: u8   = 0xF8;
 pub const PEERS_REQUEST: u8      = 0xF9;
 pub const PEERS_RESPONSE: u8     = 0xFA;
 pub const OK: u8                 = 0xFB;
+pub const GOODBYE: u8            = 0xFC;
+pub const MANIFEST_REQUEST: u8   = 0xFD;
+pub const MANIFEST: u8           = 0xFE;
+pub const KEY_CERTIFICATE: u8    = 0xEF;
+pub const COLLECTION_REQUEST: u8 = 0xEE;
+pub const COLLECTION_DELTA: u8   = 0xED;
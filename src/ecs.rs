@@ -1,6 +1,7 @@
-use std::collections::{HashMap, HashSet};
+use std::collections::HashMap;
+use std::hash::Hash;
 use std::marker::PhantomData;
-use std::net::SocketAddr;
+use std::mem;
 
 use hibitset::BitSetLike;
 use shrev::EventChannel;
@@ -17,10 +18,17 @@ pub enum NodeEvent {
     Removed(Entity),
 }
 
-pub struct WorldState<P> {
-    sorted: Vec<Entity>,
-    addresses: HashMap<SocketAddr, usize>,
-    entities: HashMap<Index, usize>,
+/// Tracks the entities holding a `P`, by key. Every operation is O(1)
+/// amortized: `nodes` is kept dense by swap-removal and `positions` says
+/// where each entity sits in it.
+pub struct WorldState<P: Node> {
+    nodes: Vec<Entity>,
+    keys: HashMap<P::Key, Entity>,
+    /// secondary key -> entity, the newest node wins a shared key
+    secondary: HashMap<P::Key, Entity>,
+    /// the keys each entity was indexed under, to unindex it by
+    indexed: HashMap<Index, (P::Key, Vec<P::Key>)>,
+    positions: HashMap<Index, usize>,
 
     changed: EventChannel<NodeEvent>,
     reader_id: ReaderId<ComponentEvent>,
@@ -28,21 +36,23 @@ pub struct WorldState<P> {
     modified: BitSet,
     inserted: BitSet,
     removed: BitSet,
-    scratch_set: HashSet<Entity>,
+    scratch: Vec<Entity>,
 
     _phantom: PhantomData<P>,
 }
 
-impl<P> WorldState<P> {
+impl<P: Node> WorldState<P> {
     pub fn new(reader_id: ReaderId<ComponentEvent>) -> Self
     where
         P: Component,
         P::Storage: Tracked,
     {
         WorldState {
-            sorted: Vec::new(),
-            addresses: HashMap::new(),
-            entities: HashMap::new(),
+            nodes: Vec::new(),
+            keys: HashMap::new(),
+            secondary: HashMap::new(),
+            indexed: HashMap::new(),
+            positions: HashMap::new(),
             changed: EventChannel::new(),
 
             reader_id,
@@ -50,22 +60,72 @@ impl<P> WorldState<P> {
             inserted: BitSet::new(),
             removed: BitSet::new(),
 
-            scratch_set: HashSet::default(),
+            scratch: Vec::new(),
 
             _phantom: PhantomData,
         }
     }
 
-    pub fn get_entity(&self, addr: &SocketAddr) -> Option<Entity> {
-        if let Some(ix) = self.addresses.get(addr) {
-            Some(self.sorted[*ix])
-        } else {
-            None
+    /// the node with `key` as its primary key, or failing that as one of
+    /// its secondary keys
+    pub fn get_entity(&self, key: &P::Key) -> Option<Entity> {
+        self.keys.get(key).or_else(|| self.secondary.get(key)).copied()
+    }
+
+    /// the node with `key` as its primary key only
+    pub fn get_primary(&self, key: &P::Key) -> Option<Entity> {
+        self.keys.get(key).copied()
+    }
+
+    fn index(&mut self, entity: Entity, node: &P) {
+        let key = node.key();
+        let secondary = node.secondary_keys();
+        for other in &secondary {
+            self.secondary.insert(other.clone(), entity);
         }
+        self.keys.insert(key.clone(), entity);
+        self.indexed.insert(entity.id(), (key, secondary));
     }
 
+    /// drop the keys `entity` was indexed under, unless a newer node took them
+    fn unindex(&mut self, entity: Entity) {
+        let (key, secondary) = match self.indexed.remove(&entity.id()) {
+            Some(keys) => keys,
+            None => return,
+        };
+        if self.keys.get(&key) == Some(&entity) {
+            self.keys.remove(&key);
+        }
+        for other in secondary {
+            if self.secondary.get(&other) == Some(&entity) {
+                self.secondary.remove(&other);
+            }
+        }
+    }
+
+    /// every tracked entity, in no particular order
     pub fn all(&self) -> &[Entity] {
-        self.sorted.as_slice()
+        self.nodes.as_slice()
+    }
+
+    /// the entity whose node was tracked under `index`, if it still is
+    fn tracked(&self, index: Index) -> Option<Entity> {
+        self.positions.get(&index).map(|position| self.nodes[*position])
+    }
+
+    fn push(&mut self, entity: Entity) {
+        self.positions.insert(entity.id(), self.nodes.len());
+        self.nodes.push(entity);
+    }
+
+    /// swap the last entity into the hole left by `entity`
+    fn swap_remove(&mut self, entity: Entity) {
+        if let Some(position) = self.positions.remove(&entity.id()) {
+            self.nodes.swap_remove(position);
+            if let Some(moved) = self.nodes.get(position) {
+                self.positions.insert(moved.id(), position);
+            }
+        }
     }
 
     /// Get a token for tracking the modification events from the WorldState
@@ -110,81 +170,78 @@ impl<P> WorldState<P> {
 
         // bump duplicates
         for (_entity, _, node) in (&*entities, &self.inserted, &nodes).join() {
-            if let Some(ix) = self.addresses.get(&node.addr()) {
-                let other_entity = self.sorted[ix.clone()];
+            if let Some(other_entity) = self.keys.get(&node.key()) {
                 self.removed.add(other_entity.id());
             }
         }
 
-        // process removed components
-        self.scratch_set.clear();
-        for id in (&self.removed).iter() {
-            if let Some(index) = self.entities.get(&id) {
-                self.scratch_set.insert(self.sorted[*index]);
-            }
-        }
-
-        // do removal
-        if !self.scratch_set.is_empty() {
-            let mut i = 0;
-            let mut min_index = std::usize::MAX;
-            while i < self.sorted.len() {
-                let entity = self.sorted[i];
-                let remove = self.scratch_set.contains(&entity);
-                if remove {
-                    if i < min_index {
-                        min_index = i;
+        // nodes changed in place may have new keys
+        let modified = mem::take(&mut self.modified);
+        for (entity, _, node) in (&*entities, &modified, &nodes).join() {
+            if self.positions.contains_key(&entity.id()) && !self.removed.contains(entity.id()) {
+                // and bump the node that held the new key, as an insert would
+                if let Some(other_entity) = self.keys.get(&node.key()) {
+                    if *other_entity != entity {
+                        self.removed.add(other_entity.id());
                     }
-                    self.scratch_set.insert(entity);
-                    self.sorted.remove(i);
-                    self.entities.remove(&entity.id());
-                } else {
-                    i += 1;
                 }
+                self.unindex(entity);
+                self.index(entity, node);
             }
-            for i in min_index..self.sorted.len() {
-                self.entities.insert(self.sorted[i].id(), i);
-            }
-            for entity in &self.scratch_set {
-                self.changed.single_write(NodeEvent::Removed(*entity));
+        }
+        self.modified = modified;
+
+        // removals are announced by ascending entity id
+        self.scratch.clear();
+        for id in (&self.removed).iter() {
+            if let Some(entity) = self.tracked(id) {
+                self.scratch.push(entity);
             }
         }
-        for (_, _, node) in (&*entities, &self.removed, &nodes).join() {
-            self.addresses.remove(&node.addr());
+        let removed = mem::take(&mut self.scratch);
+        for entity in &removed {
+            self.swap_remove(*entity);
+            self.changed.single_write(NodeEvent::Removed(*entity));
+            // deleted entities no longer have a node to look the keys up by
+            self.unindex(*entity);
         }
-        self.scratch_set.clear();
+        self.scratch = removed;
 
-        for (entity, _, node) in (&*entities, &self.inserted, &nodes).join() {
-            let insert_index = self.sorted.len();
-            self.entities.insert(entity.id(), insert_index);
-            self.sorted.push(entity);
-            self.scratch_set.insert(entity);
+        // then nodes bumped by another inserted this run, then the inserts
+        self.scratch.clear();
+        let inserted = mem::take(&mut self.inserted);
+        for (entity, _, node) in (&*entities, &inserted, &nodes).join() {
+            self.push(entity);
+            self.scratch.push(entity);
 
-            if let Some(ix) = self.addresses.get(&node.addr()) {
-                let other_entity = self.sorted[ix.clone()];
+            if let Some(other_entity) = self.keys.get(&node.key()) {
                 if !self.removed.contains(other_entity.id()) {
-                    self.changed.single_write(NodeEvent::Removed(other_entity));
+                    self.changed.single_write(NodeEvent::Removed(*other_entity));
                 }
             }
-            self.addresses.insert(node.addr(), insert_index);
+            self.index(entity, node);
         }
+        self.inserted = inserted;
 
-        if !self.scratch_set.is_empty() {
-            for i in 0..self.sorted.len() {
-                let entity = self.sorted[i];
-                let notify = self.scratch_set.contains(&entity);
-                if notify {
-                    self.scratch_set.insert(entity);
-                    self.changed.single_write(NodeEvent::Modified(entity));
-                }
-            }
+        for entity in &self.scratch {
+            self.changed.single_write(NodeEvent::Modified(*entity));
         }
-        self.scratch_set.clear();
+        self.scratch.clear();
     }
 }
 
+/// Something `WorldState` keeps one entity per key of, such as a peer
+/// by id. A newer node with the same primary key replaces the older.
 pub trait Node {
-    fn addr(&self) -> SocketAddr;
+    type Key: Hash + Eq + Clone + Send + Sync + 'static;
+
+    fn key(&self) -> Self::Key;
+
+    /// more keys the node can be looked up by, e.g. other addresses it is
+    /// reachable at. They do not replace other nodes.
+    fn secondary_keys(&self) -> Vec<Self::Key> {
+        Vec::new()
+    }
 }
 
 #[derive(SystemData)]
@@ -235,7 +292,7 @@ where
 mod tests {
 
     use super::*;
-    use std::net::{IpAddr, Ipv6Addr};
+    use std::net::{IpAddr, Ipv6Addr, SocketAddr};
     use super::Node as NNode;
     use specs::prelude::{DenseVecStorage, FlaggedStorage};
     use specs::world::Builder;
@@ -257,7 +314,9 @@ mod tests {
     }
 
     impl NNode for Node {
-        fn addr(&self) -> SocketAddr {
+        type Key = SocketAddr;
+
+        fn key(&self) -> SocketAddr {
             self.addr
         }
     }
@@ -321,7 +380,7 @@ mod tests {
             let nodes = world.read_storage::<Node>();
             let node = nodes
                 .get(e5)
-                .map(|node| node.addr())
+                .map(|node| node.key())
                 .unwrap();
             assert_eq!(node, ip5);
         }
@@ -374,4 +433,184 @@ mod tests {
         let entity = world.fetch::<WorldState<Node>>().get_entity(&ip1).unwrap();
         assert_eq!(entity, e2);
     }
+
+    fn node_addr(i: u32) -> SocketAddr {
+        SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, (i >> 16) as u16, i as u16)), 80)
+    }
+
+    fn read_events(world: &World, reader_id: &mut ReaderId<NodeEvent>) -> Vec<NodeEvent> {
+        world.fetch::<WorldState<Node>>().changed().read(reader_id).copied().collect()
+    }
+
+    #[test]
+    fn test_churn() {
+        let mut world = World::new();
+        world.register::<Node>();
+        let mut system = NodeSystem::<Node>::new(&mut world);
+        let mut reader_id = world.write_resource::<WorldState<Node>>().track();
+
+        let mut live: Vec<(u32, Entity)> = (0..5000)
+            .map(|i| (i, world.create_entity().with(Node { addr: node_addr(i), name: i.to_string() }).build()))
+            .collect();
+        system.run_now(&mut world);
+        world.maintain();
+        let joined: Vec<NodeEvent> = live.iter().map(|(_, e)| NodeEvent::Modified(*e)).collect();
+        assert_eq!(read_events(&world, &mut reader_id), joined);
+
+        let mut next = 5000;
+        for round in 0..20 {
+            // every third peer leaves, as many new ones join
+            let mut left = vec![];
+            let mut i = round % 3;
+            while i < live.len() {
+                left.push(live.swap_remove(i).1);
+                i += 3;
+            }
+            for entity in &left {
+                world.delete_entity(*entity).unwrap();
+            }
+            let mut joined = vec![];
+            for _ in 0..left.len() {
+                let entity = world.create_entity().with(Node { addr: node_addr(next), name: next.to_string() }).build();
+                live.push((next, entity));
+                joined.push(entity);
+                next += 1;
+            }
+            system.run_now(&mut world);
+            world.maintain();
+
+            // removals by entity id, then joins in the order they were made
+            left.sort_by_key(|e| e.id());
+            joined.sort_by_key(|e| e.id());
+            let expected: Vec<NodeEvent> = left.into_iter().map(NodeEvent::Removed)
+                .chain(joined.into_iter().map(NodeEvent::Modified))
+                .collect();
+            assert_eq!(read_events(&world, &mut reader_id), expected);
+
+            let state = world.fetch::<WorldState<Node>>();
+            assert_eq!(state.all().len(), live.len());
+            for (i, entity) in &live {
+                assert_eq!(state.get_entity(&node_addr(*i)), Some(*entity));
+            }
+        }
+        let state = world.fetch::<WorldState<Node>>();
+        for i in 0..next {
+            let alive = live.iter().any(|(j, _)| *j == i);
+            assert_eq!(state.get_entity(&node_addr(i)).is_some(), alive);
+        }
+    }
+
+    #[test]
+    fn test_bulk_replacement() {
+        let mut world = World::new();
+        world.register::<Node>();
+        let mut system = NodeSystem::<Node>::new(&mut world);
+        let mut reader_id = world.write_resource::<WorldState<Node>>().track();
+
+        let old: Vec<Entity> = (0..2000)
+            .map(|i| world.create_entity().with(Node { addr: node_addr(i), name: "old".to_string() }).build())
+            .collect();
+        system.run_now(&mut world);
+        world.maintain();
+        read_events(&world, &mut reader_id);
+
+        // the same peers reconnect as new entities
+        let new: Vec<Entity> = (0..2000).rev()
+            .map(|i| world.create_entity().with(Node { addr: node_addr(i), name: "new".to_string() }).build())
+            .collect();
+        system.run_now(&mut world);
+        let mut old_sorted = old.clone();
+        old_sorted.sort_by_key(|e| e.id());
+        let expected: Vec<NodeEvent> = old_sorted.iter().map(|e| NodeEvent::Removed(*e))
+            .chain(new.iter().map(|e| NodeEvent::Modified(*e)))
+            .collect();
+        assert_eq!(read_events(&world, &mut reader_id), expected);
+
+        world.maintain();
+        let state = world.fetch::<WorldState<Node>>();
+        assert_eq!(state.all().len(), 2000);
+        for (i, entity) in new.iter().rev().enumerate() {
+            assert_eq!(state.get_entity(&node_addr(i as u32)), Some(*entity));
+        }
+    }
+
+    /// a contact known by name, reachable at any of its addresses
+    struct Contact {
+        name: String,
+        addresses: Vec<String>,
+    }
+
+    impl Component for Contact {
+        type Storage = FlaggedStorage<Self, DenseVecStorage<Self>>;
+    }
+
+    impl NNode for Contact {
+        type Key = String;
+
+        fn key(&self) -> String {
+            self.name.clone()
+        }
+
+        fn secondary_keys(&self) -> Vec<String> {
+            self.addresses.clone()
+        }
+    }
+
+    #[test]
+    fn test_secondary_keys() {
+        let mut world = World::new();
+        world.register::<Contact>();
+        let mut system = NodeSystem::<Contact>::new(&mut world);
+        let contact = |name: &str, addresses: &[&str]| Contact {
+            name: name.to_string(),
+            addresses: addresses.iter().map(|a| a.to_string()).collect(),
+        };
+        let alice = world.create_entity().with(contact("alice", &["a1", "a2"])).build();
+        let bob = world.create_entity().with(contact("bob", &["b1"])).build();
+        system.run_now(&mut world);
+        world.maintain();
+        {
+            let state = world.fetch::<WorldState<Contact>>();
+            assert_eq!(state.get_entity(&"alice".to_string()), Some(alice));
+            assert_eq!(state.get_entity(&"a2".to_string()), Some(alice));
+            assert_eq!(state.get_primary(&"a2".to_string()), None);
+            assert_eq!(state.get_entity(&"b1".to_string()), Some(bob));
+        }
+
+        // bob moved
+        world.write_storage::<Contact>().get_mut(bob).unwrap().addresses = vec!["b2".to_string()];
+        let _ = world.delete_entity(alice);
+        system.run_now(&mut world);
+        world.maintain();
+        let state = world.fetch::<WorldState<Contact>>();
+        assert_eq!(state.get_entity(&"b1".to_string()), None);
+        assert_eq!(state.get_entity(&"b2".to_string()), Some(bob));
+        assert_eq!(state.get_entity(&"a1".to_string()), None);
+        assert_eq!(state.get_entity(&"alice".to_string()), None);
+        assert_eq!(state.all(), &[bob]);
+    }
+
+    #[test]
+    fn test_modified_duplicate() {
+        let mut world = World::new();
+        world.register::<Contact>();
+        let mut system = NodeSystem::<Contact>::new(&mut world);
+        let mut reader_id = world.fetch_mut::<WorldState<Contact>>().track();
+        let contact = |name: &str| Contact { name: name.to_string(), addresses: vec![] };
+        let alice = world.create_entity().with(contact("alice")).build();
+        let bob = world.create_entity().with(contact("bob")).build();
+        system.run_now(&mut world);
+        world.maintain();
+        let _ = world.fetch::<WorldState<Contact>>().changed().read(&mut reader_id).count();
+
+        // bob takes alice's name in place and replaces her
+        world.write_storage::<Contact>().get_mut(bob).unwrap().name = "alice".to_string();
+        system.run_now(&mut world);
+        let state = world.fetch::<WorldState<Contact>>();
+        let events: Vec<NodeEvent> = state.changed().read(&mut reader_id).copied().collect();
+        assert_eq!(events, vec![NodeEvent::Removed(alice)]);
+        assert_eq!(state.get_entity(&"alice".to_string()), Some(bob));
+        assert_eq!(state.get_entity(&"bob".to_string()), None);
+        assert_eq!(state.all(), &[bob]);
+    }
 }
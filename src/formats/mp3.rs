@@ -8,6 +8,9 @@ extern crate mp3_metadata;
 #[derive(Debug)]
 pub enum FileError {
     InvalidFormat,
+    /// no audio frames to read the bitrate and format from
+    NoFrames,
+    PathNotUtf8,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
@@ -19,8 +22,8 @@ pub struct MusicFileData {
     sampling_freq: u16,
     channel_type: String,
     pub title: String,
-    artist: String,
-    album: String,
+    pub artist: String,
+    pub album: String,
     year: u16,
     genre: String,
     hash: String,
@@ -65,7 +68,7 @@ pub fn get_mp3_data(file: &Path) -> Result<MusicFileData, FileError> {
             }
         },
     };
-    let frame = &meta.frames[0];
+    let frame = meta.frames.first().ok_or(FileError::NoFrames)?;
 
     let version = fmt::format(format_args!("{:?}", frame.version));
     let chan_type = fmt::format(format_args!("{:?}", frame.chan_type));
@@ -75,16 +78,29 @@ pub fn get_mp3_data(file: &Path) -> Result<MusicFileData, FileError> {
     let artist = tag.artist.trim_matches(char::from(0));
     let album = tag.album.trim_matches(char::from(0));
 
+    // fields the ID3v1 tag lacks come from the first ID3v2 tag
+    let v2 = meta.optional_info.first();
+    let or_v2 = |v1: &str, v2: Option<&String>| -> String {
+        match v2 {
+            Some(v2) if v1.trim().is_empty() => v2.trim_matches(char::from(0)).to_string(),
+            _ => v1.to_string(),
+        }
+    };
+    let title = or_v2(title, v2.and_then(|v2| v2.title.as_ref()));
+    // the album artist groups compilations better than the track's
+    let artist = or_v2(artist, v2.and_then(|v2| v2.band.as_ref().or_else(|| v2.performers.first())));
+    let album = or_v2(album, v2.and_then(|v2| v2.album_movie_show.as_ref()));
+
     let mp3_data = MusicFileData {
-        path: file.to_str().unwrap().to_string(),
+        path: file.to_str().ok_or(FileError::PathNotUtf8)?.to_string(),
         version: version,
         layer: layer,
         bitrate: frame.bitrate,
         sampling_freq: frame.sampling_freq,
         channel_type: chan_type,
-        title: title.to_string(),
-        artist: artist.to_string(),
-        album: album.to_string(),
+        title,
+        artist,
+        album,
         year: tag.year,
         genre: genre,
         hash: "".to_string(),
@@ -100,6 +116,31 @@ mod tests {
     #[test]
     fn test_mp3() {
         const MP3_FILE: &str = "./static/01 - mirror.mp3";
-        let _valid = get_mp3_data(Path::new(MP3_FILE)).unwrap();
+        let valid = get_mp3_data(Path::new(MP3_FILE)).unwrap();
+        assert_eq!(valid.title, "Mirror");
+        assert_eq!(valid.artist, "Moss Icon");
+        assert_eq!(valid.album, "Lyburnum");
+    }
+
+    #[test]
+    fn test_broken_files() {
+        use std::ffi::OsStr;
+        use std::fs;
+        use std::os::unix::ffi::OsStrExt;
+
+        // a tag and no audio
+        let mut v1 = vec![0u8; 128];
+        v1[..3].copy_from_slice(b"TAG");
+        let path = Path::new("/tmp/test_no_frames.mp3");
+        fs::write(path, &v1).unwrap();
+        assert!(get_mp3_data(path).is_err());
+
+        let path = Path::new(OsStr::from_bytes(b"/tmp/test_not_utf8_\xff.mp3"));
+        fs::copy("./static/01 - mirror.mp3", path).unwrap();
+        match get_mp3_data(path) {
+            Err(FileError::PathNotUtf8) => {},
+            other => panic!("expected a path error, got {:?}", other),
+        }
+        fs::remove_file(path).unwrap();
     }
 }
@@ -0,0 +1,160 @@
+use std::collections::HashMap;
+use std::time::Instant;
+
+use crate::codec::MessageEvent;
+use crate::models::RecordError;
+use crate::consts::*;
+
+/// most peers we accept in a single `PeersResponse`
+pub const MAX_PEERS_PER_RESPONSE: usize = 64;
+
+// misbehaviour points per offence
+pub const DECODE_ERROR_PENALTY: u32 = 10;
+pub const RATE_LIMIT_PENALTY: u32 = 5;
+pub const PROTOCOL_VIOLATION_PENALTY: u32 = 25;
+pub const BAD_RECORD_PENALTY: u32 = 25;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Offence {
+    DecodeError,
+    RateLimited(u8),
+    ProtocolViolation(&'static str),
+    BadRecord(RecordError),
+}
+
+impl Offence {
+    pub fn penalty(&self) -> u32 {
+        match self {
+            Offence::DecodeError => DECODE_ERROR_PENALTY,
+            Offence::RateLimited(_) => RATE_LIMIT_PENALTY,
+            Offence::ProtocolViolation(_) => PROTOCOL_VIOLATION_PENALTY,
+            Offence::BadRecord(_) => BAD_RECORD_PENALTY,
+        }
+    }
+
+    pub fn reason(&self) -> String {
+        match self {
+            Offence::DecodeError => "malformed frame".to_string(),
+            Offence::RateLimited(kind) => format!("rate limit exceeded for 0x{:X}", kind),
+            Offence::ProtocolViolation(what) => format!("protocol violation: {}", what),
+            Offence::BadRecord(e) => e.to_string(),
+        }
+    }
+}
+
+/// (burst, messages per second) allowed for each message type
+fn limit_for(kind: u8) -> (f64, f64) {
+    match kind {
+        PING | PONG => (5.0, 0.5),
+        ARTISTS_REQUEST | ALBUM_REQUEST | REQUEST_FILE | MANIFEST_REQUEST | COLLECTION_REQUEST => (10.0, 0.5),
+        PEERS_REQUEST => (5.0, 0.1),
+        ARTISTS_RESPONSE | ALBUM_RESPONSE | PEERS_RESPONSE | MANIFEST | COLLECTION_DELTA => (20.0, 1.0),
+        _ => (50.0, 10.0),
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    capacity: f64,
+    refill: f64,
+    last: Instant,
+}
+
+impl Bucket {
+    fn new(capacity: f64, refill: f64, now: Instant) -> Self {
+        Bucket {
+            tokens: capacity,
+            capacity,
+            refill,
+            last: now,
+        }
+    }
+
+    fn take(&mut self, now: Instant) -> bool {
+        let elapsed = now.duration_since(self.last).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * self.refill).min(self.capacity);
+        self.last = now;
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+/// Per connection rate limiting and sanity checks on incoming messages.
+pub struct PeerGuard {
+    buckets: HashMap<u8, Bucket>,
+}
+
+impl PeerGuard {
+    pub fn new() -> Self {
+        PeerGuard {
+            buckets: HashMap::new(),
+        }
+    }
+
+    pub fn check(&mut self, message: &MessageEvent) -> Result<(), Offence> {
+        self.check_at(message, Instant::now())
+    }
+
+    fn check_at(&mut self, message: &MessageEvent, now: Instant) -> Result<(), Offence> {
+        let kind = match message.kind() {
+            Some(kind) => kind,
+            None => return Ok(()),
+        };
+        let bucket = self.buckets.entry(kind).or_insert_with(|| {
+            let (capacity, refill) = limit_for(kind);
+            Bucket::new(capacity, refill, now)
+        });
+        if !bucket.take(now) {
+            return Err(Offence::RateLimited(kind));
+        }
+
+        match message {
+            MessageEvent::PeersResponse(peers) if peers.len() > MAX_PEERS_PER_RESPONSE => {
+                Err(Offence::ProtocolViolation("too many peers in response"))
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+    use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+    use crate::models::Peer;
+
+    #[test]
+    fn test_rate_limit() {
+        let mut guard = PeerGuard::new();
+        let now = Instant::now();
+        let (burst, refill) = limit_for(ARTISTS_REQUEST);
+        for _ in 0..burst as usize {
+            assert_eq!(guard.check_at(&MessageEvent::ArtistsRequest, now), Ok(()));
+        }
+        assert_eq!(
+            guard.check_at(&MessageEvent::ArtistsRequest, now),
+            Err(Offence::RateLimited(ARTISTS_REQUEST)),
+        );
+        // other message types have their own budget
+        assert_eq!(guard.check_at(&MessageEvent::PeersRequest, now), Ok(()));
+
+        let later = now + Duration::from_secs_f64(1.0 / refill);
+        assert_eq!(guard.check_at(&MessageEvent::ArtistsRequest, later), Ok(()));
+    }
+
+    #[test]
+    fn test_peers_flood() {
+        let addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 8000);
+        let peers = vec![Peer::new(addr, false, None, None, None); MAX_PEERS_PER_RESPONSE + 1];
+        let mut guard = PeerGuard::new();
+        match guard.check(&MessageEvent::PeersResponse(peers)) {
+            Err(Offence::ProtocolViolation(_)) => {},
+            other => assert!(false, "expected violation, got {:?}", other),
+        }
+    }
+}
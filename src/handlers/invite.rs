@@ -0,0 +1,38 @@
+use std::error::Error;
+use std::net::SocketAddr;
+
+use chrono::{Duration, Utc};
+
+use crate::identity::Identity;
+use crate::models::{Invite, InviteError, Service};
+use super::process::connect;
+
+/// An invite code for `identity`, valid for `expires_hours` if given.
+pub fn create_invite(identity: &Identity, addresses: Vec<SocketAddr>, name: Option<String>, expires_hours: Option<i64>) -> Result<String, InviteError> {
+    let expires = expires_hours.map(|hours| (Utc::now() + Duration::hours(hours)).timestamp() as u64);
+    Invite::new(identity, addresses, name, expires).map(|invite| invite.encode())
+}
+
+/// Check an invite code, trust and store its peer, then dial it on the
+/// first address that answers.
+pub async fn redeem_invite(service: &Service, code: &str, petname: Option<String>) -> Result<(), Box<dyn Error>> {
+    let invite = Invite::decode_now(code)?;
+    let petname = petname
+        .or_else(|| invite.name.clone())
+        .unwrap_or_else(|| invite.peer_id().to_string());
+    println!("redeeming invite from {} ({})", petname, invite.peer_id());
+
+    let redeemed = invite.clone();
+    service.database.call(move |db| db.redeem_invite(&redeemed, &petname)).await??;
+
+    let service = service.clone();
+    tokio::spawn(async move {
+        for addr in invite.addresses {
+            match connect(service.clone(), addr).await {
+                Ok(()) => break,
+                Err(e) => println!("could not reach {}: {:?}", addr, e),
+            }
+        }
+    });
+    Ok(())
+}
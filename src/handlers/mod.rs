@@ -1,4 +1,6 @@
 mod process;
+pub mod guard;
+pub mod invite;
 pub mod scheduler;
 
-pub use self::process::{process, Service};
+pub use self::process::{connect, process, Service};
@@ -1,96 +1,569 @@
-use std::sync::Arc;
 use std::error::Error;
 use std::net::SocketAddr;
+use std::time::Instant;
 
-use tokio::sync::Mutex;
+use futures::future::{self, Either};
 use tokio::stream::StreamExt;
 use tokio::net::TcpStream;
+use tokio::time;
 use tokio_util::codec::Framed;
 
 pub use crate::models::Service;
-use crate::models::{Collection, PeerConnection};
+use chrono::Utc;
+
+use crate::models::{Collection, Event, Heartbeat, Message, Peer, PeerConnection, PeerId, RecordError, MAX_CLOCK_SKEW_SECS};
 use crate::codec::{
     MessageEvent,
     MessageCodec,
 };
+use super::guard::{Offence, PeerGuard, MAX_PEERS_PER_RESPONSE};
+use super::scheduler::PING_TIMEOUT;
+
+/// Dial a peer and process the connection, unless the peer is banned.
+pub async fn connect(
+    service: Service,
+    addr: SocketAddr,
+) -> Result<(), Box<dyn Error>> {
+    let ip = addr.ip();
+    if service.database.call(move |db| db.is_banned(&ip)).await? {
+        return Err(format!("refusing to dial banned peer {}", addr).into());
+    }
+    let stream = TcpStream::connect(addr).await?;
+    process(service, stream, addr).await
+}
+
+fn now_secs() -> u64 {
+    Utc::now().timestamp() as u64
+}
+
+/// Keep the records that verify. Stale ones are dropped quietly, they may
+/// just have been relayed for a while, anything forged is an offence.
+fn verified_peers(peers: Vec<Peer>) -> (Vec<Peer>, Option<Offence>) {
+    let now = now_secs();
+    let mut offence = None;
+    let peers = peers.into_iter()
+        .filter(|peer| match peer.verify(now) {
+            Ok(()) => true,
+            Err(RecordError::Stale) => false,
+            Err(e) => {
+                offence = Some(Offence::BadRecord(e));
+                false
+            },
+        })
+        .collect();
+    (peers, offence)
+}
+
+/// Ask what changed in the peer's catalogue since the version we hold.
+async fn catalogue_request(service: &Service, record: &Peer) -> Result<MessageEvent, Box<dyn Error>> {
+    let known = record.address;
+    let since = service.database.call(move |db| db.collection_version(&known)).await?;
+    Ok(MessageEvent::CollectionRequest(since))
+}
+
+/// A record must be signed and its key not revoked.
+async fn check_record(service: &Service, record: &Peer) -> Result<Result<(), RecordError>, Box<dyn Error>> {
+    if let Err(e) = record.verify(now_secs()) {
+        return Ok(Err(e));
+    }
+    let revoked = match record.id() {
+        Some(id) => service.database.call(move |db| db.is_revoked(&id)).await?,
+        None => false,
+    };
+    Ok(if revoked { Err(RecordError::Revoked) } else { Ok(()) })
+}
+
+/// In friend-to-friend mode only fresh records from trusted keys get in.
+/// Records are relayed, so in every mode the connection must also answer
+/// one of our pings with the record's key before it is admitted, see
+/// `proves_key`.
+async fn admit(service: &Service, record: &Peer) -> Result<bool, Box<dyn Error>> {
+    if !service.friends_only {
+        return Ok(true);
+    }
+    let id = match record.id() {
+        Some(id) => id,
+        None => return Ok(false),
+    };
+    if record.timestamp() + MAX_CLOCK_SKEW_SECS < now_secs() {
+        return Ok(false);
+    }
+    Ok(service.database.call(move |db| db.is_trusted(&id)).await?)
+}
+
+/// Whether a Pong answers our outstanding ping with `record`'s key. A
+/// replayed record can't pass, its proof would be for someone else's nonce.
+fn proves_key(record: &Peer, heartbeat: &Heartbeat, proof: &[u8], pending: Option<(u64, Instant)>) -> bool {
+    match pending {
+        Some((nonce, _)) if nonce == heartbeat.nonce => record.verify_proof(&heartbeat.challenge(), proof),
+        _ => false,
+    }
+}
+
+/// How far a connection got in proving the key of its record. The same
+/// in every mode, `admit` only narrows who may try.
+struct Handshake {
+    /// nonce and send time of our last unanswered ping
+    pending: Option<(u64, Instant)>,
+    /// the key the peer proved it holds
+    proven: Option<PeerId>,
+}
+
+impl Handshake {
+    fn new() -> Self {
+        Handshake { pending: None, proven: None }
+    }
+
+    /// Nothing is served to the peer or written under its address before.
+    fn admitted(&self) -> bool {
+        self.proven.is_some()
+    }
+
+    /// Only the handshake and a goodbye are taken before admission.
+    fn allows(&self, message: &MessageEvent) -> bool {
+        match message {
+            MessageEvent::Ping(..) | MessageEvent::Pong(..) | MessageEvent::Goodbye => true,
+            _ => self.admitted(),
+        }
+    }
+
+    /// A Pong must answer our ping with the key of its record to admit
+    /// the peer, and keep to that key afterwards.
+    fn check_pong(&mut self, record: &Peer, heartbeat: &Heartbeat, proof: &[u8]) -> Result<(), &'static str> {
+        match self.proven {
+            None if proves_key(record, heartbeat, proof, self.pending) => {
+                self.proven = record.id();
+                Ok(())
+            },
+            None => Err("did not prove it holds the key of its record"),
+            Some(id) if record.id() == Some(id) => Ok(()),
+            Some(_) => Err("changed keys mid connection"),
+        }
+    }
+}
+
+/// Note that the connection from `addr` is the peer at `address`.
+fn identify(service: &Service, addr: SocketAddr, remote: &mut Option<SocketAddr>, address: SocketAddr) {
+    if *remote != Some(address) {
+        service.registry.cast(move |registry| registry.identify(addr, address));
+        *remote = Some(address);
+    }
+}
+
+/// Penalize the peer for an offence, returns true if it is now banned.
+async fn punish(service: &Service, addr: SocketAddr, offence: Offence) -> Result<bool, Box<dyn Error>> {
+    println!("{} misbehaved: {}", addr, offence.reason());
+    let banned = service.database.call(move |db| {
+        db.penalize(addr.ip(), offence.penalty(), &offence.reason())
+    }).await?;
+    if banned {
+        println!("banned {}", addr.ip());
+    }
+    Ok(banned)
+}
 
 pub async fn process(
-    state: Arc<Mutex<Service>>,
+    service: Service,
     stream: TcpStream,
     addr: SocketAddr,
 ) -> Result<(), Box<dyn Error>> {
     let transport = Framed::new(stream, MessageCodec::new());
-    let mut peer = PeerConnection::new(state.clone(), transport).await?;
+    let mut peer = PeerConnection::new(&service, transport).await?;
+    let mut remote = None;
+    let result = serve(&service, &mut peer, addr, &mut remote).await;
+    let (sent, received) = peer.take_traffic();
+    let (database, events) = (service.database.clone(), service.events.clone());
+    service.registry.cast(move |registry| {
+        // the peer may still be connected on another socket
+        let last = registry.deregister(&addr);
+        if let Some(remote) = remote {
+            let left = last == Some(remote);
+            database.cast(move |db| {
+                db.record_traffic(&remote, sent, received);
+                if left {
+                    db.record_disconnected(&remote);
+                }
+            });
+            if left {
+                events.publish(Event::PeerLeft(remote));
+            }
+        }
+    });
+    result
+}
 
-    while let Some(result) = peer.next().await {
+/// `remote` is the address the peer advertises in its Ping/Pong, its
+/// records in the database are kept under this rather than the socket
+/// address. Set once the peer is admitted.
+async fn serve(
+    service: &Service,
+    peer: &mut PeerConnection,
+    addr: SocketAddr,
+    remote: &mut Option<SocketAddr>,
+) -> Result<(), Box<dyn Error>> {
+    let mut guard = PeerGuard::new();
+    // the key the peer signs its records with, if any
+    let mut remote_id: Option<PeerId> = None;
+    let mut handshake = Handshake::new();
+    // key certificates are passed on once per connection
+    let mut greeted = false;
+
+    loop {
+        // fires once our last ping has gone unanswered for PING_TIMEOUT
+        let timeout = match handshake.pending {
+            Some((_, sent)) => Either::Left(time::delay_until((sent + PING_TIMEOUT).into())),
+            None => Either::Right(future::pending()),
+        };
+        let stop = future::select(Box::pin(service.shutdown.wait()), timeout);
+        let result = match future::select(peer.next(), stop).await {
+            Either::Left((Some(result), _)) => result,
+            Either::Left((None, _)) => break,
+            Either::Right((Either::Left(_), _)) => {
+                let _ = peer.send_message(MessageEvent::Goodbye).await;
+                break;
+            },
+            Either::Right((Either::Right(_), _)) => {
+                handshake.pending = None;
+                // a peer that never said who it is goes by its socket address
+                let known = remote.unwrap_or(addr);
+                println!("{} missed a ping", known);
+                service.database.cast(move |db| db.record_timeout(&known));
+                continue;
+            },
+        };
+        if let Some(remote) = *remote {
+            let (sent, received) = peer.take_traffic();
+            if sent + received > 0 {
+                service.database.cast(move |db| db.record_traffic(&remote, sent, received));
+            }
+        }
+        if let Ok(Message::Received(message)) = &result {
+            if let Err(offence) = guard.check(message) {
+                if punish(service, addr, offence).await? {
+                    break;
+                }
+                continue;
+            }
+            if !handshake.allows(message) {
+                println!("{} sent {:?} before being admitted", addr, message.kind());
+                continue;
+            }
+        }
         match result {
-            Ok(MessageEvent::Ping(peer_data)) => {
-                let mut state = state.lock().await;
-                peer.send_message(MessageEvent::Pong(state.my_contact.clone())).await.unwrap();
-                state.database.add_peer(peer_data, Collection::new(vec![]));
-                peer.send_message(MessageEvent::ArtistsRequest).await.unwrap();
-            },
-            Ok(MessageEvent::Pong(peer_data)) => {
-                let mut state = state.lock().await;
-                state.database.add_peer(peer_data, Collection::new(vec![]));
-                peer.send_message(MessageEvent::ArtistsRequest).await.unwrap();
-            },
-            Ok(MessageEvent::ArtistsRequest) => {
-                let state = state.lock().await;
-                peer.send_message(MessageEvent::ArtistsResponse(
-                    state.get_collection(false, None, None))
-                ).await.unwrap();
-            },
-            Ok(MessageEvent::ArtistsResponse(artist_data)) => {
-                let mut state = state.lock().await;
-                state.database.update_collection(&addr, Collection::new(artist_data));
-            },
-            Ok(MessageEvent::AlbumRequest(album)) => {
-                let state = state.lock().await;
-                peer.send_message(
-                    MessageEvent::ArtistsResponse(
-                        state.get_collection(
-                            false, album.artist.as_deref(),
-                            Some(&album.album_title),
-                    ))
-                ).await.unwrap();
-            },
-            Ok(MessageEvent::AlbumResponse(album_data)) => {
-                let mut state = state.lock().await;
-                state.database.add_tracks(&addr, album_data);
-            },
-            Ok(MessageEvent::PeersRequest) => {
-                let state = state.lock().await;
-                peer.send_message(
-                    MessageEvent::PeersResponse(
-                        state.database.all_peers()
+            Ok(Message::Broadcast(message)) => {
+                if let MessageEvent::Ping(heartbeat, _) = &message {
+                    handshake.pending = Some((heartbeat.nonce, Instant::now()));
+                }
+                peer.send_message(message).await?;
+            },
+            Ok(Message::Received(MessageEvent::Ping(heartbeat, peer_data))) => {
+                let checked = check_record(service, &peer_data).await?;
+                if let Err(e) = checked {
+                    if punish(service, addr, Offence::BadRecord(e)).await? {
+                        break;
+                    }
+                    continue;
+                }
+                if !admit(service, &peer_data).await? {
+                    println!("{} is not a trusted contact", addr);
+                    let _ = peer.send_message(MessageEvent::Goodbye).await;
+                    break;
+                }
+                let proof = service.identity.sign(&heartbeat.challenge());
+                if !handshake.admitted() {
+                    // answer, and have it prove the key of its record in turn
+                    peer.send_message(MessageEvent::Pong(heartbeat, service.my_contact(), proof)).await?;
+                    if handshake.pending.is_none() {
+                        let challenge = Heartbeat::new();
+                        handshake.pending = Some((challenge.nonce, Instant::now()));
+                        peer.send_message(MessageEvent::Ping(challenge, service.my_contact())).await?;
+                    }
+                    continue;
+                }
+                if peer_data.id() != handshake.proven {
+                    println!("{} changed keys mid connection", addr);
+                    let _ = peer.send_message(MessageEvent::Goodbye).await;
+                    break;
+                }
+                if !greeted {
+                    greeted = true;
+                    service.events.publish(Event::PeerJoined(peer_data.clone()));
+                    for certificate in service.database.call(|db| db.certificates()).await? {
+                        peer.send_message(MessageEvent::KeyCertificate(certificate)).await?;
+                    }
+                }
+                peer.send_message(MessageEvent::Pong(heartbeat, service.my_contact(), proof)).await?;
+                identify(service, addr, remote, peer_data.address);
+                remote_id = peer_data.id();
+                let catalogue_request = catalogue_request(service, &peer_data).await?;
+                service.database.cast(move |db| {
+                    let peer_addr = peer_data.address;
+                    db.add_peers(vec![peer_data]);
+                    db.record_seen(&peer_addr);
+                });
+                peer.send_message(catalogue_request).await?;
+            },
+            Ok(Message::Received(MessageEvent::Pong(heartbeat, peer_data, proof))) => {
+                let checked = check_record(service, &peer_data).await?;
+                if let Err(e) = checked {
+                    if punish(service, addr, Offence::BadRecord(e)).await? {
+                        break;
+                    }
+                    continue;
+                }
+                if !admit(service, &peer_data).await? {
+                    println!("{} is not a trusted contact", addr);
+                    let _ = peer.send_message(MessageEvent::Goodbye).await;
+                    break;
+                }
+                if let Err(reason) = handshake.check_pong(&peer_data, &heartbeat, &proof) {
+                    println!("{} {}", addr, reason);
+                    let _ = peer.send_message(MessageEvent::Goodbye).await;
+                    break;
+                }
+                if !greeted {
+                    greeted = true;
+                    service.events.publish(Event::PeerJoined(peer_data.clone()));
+                    for certificate in service.database.call(|db| db.certificates()).await? {
+                        peer.send_message(MessageEvent::KeyCertificate(certificate)).await?;
+                    }
+                }
+                identify(service, addr, remote, peer_data.address);
+                remote_id = peer_data.id();
+                let catalogue_request = catalogue_request(service, &peer_data).await?;
+                let rtt = match handshake.pending {
+                    Some((nonce, sent)) if nonce == heartbeat.nonce => {
+                        handshake.pending = None;
+                        Some(sent.elapsed().as_secs_f64() * 1000.0)
+                    },
+                    _ => None,
+                };
+                service.database.cast(move |db| {
+                    let peer_addr = peer_data.address;
+                    db.add_peers(vec![peer_data]);
+                    db.record_seen(&peer_addr);
+                    db.record_answer(&peer_addr);
+                    if let Some(rtt) = rtt {
+                        db.record_rtt(&peer_addr, rtt);
+                    }
+                });
+                peer.send_message(catalogue_request).await?;
+            },
+            Ok(Message::Received(MessageEvent::ArtistsRequest)) => {
+                let artists = service.library.call(|library| {
+                    library.get_collection(false, None, None)
+                }).await?;
+                peer.send_message(MessageEvent::ArtistsResponse(artists)).await?;
+            },
+            Ok(Message::Received(MessageEvent::ArtistsResponse(artist_data))) => {
+                let known = remote.unwrap_or(addr);
+                service.database.cast(move |db| {
+                    db.update_collection(&known, Collection::new(artist_data));
+                    db.record_answer(&known);
+                });
+            },
+            Ok(Message::Received(MessageEvent::AlbumRequest(album))) => {
+                let artists = service.library.call(move |library| {
+                    library.get_collection(
+                        false, album.artist.as_deref(),
+                        Some(&album.album_title),
                     )
-                ).await.unwrap();
+                }).await?;
+                peer.send_message(MessageEvent::ArtistsResponse(artists)).await?;
+            },
+            Ok(Message::Received(MessageEvent::AlbumResponse(album_data))) => {
+                let known = remote.unwrap_or(addr);
+                service.database.cast(move |db| {
+                    db.add_tracks(&known, album_data);
+                    db.record_answer(&known);
+                });
+            },
+            Ok(Message::Received(MessageEvent::CollectionRequest(since))) => {
+                let delta = service.library.call(move |library| library.changes_since(since)).await?;
+                peer.send_message(MessageEvent::CollectionDelta(delta)).await?;
+            },
+            Ok(Message::Received(MessageEvent::CollectionDelta(delta))) => {
+                let known = remote.unwrap_or(addr);
+                let changed = !delta.is_empty();
+                let (applied, wants_manifest) = service.database.call(move |db| {
+                    db.record_answer(&known);
+                    let wants_manifest = remote_id.map_or(false, |id| db.wants_manifest(&id));
+                    (db.update_collection_delta(&known, &delta), wants_manifest)
+                }).await?;
+                if !applied {
+                    // we missed a version, start over from a snapshot
+                    peer.send_message(MessageEvent::CollectionRequest(0)).await?;
+                } else if let (true, true, Some(id)) = (changed, wants_manifest, remote_id) {
+                    // keep a signed copy to relay while it is offline, the
+                    // whole collection so not after every delta
+                    peer.send_message(MessageEvent::ManifestRequest(id)).await?;
+                }
+            },
+            Ok(Message::Received(MessageEvent::ManifestRequest(owner))) => {
+                let manifest = if owner == service.identity.peer_id() {
+                    let identity = service.identity.clone();
+                    Some(service.library.call(move |library| library.manifest(&identity)).await?)
+                } else {
+                    // relay a cached copy, only of trusted contacts in friends mode
+                    let friends_only = service.friends_only;
+                    service.database.call(move |db| {
+                        db.get_manifest(&owner).filter(|_| !friends_only || db.is_trusted(&owner))
+                    }).await?
+                };
+                if let Some(manifest) = manifest {
+                    peer.send_message(MessageEvent::Manifest(manifest)).await?;
+                }
             },
-            Ok(MessageEvent::PeersResponse(peers_list)) => {
-                let mut state = state.lock().await;
-                state.database.add_peers(peers_list);
+            Ok(Message::Received(MessageEvent::Manifest(manifest))) => {
+                if let Err(e) = manifest.verify(now_secs()) {
+                    if punish(service, addr, Offence::BadRecord(e)).await? {
+                        break;
+                    }
+                    continue;
+                }
+                let known = remote.unwrap_or(addr);
+                service.database.cast(move |db| {
+                    db.update_manifest(manifest, addr);
+                    db.record_answer(&known);
+                });
+            },
+            Ok(Message::Received(MessageEvent::KeyCertificate(certificate))) => {
+                if let Err(e) = certificate.verify(now_secs()) {
+                    if punish(service, addr, Offence::BadRecord(e)).await? {
+                        break;
+                    }
+                    continue;
+                }
+                let relay = MessageEvent::KeyCertificate(certificate.clone());
+                if service.database.call(move |db| db.apply_certificate(certificate)).await? {
+                    service.registry.cast(move |registry| registry.broadcast(&relay));
+                }
+            },
+            Ok(Message::Received(MessageEvent::PeersRequest)) => {
+                let friends_only = service.friends_only;
+                let peers = service.database.call(move |db| {
+                    if friends_only {
+                        db.exchange_trusted(MAX_PEERS_PER_RESPONSE)
+                    } else {
+                        db.exchange_peers(MAX_PEERS_PER_RESPONSE)
+                    }
+                }).await?;
+                peer.send_message(MessageEvent::PeersResponse(peers)).await?;
+            },
+            Ok(Message::Received(MessageEvent::PeersResponse(peers_list))) => {
+                let (peers_list, offence) = verified_peers(peers_list);
+                if let Some(offence) = offence {
+                    if punish(service, addr, offence).await? {
+                        break;
+                    }
+                }
+                let known = remote.unwrap_or(addr);
+                service.database.cast(move |db| {
+                    db.add_peers(peers_list);
+                    db.record_answer(&known);
+                });
             },
             // TODO: perhaps a broadcast would be useful?
-            // Ok(MessageEvent::Broadcast(msg)) => {
-            //     let mut state = state.lock().await;
-            //     state.broadcast(addr, &msg).await;
+            // Ok(Message::Received(MessageEvent::Broadcast(msg))) => {
+            //     service.registry.cast(move |registry| registry.broadcast(&msg));
             // },
-            // Ok(MessageEvent::Payload(msg)) => {
-            //     let mut state = state.lock().await;
-            //     state.broadcast(addr, &msg).await;
-            // },
-            Ok(MessageEvent::Ok) => {
-                let ss = state.lock().await;
-                println!("COUNTER: {:?}", ss.counter);
+            Ok(Message::Received(MessageEvent::Goodbye)) => {
+                println!("{} said goodbye", addr);
+                break;
+            },
+            Ok(Message::Received(MessageEvent::Ok)) => {
+                println!("COUNTER: {:?}", service.counter());
             },
             Err(e) => {
                 println!(
                     "an error occured while processing messages; error = {:?}", e
                 );
+                if punish(service, addr, Offence::DecodeError).await? {
+                    break;
+                }
             },
             _ => println!("UNK"), // do nothing?
         }
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::identity::Identity;
+    use crate::models::{AlbumData, CollectionDelta};
+    use crate::signature::generate_bytes;
+
+    #[test]
+    fn test_proves_key() {
+        let friend = Identity::from_pkcs8(&generate_bytes()).unwrap();
+        let thief = Identity::from_pkcs8(&generate_bytes()).unwrap();
+        let record = Peer::get_self(&friend, 8000);
+
+        // the friend answers our ping
+        let ping = Heartbeat::new();
+        let pending = Some((ping.nonce, Instant::now()));
+        let proof = friend.sign(&ping.challenge());
+        assert!(proves_key(&record, &ping, &proof, pending));
+
+        // a replay of that answer, or of the record with another key's
+        // proof, doesn't answer our next ping
+        let next = Heartbeat::new();
+        let pending = Some((next.nonce, Instant::now()));
+        assert!(!proves_key(&record, &ping, &proof, pending));
+        assert!(!proves_key(&record, &next, &proof, pending));
+        assert!(!proves_key(&record, &next, &thief.sign(&next.challenge()), pending));
+        assert!(!proves_key(&record, &next, &friend.sign(&next.challenge()), None));
+        assert!(proves_key(&record, &next, &friend.sign(&next.challenge()), pending));
+    }
+
+    #[test]
+    fn test_replayed_record() {
+        // outside friends mode anyone may try, but only with its own key
+        let victim = Identity::from_pkcs8(&generate_bytes()).unwrap();
+        let thief = Identity::from_pkcs8(&generate_bytes()).unwrap();
+        let record = Peer::get_self(&victim, 8000);
+        let mut handshake = Handshake::new();
+        let ping = Heartbeat::new();
+        handshake.pending = Some((ping.nonce, Instant::now()));
+
+        assert!(handshake.check_pong(&record, &ping, &thief.sign(&ping.challenge())).is_err());
+        assert!(!handshake.admitted());
+
+        assert_eq!(handshake.check_pong(&record, &ping, &victim.sign(&ping.challenge())), Ok(()));
+        assert!(handshake.admitted());
+        assert_eq!(handshake.proven, Some(victim.peer_id()));
+        // and keeps to it
+        let other = Peer::get_self(&thief, 8000);
+        assert!(handshake.check_pong(&other, &ping, &thief.sign(&ping.challenge())).is_err());
+    }
+
+    #[test]
+    fn test_before_admission() {
+        let identity = Identity::from_pkcs8(&generate_bytes()).unwrap();
+        let record = Peer::get_self(&identity, 8000);
+        let delta = CollectionDelta::snapshot(1, vec![]);
+        let data = vec![
+            MessageEvent::ArtistsRequest,
+            MessageEvent::ArtistsResponse(vec![]),
+            MessageEvent::AlbumResponse(AlbumData::new(None, "a".to_string(), 0, None)),
+            MessageEvent::CollectionDelta(delta),
+            MessageEvent::PeersResponse(vec![record.clone()]),
+            MessageEvent::ManifestRequest(identity.peer_id()),
+        ];
+        let mut handshake = Handshake::new();
+        for message in &data {
+            assert!(!handshake.allows(message), "{:?}", message);
+        }
+        assert!(handshake.allows(&MessageEvent::Ping(Heartbeat::new(), record.clone())));
+        assert!(handshake.allows(&MessageEvent::Goodbye));
+
+        let ping = Heartbeat::new();
+        handshake.pending = Some((ping.nonce, Instant::now()));
+        handshake.check_pong(&record, &ping, &identity.sign(&ping.challenge())).unwrap();
+        for message in &data {
+            assert!(handshake.allows(message), "{:?}", message);
+        }
+    }
+}
@@ -1,28 +1,55 @@
-use std::sync::Arc;
-use std::net::{Ipv6Addr, IpAddr, SocketAddr};
+use std::future::Future;
+use std::time::{Duration, Instant};
 
-use tokio::sync::Mutex;
+use futures::future::{self, Either};
+use tokio::time;
 
-use crate::models::{Peer, Service};
+use crate::models::{Heartbeat, Service};
 use crate::codec::MessageEvent;
 
+pub const PING_INTERVAL: Duration = Duration::from_millis(10_000);
+/// how often the database is asked for due maintenance jobs, each job
+/// keeps its own interval, see `MaintenanceIntervals`
+pub const MAINTENANCE_TICK: Duration = Duration::from_millis(1_000);
+/// a ping unanswered for this long marks the peer unhealthy
+pub const PING_TIMEOUT: Duration = Duration::from_millis(5_000);
+
 // TODO: drop peers after no response for some time
 // TODO: figure how to add new connections
 
-pub async fn ping_all_peers(state: Arc<Mutex<Service>>) {
-    let mut state = state.lock().await;
-    state.incr();
+/// Run `job` every `period` until shutdown, the first run is right away.
+pub fn spawn_every<F, Fut>(service: Service, period: Duration, job: F)
+where
+    F: Fn(Service) -> Fut + Send + 'static,
+    Fut: Future<Output = ()> + Send + 'static,
+{
+    tokio::spawn(async move {
+        let mut interval = time::interval(period);
+        loop {
+            match future::select(Box::pin(interval.tick()), Box::pin(service.shutdown.wait())).await {
+                Either::Left(_) => job(service.clone()).await,
+                Either::Right(_) => break,
+            }
+        }
+    });
+}
 
-    let server = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 8001);
-    let me = Peer::new(server, false, Some("MyName".into()), None, Some("ZYX987".into()));
-    for peer in state.peers.iter_mut() {
-        peer.1.send(MessageEvent::Ping(me.clone())).unwrap();
-    }
+pub async fn ping_all_peers(service: &Service) {
+    service.incr();
+
+    let ping = MessageEvent::Ping(Heartbeat::new(), service.my_contact());
+    service.registry.cast(move |registry| registry.broadcast(&ping));
+}
+
+pub async fn peers_request(service: &Service) {
+    service.registry.cast(|registry| registry.broadcast(&MessageEvent::PeersRequest));
 }
 
-pub async fn peers_request(state: Arc<Mutex<Service>>) {
-    let mut state = state.lock().await;
-    for peer in state.peers.iter_mut() {
-        peer.1.send(MessageEvent::PeersRequest).unwrap();
+/// run the database's due maintenance jobs, see `storage::maintenance`
+pub async fn run_maintenance(service: &Service) {
+    match service.database.call(|db| db.run_maintenance(Instant::now())).await {
+        Ok(Ok(_)) => {},
+        Ok(Err(e)) => println!("maintenance checkpoint failed: {}", e),
+        Err(e) => println!("maintenance failed: {:?}", e),
     }
 }
@@ -0,0 +1,84 @@
+use std::io;
+
+use ring::signature::{Ed25519KeyPair, KeyPair};
+use rustc_serialize::hex::ToHex;
+
+use crate::models::PeerId;
+use crate::signature::{generate_bytes, sign};
+use crate::storage::atomic::{load_with_backup, write_private};
+
+/// This node's long lived Ed25519 key pair.
+pub struct Identity {
+    key_pair: Ed25519KeyPair,
+}
+
+impl Identity {
+    pub fn from_pkcs8(bytes: &[u8]) -> io::Result<Self> {
+        let key_pair = Ed25519KeyPair::from_pkcs8(bytes)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, "invalid PKCS#8 identity key"))?;
+        Ok(Identity { key_pair })
+    }
+
+    /// Load the PKCS#8 key at `path`, creating it on first run.
+    pub fn load_or_create(path: &str) -> io::Result<Self> {
+        match load_with_backup(path, Identity::from_pkcs8)? {
+            Some(identity) => Ok(identity),
+            None => {
+                let identity = Identity::create(path)?;
+                println!("created new identity in {}", path);
+                Ok(identity)
+            },
+        }
+    }
+
+    /// Generate a new key and write it to `path`.
+    pub fn create(path: &str) -> io::Result<Self> {
+        let bytes = generate_bytes();
+        write_private(path, &bytes)?;
+        Identity::from_pkcs8(&bytes)
+    }
+
+    pub fn key_pair(&self) -> &Ed25519KeyPair {
+        &self.key_pair
+    }
+
+    pub fn public_key(&self) -> &[u8] {
+        self.key_pair.public_key().as_ref()
+    }
+
+    pub fn public_key_hex(&self) -> String {
+        self.public_key().to_hex()
+    }
+
+    pub fn peer_id(&self) -> PeerId {
+        PeerId::from_public_key(self.public_key())
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Vec<u8> {
+        sign(&self.key_pair, message)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_load_or_create() {
+        let path = "/tmp/identity_test.pk8";
+        let _ = fs::remove_file(path);
+        let first = Identity::load_or_create(path).unwrap();
+        let second = Identity::load_or_create(path).unwrap();
+        assert_eq!(first.peer_id(), second.peer_id());
+        assert_eq!(first.public_key_hex(), second.public_key_hex());
+        assert_eq!(first.peer_id(), PeerId::from_public_key_hex(&first.public_key_hex()).unwrap());
+    }
+
+    #[test]
+    fn test_corrupt_identity() {
+        let path = "/tmp/identity_corrupt.pk8";
+        fs::write(path, b"not a key").unwrap();
+        assert!(Identity::load_or_create(path).is_err());
+    }
+}
@@ -1,9 +1,11 @@
 pub mod signature;
+pub mod identity;
 pub mod merkle;
 mod tree_utils;
 mod formats;
 mod models;
 
+pub mod actors;
 pub mod handlers;
 pub mod organizer;
 pub mod codec;
@@ -12,6 +14,7 @@ pub mod ecs;
 pub mod storage;
 pub mod args;
 pub mod tui;
+pub mod shutdown;
 
 #[macro_use]
 extern crate shred_derive;
@@ -1,13 +1,16 @@
-use std::sync::Arc;
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
-use tokio::sync::Mutex;
+use futures::future::{self, Either};
 use tokio::net::TcpListener;
 use tokio::time;
 
 use music_snobster::handlers::{process, Service};
-use music_snobster::handlers::scheduler::ping_all_peers;
-use music_snobster::args::get_args;
+use music_snobster::handlers::scheduler::{ping_all_peers, run_maintenance, spawn_every, MAINTENANCE_TICK, PING_INTERVAL};
+use music_snobster::args::{get_args, InviteCommand};
+use music_snobster::handlers::invite::{create_invite, redeem_invite};
+use music_snobster::identity::Identity;
+use music_snobster::shutdown::wait_for_signal;
+use music_snobster::storage::{collection_command, identity_command, trust_command};
 use music_snobster::tui::run_tui;
 
 // TODO: handle requests
@@ -15,44 +18,105 @@ use music_snobster::tui::run_tui;
 //   - REQUEST FILE
 //   - DOWNLOAD FILE
 
+/// how long connections get to say goodbye before we save and exit
+const GOODBYE_TIMEOUT: Duration = Duration::from_millis(2_000);
+
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
     let config = get_args();
+    if let Some(command) = &config.trust_command {
+        trust_command(&config.config, command)?;
+        return Ok(());
+    }
+    if let Some(command) = &config.collection_command {
+        collection_command(&config.config, &config.music, command)?;
+        return Ok(());
+    }
+    if let Some(command) = &config.identity_command {
+        identity_command(&config.config, &config.identity, command)?;
+        return Ok(());
+    }
+    if let Some(InviteCommand::Create { addresses, name, expires_hours }) = &config.invite_command {
+        let identity = Identity::load_or_create(&config.identity)?;
+        let addresses = if addresses.is_empty() {
+            vec![format!("127.0.0.1:{}", config.port).parse()?]
+        } else {
+            addresses.iter().map(|a| a.parse()).collect::<Result<_, _>>()?
+        };
+        println!("{}", create_invite(&identity, addresses, name.clone(), *expires_hours)?);
+        return Ok(());
+    }
     println!("{:?}", config);
-    let state = Arc::new(Mutex::new(Service::new(config.clone())));
+    let service = Service::new(config.clone())?;
+    println!("peer id {}", service.identity.peer_id());
+    for (peer, artist, album) in service.database.call(|db| db.interrupted_transfers()).await? {
+        println!("download of {} - {} from {} was interrupted", artist, album, peer);
+    }
+    let shutdown = service.shutdown.clone();
     let mut listener = TcpListener::bind(format!("127.0.0.1:{}", config.port)).await?;
 
-    // text interface
-    if config.tui {
-        let tui_state = Arc::clone(&state);
-        tokio::spawn(async move {
-            run_tui(tui_state);
-            std::process::exit(0);
-        });
+    // SIGINT and SIGTERM
+    let signal_shutdown = shutdown.clone();
+    tokio::spawn(async move {
+        wait_for_signal().await;
+        signal_shutdown.trigger();
+    });
+
+    if let Some(InviteCommand::Redeem { code, petname }) = &config.invite_command {
+        redeem_invite(&service, code, petname.clone()).await?;
     }
 
+    // text interface
+    let tui = if config.tui {
+        let tui_service = service.clone();
+        let tui_shutdown = shutdown.clone();
+        Some(tokio::task::spawn_blocking(move || {
+            run_tui(tui_service);
+            tui_shutdown.trigger();
+        }))
+    } else {
+        None
+    };
+
     // regularly scheduled background tasks
-    let scheduler_state = Arc::clone(&state);
-    tokio::spawn(async move {
-        let mut interval = time::interval(Duration::from_millis(10_000));
-        loop {
-            let ss = Arc::clone(&scheduler_state);
-            interval.tick().await;
-            ping_all_peers(ss).await;
-        }
-    });
+    spawn_every(service.clone(), PING_INTERVAL, |service| async move { ping_all_peers(&service).await });
+    spawn_every(service.clone(), MAINTENANCE_TICK, |service| async move { run_maintenance(&service).await });
 
     // process incoming requests
     // add support for outgoing requests also, ie.
     // let mut stream = TcpStream::connect("127.0.0.1:34254")?;
     loop {
-        let (stream, addr) = listener.accept().await?;
-        let state = Arc::clone(&state);
+        let (stream, addr) = match future::select(Box::pin(listener.accept()), Box::pin(shutdown.wait())).await {
+            Either::Left((accepted, _)) => accepted?,
+            Either::Right(_) => break,
+        };
+        let ip = addr.ip();
+        if service.database.call(move |db| db.is_banned(&ip)).await? {
+            println!("rejected banned peer {}", addr);
+            continue;
+        }
+        let service = service.clone();
 
         tokio::spawn(async move {
-            if let Err(e) = process(state, stream, addr).await {
+            if let Err(e) = process(service, stream, addr).await {
                 println!("an error occured; error = {:?}", e);
             }
         });
     }
+
+    // connections send their goodbyes and deregister themselves
+    let started = Instant::now();
+    while !service.registry.call(|registry| registry.is_empty()).await? && started.elapsed() < GOODBYE_TIMEOUT {
+        time::delay_for(Duration::from_millis(50)).await;
+    }
+    if let Some(tui) = tui {
+        let _ = tui.await;
+    }
+    let interrupted = service.transfers.call(|transfers| transfers.flush()).await?;
+    if interrupted > 0 {
+        println!("stopped {} transfers still in progress, they will not resume", interrupted);
+    }
+    service.save().await?;
+    println!("state saved, bye");
+    Ok(())
 }
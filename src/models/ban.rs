@@ -0,0 +1,148 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+
+use chrono::{DateTime, Duration, Utc};
+
+/// misbehaviour points before a peer gets banned
+pub const BAN_THRESHOLD: u32 = 100;
+/// temporary bans before the next one becomes permanent
+pub const MAX_TEMPORARY_BANS: u32 = 3;
+/// length of the first temporary ban, doubled for each repeat offence
+pub const BASE_BAN_MINUTES: i64 = 10;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Ban {
+    pub reason: String,
+    pub since: DateTime<Utc>,
+    pub until: Option<DateTime<Utc>>, // None is permanent
+}
+
+impl Ban {
+    pub fn is_active(&self, now: DateTime<Utc>) -> bool {
+        match self.until {
+            Some(until) => now < until,
+            None => true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct BanList {
+    bans: HashMap<IpAddr, Ban>,
+    scores: HashMap<IpAddr, u32>,
+    offences: HashMap<IpAddr, u32>,
+}
+
+impl BanList {
+    pub fn new() -> Self {
+        BanList::default()
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr, now: DateTime<Utc>) -> bool {
+        self.bans.get(ip).map_or(false, |ban| ban.is_active(now))
+    }
+
+    /// Add misbehaviour points, banning the address once it crosses
+    /// `BAN_THRESHOLD`. Returns true if the address is now banned.
+    pub fn penalize(&mut self, ip: IpAddr, points: u32, reason: &str, now: DateTime<Utc>) -> bool {
+        if self.is_banned(&ip, now) {
+            return true;
+        }
+        let score = self.scores.entry(ip).or_insert(0);
+        *score = score.saturating_add(points);
+        if *score < BAN_THRESHOLD {
+            return false;
+        }
+        self.scores.remove(&ip);
+
+        let offences = self.offences.entry(ip).or_insert(0);
+        *offences += 1;
+        let until = if *offences > MAX_TEMPORARY_BANS {
+            None
+        } else {
+            Some(now + Duration::minutes(BASE_BAN_MINUTES << (*offences - 1)))
+        };
+        self.ban(ip, until, reason, now);
+        true
+    }
+
+    pub fn ban(&mut self, ip: IpAddr, until: Option<DateTime<Utc>>, reason: &str, now: DateTime<Utc>) {
+        self.bans.insert(ip, Ban {
+            reason: reason.to_string(),
+            since: now,
+            until,
+        });
+    }
+
+    pub fn unban(&mut self, ip: &IpAddr) -> Option<Ban> {
+        self.scores.remove(ip);
+        self.offences.remove(ip);
+        self.bans.remove(ip)
+    }
+
+    /// drop temporary bans that have run out, offence counts are kept
+    pub fn expire(&mut self, now: DateTime<Utc>) {
+        self.bans.retain(|_, ban| ban.is_active(now));
+    }
+
+    pub fn all(&self) -> Vec<(IpAddr, Ban)> {
+        self.bans.iter().map(|(ip, ban)| (*ip, ban.clone())).collect()
+    }
+
+    /// what outlives a restart, the ban and past offences of each address
+    pub fn records(&self) -> Vec<(IpAddr, Option<Ban>, u32)> {
+        let mut ips: Vec<IpAddr> = self.bans.keys().chain(self.offences.keys()).copied().collect();
+        ips.sort();
+        ips.dedup();
+        ips.into_iter()
+            .map(|ip| (ip, self.bans.get(&ip).cloned(), self.offences.get(&ip).copied().unwrap_or(0)))
+            .collect()
+    }
+
+    /// put back a record from `records`
+    pub fn restore(&mut self, ip: IpAddr, ban: Option<Ban>, offences: u32) {
+        if let Some(ban) = ban {
+            self.bans.insert(ip, ban);
+        }
+        if offences > 0 {
+            self.offences.insert(ip, offences);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_penalize_until_banned() {
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let now = Utc::now();
+        let mut bans = BanList::new();
+        assert_eq!(bans.penalize(ip, BAN_THRESHOLD - 1, "decode", now), false);
+        assert_eq!(bans.is_banned(&ip, now), false);
+        assert_eq!(bans.penalize(ip, 1, "decode", now), true);
+        assert_eq!(bans.is_banned(&ip, now), true);
+
+        let later = now + Duration::minutes(BASE_BAN_MINUTES + 1);
+        assert_eq!(bans.is_banned(&ip, later), false);
+        bans.expire(later);
+        assert_eq!(bans.all().len(), 0);
+    }
+
+    #[test]
+    fn test_repeat_offender_is_permanent() {
+        let ip = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let mut now = Utc::now();
+        let mut bans = BanList::new();
+        for _ in 0..MAX_TEMPORARY_BANS {
+            assert!(bans.penalize(ip, BAN_THRESHOLD, "flood", now));
+            assert!(bans.all()[0].1.until.is_some());
+            now = now + Duration::days(1);
+        }
+        assert!(bans.penalize(ip, BAN_THRESHOLD, "flood", now));
+        assert_eq!(bans.all()[0].1.until, None);
+        assert!(bans.is_banned(&ip, now + Duration::days(365)));
+    }
+}
@@ -0,0 +1,312 @@
+use std::collections::HashMap;
+
+use super::{AlbumData, ArtistData, Collection};
+
+/// Values in insertion order, looked up by name. Removing leaves a hole
+/// that is compacted away once holes outnumber the values. A name listed
+/// twice keeps both values, lookups find the first.
+#[derive(Clone, Debug)]
+struct Slots<T> {
+    values: Vec<Option<(String, T)>>,
+    positions: HashMap<String, usize>,
+    duplicates: usize,
+}
+
+impl<T> Default for Slots<T> {
+    fn default() -> Self {
+        Slots {
+            values: vec![],
+            positions: HashMap::new(),
+            duplicates: 0,
+        }
+    }
+}
+
+impl<T> Slots<T> {
+    fn get(&self, key: &str) -> Option<&T> {
+        let index = *self.positions.get(key)?;
+        self.values[index].as_ref().map(|(_, value)| value)
+    }
+
+    fn get_mut(&mut self, key: &str) -> Option<&mut T> {
+        let index = *self.positions.get(key)?;
+        self.values[index].as_mut().map(|(_, value)| value)
+    }
+
+    /// replaces a value of the same name in place, or goes last
+    fn insert(&mut self, key: String, value: T) -> Option<T> {
+        match self.positions.get(&key) {
+            Some(&index) => self.values[index].replace((key, value)).map(|(_, old)| old),
+            None => {
+                self.positions.insert(key.clone(), self.values.len());
+                self.values.push(Some((key, value)));
+                None
+            },
+        }
+    }
+
+    /// goes last even if the name is taken
+    fn push(&mut self, key: String, value: T) {
+        match self.positions.contains_key(&key) {
+            true => self.duplicates += 1,
+            false => {
+                self.positions.insert(key.clone(), self.values.len());
+            },
+        }
+        self.values.push(Some((key, value)));
+    }
+
+    fn remove(&mut self, key: &str) -> Option<T> {
+        let index = self.positions.remove(key)?;
+        let value = self.values[index].take().map(|(_, value)| value);
+        if self.duplicates > 0 {
+            // the next value of that name takes its place
+            let next = self.values[index..].iter()
+                .position(|slot| slot.as_ref().map_or(false, |(k, _)| k == key));
+            if let Some(next) = next {
+                self.positions.insert(key.to_string(), index + next);
+                self.duplicates -= 1;
+            }
+        }
+        if self.len() * 2 < self.values.len() {
+            self.compact();
+        }
+        value
+    }
+
+    fn compact(&mut self) {
+        self.values.retain(Option::is_some);
+        self.positions.clear();
+        for (index, (key, _)) in self.values.iter().flatten().enumerate() {
+            self.positions.entry(key.clone()).or_insert(index);
+        }
+    }
+
+    fn values(&self) -> impl Iterator<Item = &T> {
+        self.values.iter().flatten().map(|(_, value)| value)
+    }
+
+    fn len(&self) -> usize {
+        self.positions.len() + self.duplicates
+    }
+}
+
+#[derive(Clone, Debug)]
+struct ArtistEntry {
+    name: String,
+    /// `None` when the artist was listed without albums
+    albums: Option<Slots<AlbumData>>,
+}
+
+impl ArtistEntry {
+    fn to_data(&self) -> ArtistData {
+        let albums = self.albums.as_ref().map(|albums| albums.values().cloned().collect());
+        ArtistData::new(self.name.clone(), albums)
+    }
+}
+
+/// A `Collection` held in memory, indexed by artist name and album title
+/// so that adding, replacing or removing an album does not scan the rest.
+/// Artists and albums keep the order they were first seen in.
+#[derive(Clone, Debug, Default)]
+pub struct Catalogue {
+    artists: Slots<ArtistEntry>,
+}
+
+impl Catalogue {
+    pub fn new() -> Self {
+        Catalogue::default()
+    }
+
+    /// number of artists
+    pub fn len(&self) -> usize {
+        self.artists.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.artists.len() == 0
+    }
+
+    pub fn artist(&self, name: &str) -> Option<ArtistData> {
+        self.artists.get(name).map(ArtistEntry::to_data)
+    }
+
+    pub fn album(&self, artist: &str, title: &str) -> Option<&AlbumData> {
+        self.artists.get(artist)?.albums.as_ref()?.get(title)
+    }
+
+    /// Put `album` under `artist`, replacing the album of the same title
+    /// if there is one, which is returned.
+    pub fn upsert_album(&mut self, artist: &str, album: AlbumData) -> Option<AlbumData> {
+        let entry = self.entry(artist);
+        let albums = entry.albums.get_or_insert_with(Slots::default);
+        albums.insert(album.album_title.clone(), album)
+    }
+
+    /// Merge in an artist: new artists and albums are added, the tracks
+    /// of albums we already hold are appended to theirs.
+    pub fn merge(&mut self, artist: &ArtistData) {
+        let entry = self.entry(&artist.artist);
+        let added = match &artist.albums {
+            Some(albums) => albums,
+            None => return,
+        };
+        let albums = entry.albums.get_or_insert_with(Slots::default);
+        for album in added {
+            match albums.get_mut(&album.album_title) {
+                None => {
+                    albums.insert(album.album_title.clone(), album.clone());
+                },
+                Some(current) => for track in album.tracks.iter().flatten() {
+                    current.tracks.get_or_insert_with(Vec::new).push(track.clone());
+                },
+            }
+        }
+    }
+
+    /// Take away what `removed` names: an artist without albums goes
+    /// whole, as does an album without tracks, otherwise only the listed
+    /// tracks go. Anything we do not hold is ignored.
+    pub fn remove(&mut self, removed: &ArtistData) {
+        let albums = match &removed.albums {
+            Some(albums) => albums,
+            None => {
+                self.artists.remove(&removed.artist);
+                return;
+            },
+        };
+        let current = match self.artists.get_mut(&removed.artist).and_then(|e| e.albums.as_mut()) {
+            Some(current) => current,
+            None => return,
+        };
+        for album in albums {
+            match &album.tracks {
+                None => {
+                    current.remove(&album.album_title);
+                },
+                Some(tracks) => if let Some(held) = current.get_mut(&album.album_title) {
+                    if let Some(held) = held.tracks.as_mut() {
+                        held.retain(|t| !tracks.contains(t));
+                    }
+                },
+            }
+        }
+    }
+
+    pub fn artists(&self) -> Vec<ArtistData> {
+        self.artists.values().map(ArtistEntry::to_data).collect()
+    }
+
+    pub fn to_collection(&self) -> Collection {
+        Collection::new(self.artists())
+    }
+
+    fn entry(&mut self, artist: &str) -> &mut ArtistEntry {
+        if self.artists.get(artist).is_none() {
+            self.artists.insert(artist.to_string(), ArtistEntry {
+                name: artist.to_string(),
+                albums: None,
+            });
+        }
+        self.artists.get_mut(artist).expect("just inserted")
+    }
+}
+
+impl From<&Collection> for Catalogue {
+    /// albums are kept as listed, artists listed more than once are merged
+    fn from(collection: &Collection) -> Self {
+        let mut catalogue = Catalogue::new();
+        for artist in &collection.artists {
+            let entry = catalogue.entry(&artist.artist);
+            if let Some(albums) = &artist.albums {
+                let held = entry.albums.get_or_insert_with(Slots::default);
+                for album in albums {
+                    held.push(album.album_title.clone(), album.clone());
+                }
+            }
+        }
+        catalogue
+    }
+}
+
+impl From<Collection> for Catalogue {
+    fn from(collection: Collection) -> Self {
+        Catalogue::from(&collection)
+    }
+}
+
+impl From<&Catalogue> for Collection {
+    fn from(catalogue: &Catalogue) -> Self {
+        catalogue.to_collection()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::TrackData;
+
+    fn album(title: &str, tracks: &[&str]) -> AlbumData {
+        let tracks = tracks.iter().map(|t| TrackData::new(t.to_string(), 320, 200)).collect();
+        AlbumData::new(None, title.to_string(), 1, Some(tracks))
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let collection = Collection::new(vec![
+            ArtistData::new("b".to_string(), Some(vec![album("two", &["x"]), album("one", &[]), album("two", &[])])),
+            ArtistData::new("a".to_string(), None),
+            ArtistData::new("c".to_string(), Some(vec![])),
+        ]);
+        let mut catalogue = Catalogue::from(&collection);
+        assert_eq!(catalogue.to_collection(), collection);
+
+        // the first of two albums of the same title goes first
+        assert_eq!(catalogue.album("b", "two"), Some(&album("two", &["x"])));
+        let mut two = album("two", &[]);
+        two.tracks = None;
+        catalogue.remove(&ArtistData::new("b".to_string(), Some(vec![two.clone()])));
+        assert_eq!(catalogue.album("b", "two"), Some(&album("two", &[])));
+        catalogue.remove(&ArtistData::new("b".to_string(), Some(vec![two])));
+        assert_eq!(catalogue.album("b", "two"), None);
+    }
+
+    #[test]
+    fn test_upsert_and_remove() {
+        let mut catalogue = Catalogue::from(Collection::new(vec![ArtistData::new("a".to_string(), None)]));
+        assert_eq!(catalogue.upsert_album("a", album("one", &["x"])), None);
+        assert_eq!(catalogue.upsert_album("b", album("two", &[])), None);
+        let replaced = catalogue.upsert_album("a", album("one", &["y", "z"]));
+        assert_eq!(replaced, Some(album("one", &["x"])));
+        assert_eq!(catalogue.album("a", "one"), Some(&album("one", &["y", "z"])));
+
+        catalogue.remove(&ArtistData::new("a".to_string(), Some(vec![album("one", &["y"])])));
+        assert_eq!(catalogue.album("a", "one"), Some(&album("one", &["z"])));
+        catalogue.remove(&ArtistData::new("a".to_string(), None));
+        // nothing to remove
+        catalogue.remove(&ArtistData::new("a".to_string(), Some(vec![album("one", &[])])));
+        catalogue.upsert_album("a", album("three", &[]));
+        let names: Vec<String> = catalogue.artists().into_iter().map(|a| a.artist).collect();
+        assert_eq!(names, vec!["b", "a"]);
+    }
+
+    #[test]
+    fn test_many_artists() {
+        let mut catalogue = Catalogue::new();
+        for i in 0..5000 {
+            catalogue.upsert_album(&format!("artist {}", i), album("first", &["x"]));
+        }
+        for i in (0..5000).step_by(2) {
+            catalogue.remove(&ArtistData::new(format!("artist {}", i), None));
+        }
+        for i in 0..5000 {
+            catalogue.merge(&ArtistData::new(format!("artist {}", i), Some(vec![album("first", &["y"])])));
+        }
+        assert_eq!(catalogue.len(), 5000);
+        assert_eq!(catalogue.album("artist 1", "first"), Some(&album("first", &["x", "y"])));
+        assert_eq!(catalogue.album("artist 2", "first"), Some(&album("first", &["y"])));
+        let artists = catalogue.artists();
+        assert_eq!(artists[0].artist, "artist 1");
+        assert_eq!(artists[2500].artist, "artist 0");
+    }
+}
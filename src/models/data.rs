@@ -1,12 +1,13 @@
 use bytes::{BytesMut, BufMut};
-use std::convert::TryInto;
 use std::str;
 use serde::{Deserialize, Serialize};
 
 use super::utils::{
+    take_nstring,
+    take_u8,
     take_u16,
     take_u64,
-    get_nstring,
+    MessageCodecError,
 };
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
@@ -23,12 +24,20 @@ impl Collection {
         let mut buf = BytesMut::new();
         buf.put_u64(self.artists.len() as u64);
         for artist in &self.artists {
-            let artist_bytes = artist.to_bytes();
-            buf.put_u8(artist_bytes.len() as u8);
-            buf.extend_from_slice(&artist_bytes[..]);
+            buf.extend_from_slice(&artist.to_bytes()[..]);
         }
         buf
     }
+
+    pub fn from_bytes(buf: &mut BytesMut) -> Result<Collection, MessageCodecError> {
+        let mut artist_count = take_u64(buf)?;
+        let mut artists = vec![];
+        while artist_count > 0 {
+            artists.push(ArtistData::from_bytes(buf)?);
+            artist_count -= 1;
+        }
+        Ok(Collection::new(artists))
+    }
 }
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
@@ -60,15 +69,15 @@ impl ArtistData {
         buf
     }
 
-    pub fn from_bytes(buf: &mut BytesMut) -> ArtistData {
-        let artist_name_len = take_u64(buf).unwrap() as usize;
-        let artist = get_nstring(buf, artist_name_len).unwrap();
-        let mut album_count = take_u64(buf).unwrap();
+    pub fn from_bytes(buf: &mut BytesMut) -> Result<ArtistData, MessageCodecError> {
+        let artist_name_len = take_u64(buf)? as usize;
+        let artist = take_nstring(buf, artist_name_len)?.unwrap_or_default();
+        let mut album_count = take_u64(buf)?;
         let mut album_vec: Vec<AlbumData> = vec![];
 
         let albums = if album_count > 0 {
             while album_count > 0 {
-                let album = AlbumData::from_bytes(buf);
+                let album = AlbumData::from_bytes(buf)?;
                 album_vec.push(album);
                 album_count -= 1;
             }
@@ -77,10 +86,10 @@ impl ArtistData {
             None
         };
 
-        ArtistData::new(
+        Ok(ArtistData::new(
             artist,
             albums,
-        )
+        ))
     }
 }
 
@@ -102,6 +111,10 @@ impl AlbumData {
         }
     }
 
+    pub fn track_count(&self) -> u8 {
+        self.track_count
+    }
+
     pub fn to_bytes(&self) -> BytesMut {
         let mut buf = BytesMut::new();
         if let Some(artist) = &self.artist {
@@ -129,20 +142,20 @@ impl AlbumData {
         buf
     }
 
-    pub fn from_bytes(buf: &mut BytesMut) -> AlbumData {
-        let artist_name_len = take_u64(buf).unwrap() as usize;
-        let artist = get_nstring(buf, artist_name_len);
-        let album_name_len = take_u64(buf).unwrap() as usize;
-        let album = get_nstring(buf, album_name_len).unwrap();
-        let track_count = buf.split_to(1)[0];
-        let get_tracks = buf.split_to(1)[0];
+    pub fn from_bytes(buf: &mut BytesMut) -> Result<AlbumData, MessageCodecError> {
+        let artist_name_len = take_u64(buf)? as usize;
+        let artist = take_nstring(buf, artist_name_len)?;
+        let album_name_len = take_u64(buf)? as usize;
+        let album = take_nstring(buf, album_name_len)?.unwrap_or_default();
+        let track_count = take_u8(buf)?;
+        let get_tracks = take_u8(buf)?;
 
         let tracks = if get_tracks == 1 {
             let mut tracks = vec![];
 
-            let mut track_count = take_u64(buf).unwrap();
+            let mut track_count = take_u64(buf)?;
             while track_count > 0 {
-                let track: TrackData = buf.into();
+                let track = TrackData::from_bytes(buf)?;
                 tracks.push(track);
                 track_count -= 1;
             }
@@ -151,13 +164,12 @@ impl AlbumData {
             None
         };
 
-        AlbumData::new(
+        Ok(AlbumData::new(
             artist,
             album,
             track_count,
             tracks,
-        )
-
+        ))
     }
 }
 
@@ -177,6 +189,14 @@ impl TrackData {
         }
     }
 
+    pub fn bitrate(&self) -> u16 {
+        self.bitrate
+    }
+
+    pub fn length(&self) -> u8 {
+        self.length
+    }
+
     pub fn to_bytes(&self) -> BytesMut {
         let mut buf = BytesMut::new();
         buf.put_u64(self.title.len() as u64);
@@ -185,18 +205,16 @@ impl TrackData {
         buf.put_u8(self.length);
         buf
     }
-}
 
-impl From<&mut BytesMut> for TrackData {
-    fn from(buf: &mut BytesMut) -> TrackData {
-        let track_name_len = take_u64(buf).unwrap() as usize;
-        let track = get_nstring(buf, track_name_len).unwrap();
-        let bitrate: u16 = take_u16(buf).unwrap();
-        let length: u8 = buf.split_to(1)[0].try_into().unwrap();
-        TrackData::new(
+    pub fn from_bytes(buf: &mut BytesMut) -> Result<TrackData, MessageCodecError> {
+        let track_name_len = take_u64(buf)? as usize;
+        let track = take_nstring(buf, track_name_len)?.unwrap_or_default();
+        let bitrate = take_u16(buf)?;
+        let length = take_u8(buf)?;
+        Ok(TrackData::new(
             track,
             bitrate,
             length,
-        )
+        ))
     }
 }
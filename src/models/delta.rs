@@ -0,0 +1,219 @@
+use std::collections::HashMap;
+
+use bytes::{BytesMut, BufMut};
+use serde::{Deserialize, Serialize};
+
+use super::{take_u8, take_u64, AlbumData, ArtistData, Catalogue, Collection, MessageCodecError};
+
+/// What changed in a collection from version `since` to `version`.
+///
+/// `removed` names what went away: an artist without albums is removed
+/// whole, as is an album without tracks, otherwise only the listed tracks
+/// go. `added` is merged in after the removals. A snapshot instead
+/// carries the whole collection in `added`.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct CollectionDelta {
+    pub since: u64,
+    pub version: u64,
+    pub snapshot: bool,
+    pub added: Vec<ArtistData>,
+    pub removed: Vec<ArtistData>,
+}
+
+impl CollectionDelta {
+    pub fn snapshot(version: u64, artists: Vec<ArtistData>) -> Self {
+        CollectionDelta {
+            since: 0,
+            version,
+            snapshot: true,
+            added: artists,
+            removed: vec![],
+        }
+    }
+
+    /// The changes from `old` to `new`, or a snapshot of `new` when they
+    /// cannot be expressed as a delta that reproduces it exactly.
+    pub fn between(since: u64, old: &[ArtistData], version: u64, new: &[ArtistData]) -> Self {
+        let mut delta = CollectionDelta {
+            since,
+            version,
+            snapshot: false,
+            added: vec![],
+            removed: vec![],
+        };
+        let old_names = by_name(old);
+        let new_names = by_name(new);
+        for artist in old {
+            match new_names.get(artist.artist.as_str()) {
+                None => delta.removed.push(ArtistData::new(artist.artist.clone(), None)),
+                Some(current) => {
+                    let (added, removed) = diff_albums(artist, current);
+                    if !added.is_empty() {
+                        delta.added.push(ArtistData::new(artist.artist.clone(), Some(added)));
+                    }
+                    if !removed.is_empty() {
+                        delta.removed.push(ArtistData::new(artist.artist.clone(), Some(removed)));
+                    }
+                },
+            }
+        }
+        for artist in new {
+            if !old_names.contains_key(artist.artist.as_str()) {
+                delta.added.push(artist.clone());
+            }
+        }
+        if delta.apply(&Collection::new(old.to_vec())).artists != new {
+            return CollectionDelta::snapshot(version, new.to_vec());
+        }
+        delta
+    }
+
+    /// nothing changed
+    pub fn is_empty(&self) -> bool {
+        !self.snapshot && self.added.is_empty() && self.removed.is_empty()
+    }
+
+    /// `collection` at version `since` brought to `version`
+    pub fn apply(&self, collection: &Collection) -> Collection {
+        let mut catalogue = Catalogue::from(collection);
+        self.apply_to(&mut catalogue);
+        catalogue.to_collection()
+    }
+
+    /// `apply` in place, touching only the artists the delta names
+    pub fn apply_to(&self, catalogue: &mut Catalogue) {
+        if self.snapshot {
+            *catalogue = Catalogue::from(Collection::new(self.added.clone()));
+            return;
+        }
+        for removed in &self.removed {
+            catalogue.remove(removed);
+        }
+        for added in &self.added {
+            catalogue.merge(added);
+        }
+    }
+
+    /// names of the artists the delta changes, each once
+    pub fn artists(&self) -> Vec<&str> {
+        let mut names: Vec<&str> = vec![];
+        for artist in self.removed.iter().chain(&self.added) {
+            if !names.contains(&artist.artist.as_str()) {
+                names.push(&artist.artist);
+            }
+        }
+        names
+    }
+
+    pub fn to_bytes(&self) -> BytesMut {
+        let mut buf = BytesMut::new();
+        buf.put_u64(self.since);
+        buf.put_u64(self.version);
+        buf.put_u8(self.snapshot as u8);
+        buf.extend_from_slice(&Collection::new(self.added.clone()).to_bytes()[..]);
+        buf.extend_from_slice(&Collection::new(self.removed.clone()).to_bytes()[..]);
+        buf
+    }
+
+    pub fn from_bytes(buf: &mut BytesMut) -> Result<Self, MessageCodecError> {
+        Ok(CollectionDelta {
+            since: take_u64(buf)?,
+            version: take_u64(buf)?,
+            snapshot: take_u8(buf)? == 1,
+            added: Collection::from_bytes(buf)?.artists,
+            removed: Collection::from_bytes(buf)?.artists,
+        })
+    }
+}
+
+/// artists by name, the first of any listed twice
+fn by_name(artists: &[ArtistData]) -> HashMap<&str, &ArtistData> {
+    let mut names = HashMap::new();
+    for artist in artists {
+        names.entry(artist.artist.as_str()).or_insert(artist);
+    }
+    names
+}
+
+/// albums (and tracks of albums) added to and removed from `old`
+fn diff_albums(old: &ArtistData, new: &ArtistData) -> (Vec<AlbumData>, Vec<AlbumData>) {
+    let old_albums = old.albums.as_deref().unwrap_or(&[]);
+    let new_albums = new.albums.as_deref().unwrap_or(&[]);
+    let mut added = vec![];
+    let mut removed = vec![];
+    for album in old_albums {
+        match new_albums.iter().find(|a| a.album_title == album.album_title) {
+            None => removed.push(without_tracks(album)),
+            Some(current) if current == album => {},
+            Some(current) => {
+                let old_tracks = album.tracks.as_deref().unwrap_or(&[]);
+                let new_tracks = current.tracks.as_deref().unwrap_or(&[]);
+                let gone: Vec<_> = old_tracks.iter().filter(|t| !new_tracks.contains(t)).cloned().collect();
+                let fresh: Vec<_> = new_tracks.iter().filter(|t| !old_tracks.contains(t)).cloned().collect();
+                if !gone.is_empty() {
+                    let mut album = album.clone();
+                    album.tracks = Some(gone);
+                    removed.push(album);
+                }
+                if !fresh.is_empty() {
+                    let mut album = current.clone();
+                    album.tracks = Some(fresh);
+                    added.push(album);
+                }
+            },
+        }
+    }
+    for album in new_albums {
+        if !old_albums.iter().any(|a| a.album_title == album.album_title) {
+            added.push(album.clone());
+        }
+    }
+    (added, removed)
+}
+
+fn without_tracks(album: &AlbumData) -> AlbumData {
+    let mut album = album.clone();
+    album.tracks = None;
+    album
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::models::TrackData;
+
+    fn album(title: &str, tracks: &[&str]) -> AlbumData {
+        let tracks = tracks.iter().map(|t| TrackData::new(t.to_string(), 320, 200)).collect();
+        AlbumData::new(None, title.to_string(), 1, Some(tracks))
+    }
+
+    #[test]
+    fn test_delta() {
+        let old = vec![
+            ArtistData::new("a".to_string(), Some(vec![album("one", &["x", "y"]), album("two", &["z"])])),
+            ArtistData::new("b".to_string(), None),
+        ];
+        let new = vec![
+            ArtistData::new("a".to_string(), Some(vec![album("one", &["x", "w"])])),
+            ArtistData::new("c".to_string(), Some(vec![album("three", &[])])),
+        ];
+        let delta = CollectionDelta::between(4, &old, 5, &new);
+        assert!(!delta.snapshot);
+        assert_eq!(delta.apply(&Collection::new(old.clone())).artists, new);
+        assert_eq!(delta.removed.len(), 2);
+
+        let mut bytes = delta.to_bytes();
+        assert_eq!(CollectionDelta::from_bytes(&mut bytes).unwrap(), delta);
+        assert!(CollectionDelta::between(5, &new, 5, &new).is_empty());
+    }
+
+    #[test]
+    fn test_delta_falls_back_to_snapshot() {
+        // reordered tracks cannot be expressed as additions and removals
+        let old = vec![ArtistData::new("a".to_string(), Some(vec![album("one", &["x", "y"])]))];
+        let new = vec![ArtistData::new("a".to_string(), Some(vec![album("one", &["y", "x"])]))];
+        let delta = CollectionDelta::between(1, &old, 2, &new);
+        assert!(delta.snapshot);
+        assert_eq!(delta.apply(&Collection::new(vec![])).artists, new);
+    }
+}
@@ -0,0 +1,68 @@
+use std::net::SocketAddr;
+
+use tokio::sync::broadcast;
+
+use super::{AlbumData, Peer};
+
+/// events a subscriber can fall behind by before missing the oldest
+const FEED_CAPACITY: usize = 256;
+
+/// A change to the network or the database, see `Service::subscribe`.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Event {
+    /// a connection was admitted, with the record the peer sent
+    PeerJoined(Peer),
+    /// the connection to the peer at this address closed
+    PeerLeft(SocketAddr),
+    /// the peer's collection changed, in full or by a delta
+    CollectionUpdated(SocketAddr),
+    TracksAdded(SocketAddr, AlbumData),
+}
+
+/// Fans `Event`s out to every subscriber. Publishing never waits, a
+/// subscriber that lags too far gets `RecvError::Lagged` instead.
+#[derive(Clone)]
+pub struct EventFeed {
+    sender: broadcast::Sender<Event>,
+}
+
+impl EventFeed {
+    pub fn new() -> Self {
+        let (sender, _) = broadcast::channel(FEED_CAPACITY);
+        EventFeed { sender }
+    }
+
+    pub fn publish(&self, event: Event) {
+        // nobody listening is fine
+        let _ = self.sender.send(event);
+    }
+
+    /// events published from now on
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.sender.subscribe()
+    }
+}
+
+impl Default for EventFeed {
+    fn default() -> Self {
+        EventFeed::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv6Addr};
+
+    #[tokio::test]
+    async fn test_feed() {
+        let addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 8000);
+        let feed = EventFeed::new();
+        feed.publish(Event::PeerLeft(addr));
+        let mut first = feed.subscribe();
+        let mut second = feed.clone().subscribe();
+        feed.publish(Event::CollectionUpdated(addr));
+        assert_eq!(first.recv().await.unwrap(), Event::CollectionUpdated(addr));
+        assert_eq!(second.recv().await.unwrap(), Event::CollectionUpdated(addr));
+    }
+}
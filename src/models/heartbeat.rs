@@ -0,0 +1,54 @@
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bytes::{BytesMut, BufMut};
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Serialize};
+
+use super::utils::{take_u64, MessageCodecError};
+
+/// Carried by Ping and echoed back unchanged in the Pong.
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Heartbeat {
+    pub nonce: u64,
+    pub sent_at: u64, // millis since the epoch, on the pinger's clock
+}
+
+impl Heartbeat {
+    pub fn new() -> Self {
+        let mut nonce = [0u8; 8];
+        SystemRandom::new().fill(&mut nonce).unwrap();
+        Heartbeat {
+            nonce: u64::from_be_bytes(nonce),
+            sent_at: now_millis(),
+        }
+    }
+
+    pub fn to_bytes(&self) -> BytesMut {
+        let mut buf = BytesMut::new();
+        buf.put_u64(self.nonce);
+        buf.put_u64(self.sent_at);
+        buf
+    }
+
+    /// What a Pong is signed over, by the key of the record it carries.
+    /// The pinger picks the nonce, so an old answer can't be replayed.
+    pub fn challenge(&self) -> Vec<u8> {
+        let mut challenge = b"pong".to_vec();
+        challenge.extend_from_slice(&self.to_bytes()[..]);
+        challenge
+    }
+
+    pub fn from_bytes(buf: &mut BytesMut) -> Result<Self, MessageCodecError> {
+        Ok(Heartbeat {
+            nonce: take_u64(buf)?,
+            sent_at: take_u64(buf)?,
+        })
+    }
+}
+
+fn now_millis() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_millis() as u64)
+        .unwrap_or(0)
+}
@@ -0,0 +1,266 @@
+use std::fmt;
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+use bytes::{BytesMut, BufMut};
+use chrono::Utc;
+use rustc_serialize::hex::ToHex;
+
+use super::{Peer, PeerId};
+use crate::identity::Identity;
+use crate::signature::verify_with_public_key;
+
+const INVITE_PREFIX: &str = "snob-";
+const INVITE_VERSION: u8 = 1;
+const BASE32_ALPHABET: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+const PUBLIC_KEY_LEN: usize = 32;
+const SIGNATURE_LEN: usize = 64;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum InviteError {
+    Malformed,
+    BadSignature,
+    Expired,
+    TooLong,
+}
+
+impl fmt::Display for InviteError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InviteError::Malformed => write!(f, "not a valid invite code"),
+            InviteError::BadSignature => write!(f, "invite signature does not match"),
+            InviteError::Expired => write!(f, "invite has expired"),
+            InviteError::TooLong => write!(f, "an invite holds at most 255 addresses and a 255 byte name"),
+        }
+    }
+}
+
+impl std::error::Error for InviteError {}
+
+/// Everything needed to reach and trust a peer, signed by that peer and
+/// shared as a `snob-` prefixed base32 string.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Invite {
+    pub addresses: Vec<SocketAddr>,
+    public_key: Vec<u8>,
+    pub name: Option<String>,
+    pub expires: Option<u64>, // unix seconds
+    signature: Vec<u8>,
+}
+
+impl Invite {
+    pub fn new(identity: &Identity, addresses: Vec<SocketAddr>, name: Option<String>, expires: Option<u64>) -> Result<Self, InviteError> {
+        // counts and lengths are a single byte in `signed_bytes`
+        if addresses.len() > u8::MAX as usize || name.as_ref().map_or(false, |name| name.len() > u8::MAX as usize) {
+            return Err(InviteError::TooLong);
+        }
+        let mut invite = Invite {
+            addresses,
+            public_key: identity.public_key().to_vec(),
+            name,
+            expires,
+            signature: vec![],
+        };
+        invite.signature = identity.sign(&invite.signed_bytes());
+        Ok(invite)
+    }
+
+    pub fn peer_id(&self) -> PeerId {
+        PeerId::from_public_key(&self.public_key)
+    }
+
+    pub fn public_key_hex(&self) -> String {
+        self.public_key.to_hex()
+    }
+
+    /// an unsigned record for `Db` at the first address, reachable at the
+    /// rest, `None` if the invite has no addresses
+    pub fn peer(&self) -> Option<Peer> {
+        let (primary, others) = self.addresses.split_first()?;
+        let peer = Peer::new(*primary, true, self.name.clone(), Some(self.public_key_hex()), None);
+        Some(peer.with_other_addresses(others.to_vec()))
+    }
+
+    fn signed_bytes(&self) -> BytesMut {
+        let mut buf = BytesMut::new();
+        buf.put_u8(INVITE_VERSION);
+        buf.put_u8(self.addresses.len() as u8);
+        for addr in &self.addresses {
+            let ip = match addr.ip() {
+                IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+                IpAddr::V6(ip) => ip,
+            };
+            buf.put(&ip.octets()[..]);
+            buf.put_u16(addr.port());
+        }
+        buf.put(&self.public_key[..]);
+        let name = self.name.as_deref().unwrap_or("");
+        buf.put_u8(name.len() as u8);
+        buf.put(name.as_bytes());
+        buf.put_u64(self.expires.unwrap_or(0));
+        buf
+    }
+
+    pub fn encode(&self) -> String {
+        let mut bytes = self.signed_bytes();
+        bytes.put(&self.signature[..]);
+        format!("{}{}", INVITE_PREFIX, base32_encode(&bytes))
+    }
+
+    /// Parse and check an invite, `now` in unix seconds.
+    pub fn decode(code: &str, now: u64) -> Result<Self, InviteError> {
+        let code = code.trim().to_lowercase();
+        if !code.starts_with(INVITE_PREFIX) {
+            return Err(InviteError::Malformed);
+        }
+        let bytes = base32_decode(&code[INVITE_PREFIX.len()..]).ok_or(InviteError::Malformed)?;
+        let mut reader = Reader { bytes: &bytes };
+        if reader.take(1)?[0] != INVITE_VERSION {
+            return Err(InviteError::Malformed);
+        }
+        let count = reader.take(1)?[0];
+        let mut addresses = vec![];
+        for _ in 0..count {
+            let mut ip = [0u8; 16];
+            ip.copy_from_slice(reader.take(16)?);
+            let ip = Ipv6Addr::from(ip);
+            let ip = match ip.segments() {
+                [0, 0, 0, 0, 0, 0xffff, ..] => IpAddr::V4(ip.to_ipv4().unwrap()),
+                _ => IpAddr::V6(ip),
+            };
+            let port = reader.take(2)?;
+            addresses.push(SocketAddr::new(ip, u16::from_be_bytes([port[0], port[1]])));
+        }
+        let public_key = reader.take(PUBLIC_KEY_LEN)?.to_vec();
+        let name_len = reader.take(1)?[0] as usize;
+        let name = match String::from_utf8(reader.take(name_len)?.to_vec()) {
+            Ok(name) if name.is_empty() => None,
+            Ok(name) => Some(name),
+            Err(_) => return Err(InviteError::Malformed),
+        };
+        let mut expires = [0u8; 8];
+        expires.copy_from_slice(reader.take(8)?);
+        let expires = match u64::from_be_bytes(expires) {
+            0 => None,
+            expires => Some(expires),
+        };
+        let signature = reader.take(SIGNATURE_LEN)?.to_vec();
+        let invite = Invite { addresses, public_key, name, expires, signature };
+
+        verify_with_public_key(&invite.public_key, &invite.signed_bytes(), &invite.signature)
+            .map_err(|_| InviteError::BadSignature)?;
+        if invite.expires.map_or(false, |expires| expires < now) {
+            return Err(InviteError::Expired);
+        }
+        Ok(invite)
+    }
+
+    pub fn decode_now(code: &str) -> Result<Self, InviteError> {
+        Invite::decode(code, Utc::now().timestamp() as u64)
+    }
+}
+
+struct Reader<'a> {
+    bytes: &'a [u8],
+}
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Result<&'a [u8], InviteError> {
+        if self.bytes.len() < n {
+            return Err(InviteError::Malformed);
+        }
+        let (head, tail) = self.bytes.split_at(n);
+        self.bytes = tail;
+        Ok(head)
+    }
+}
+
+/// RFC 4648 base32, lowercase and unpadded
+fn base32_encode(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    let (mut buffer, mut bits) = (0u32, 0);
+    for byte in bytes {
+        buffer = (buffer << 8) | *byte as u32;
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32_ALPHABET[((buffer >> bits) & 31) as usize] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32_ALPHABET[((buffer << (5 - bits)) & 31) as usize] as char);
+    }
+    out
+}
+
+fn base32_decode(text: &str) -> Option<Vec<u8>> {
+    let mut out = vec![];
+    let (mut buffer, mut bits) = (0u32, 0);
+    for c in text.bytes() {
+        let value = BASE32_ALPHABET.iter().position(|a| *a == c)? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn test_base32() {
+        assert_eq!(base32_encode(b"foobar"), "mzxw6ytboi");
+        assert_eq!(base32_decode("mzxw6ytboi").unwrap(), b"foobar");
+        assert!(base32_decode("not base32!").is_none());
+    }
+
+    #[test]
+    fn test_invite() {
+        let identity = Identity::from_pkcs8(&crate::signature::generate_bytes()).unwrap();
+        let addresses = vec![
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 5)), 8081),
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 8081),
+        ];
+        let invite = Invite::new(&identity, addresses.clone(), Some("alice".into()), Some(1_000)).unwrap();
+        let code = invite.encode();
+        assert!(code.starts_with(INVITE_PREFIX));
+
+        let redeemed = Invite::decode(&code, 999).unwrap();
+        assert_eq!(redeemed, invite);
+        assert_eq!(redeemed.peer_id(), identity.peer_id());
+        let peer = redeemed.peer().unwrap();
+        assert_eq!(peer.id(), Some(identity.peer_id()));
+        assert_eq!(peer.addr(), addresses[0]);
+        assert_eq!(peer.other_addresses(), &addresses[1..]);
+        assert_eq!(Invite::decode(&code, 1_001), Err(InviteError::Expired));
+
+        // flip one character of the payload
+        let mut tampered = code.into_bytes();
+        let i = INVITE_PREFIX.len() + 10;
+        tampered[i] = if tampered[i] == b'a' { b'b' } else { b'a' };
+        let tampered = String::from_utf8(tampered).unwrap();
+        assert!(Invite::decode(&tampered, 999).is_err());
+        assert_eq!(Invite::decode("snob-", 0), Err(InviteError::Malformed));
+    }
+
+    #[test]
+    fn test_invite_limits() {
+        let identity = Identity::from_pkcs8(&crate::signature::generate_bytes()).unwrap();
+        let addresses: Vec<SocketAddr> = (0..256)
+            .map(|port| SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), port))
+            .collect();
+        assert_eq!(Invite::new(&identity, addresses.clone(), None, None), Err(InviteError::TooLong));
+        assert!(Invite::new(&identity, addresses[..255].to_vec(), None, None).is_ok());
+
+        let name = "a".repeat(256);
+        assert_eq!(Invite::new(&identity, vec![addresses[0]], Some(name), None), Err(InviteError::TooLong));
+        let name = "a".repeat(255);
+        let invite = Invite::new(&identity, vec![addresses[0]], Some(name.clone()), None).unwrap();
+        assert_eq!(Invite::decode(&invite.encode(), 0).unwrap().name, Some(name));
+    }
+}
@@ -0,0 +1,142 @@
+use bytes::{BytesMut, BufMut};
+use chrono::Utc;
+use rustc_serialize::hex::{FromHex, ToHex};
+use serde::{Deserialize, Serialize};
+
+use super::{take_nstring, take_u8, take_u64, MessageCodecError, PeerId, RecordError, MAX_CLOCK_SKEW_SECS};
+use crate::identity::Identity;
+use crate::signature::verify_with_public_key;
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize, Serialize)]
+pub enum KeyAction {
+    /// planned move to the successor key
+    Rotation,
+    /// the key is compromised, never accept it again. Rotations it made
+    /// are dropped unless they went to the successor named here. The thief
+    /// could name its own key too, so the successor is only a hint and
+    /// gains no trust.
+    Revocation,
+}
+
+/// A statement about `key`, signed by that same key.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct KeyCertificate {
+    pub action: KeyAction,
+    key: String,               // hex, the key being retired
+    successor: Option<String>, // hex
+    pub timestamp: u64,
+    signature: String,
+}
+
+impl KeyCertificate {
+    pub fn new(action: KeyAction, old: &Identity, successor: Option<&Identity>) -> Self {
+        let mut certificate = KeyCertificate {
+            action,
+            key: old.public_key_hex(),
+            successor: successor.map(|s| s.public_key_hex()),
+            timestamp: Utc::now().timestamp() as u64,
+            signature: String::new(),
+        };
+        certificate.signature = old.sign(&certificate.signed_bytes()).to_hex();
+        certificate
+    }
+
+    /// id of the retired key
+    pub fn subject(&self) -> Option<PeerId> {
+        PeerId::from_public_key_hex(&self.key)
+    }
+
+    pub fn successor(&self) -> Option<PeerId> {
+        self.successor.as_ref().and_then(|key| PeerId::from_public_key_hex(key))
+    }
+
+    fn signed_bytes(&self) -> BytesMut {
+        let mut buf = BytesMut::new();
+        buf.put_u8(match self.action {
+            KeyAction::Rotation => 0,
+            KeyAction::Revocation => 1,
+        });
+        for field in &[Some(&self.key), self.successor.as_ref()] {
+            let field = field.map_or("", |f| f.as_str());
+            buf.put_u64(field.len() as u64);
+            buf.put(field.as_bytes());
+        }
+        buf.put_u64(self.timestamp);
+        buf
+    }
+
+    /// Check the retired key signed it, `now` in unix seconds.
+    pub fn verify(&self, now: u64) -> Result<(), RecordError> {
+        if self.action == KeyAction::Rotation && self.successor.is_none() {
+            return Err(RecordError::Unsigned);
+        }
+        let public_key = self.key.from_hex().map_err(|_| RecordError::Unsigned)?;
+        let signature = self.signature.from_hex().map_err(|_| RecordError::Unsigned)?;
+        verify_with_public_key(&public_key, &self.signed_bytes(), &signature)
+            .map_err(|_| RecordError::BadSignature)?;
+        if self.timestamp > now + MAX_CLOCK_SKEW_SECS {
+            return Err(RecordError::FromFuture);
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> BytesMut {
+        let mut buf = self.signed_bytes();
+        buf.put_u8(self.signature.len() as u8);
+        buf.put(self.signature.as_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &mut BytesMut) -> Result<Self, MessageCodecError> {
+        let action = match take_u8(buf)? {
+            0 => KeyAction::Rotation,
+            _ => KeyAction::Revocation,
+        };
+        let key_len = take_u64(buf)? as usize;
+        let key = take_nstring(buf, key_len)?.unwrap_or_default();
+        let successor_len = take_u64(buf)? as usize;
+        let successor = take_nstring(buf, successor_len)?;
+        let timestamp = take_u64(buf)?;
+        let signature_len = take_u8(buf)? as usize;
+        let signature = take_nstring(buf, signature_len)?.unwrap_or_default();
+        Ok(KeyCertificate {
+            action,
+            key,
+            successor,
+            timestamp,
+            signature,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity() -> Identity {
+        Identity::from_pkcs8(&crate::signature::generate_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_key_certificate() {
+        let old = identity();
+        let new = identity();
+        let rotation = KeyCertificate::new(KeyAction::Rotation, &old, Some(&new));
+        let now = rotation.timestamp;
+        assert_eq!(rotation.verify(now), Ok(()));
+        assert_eq!(rotation.subject(), Some(old.peer_id()));
+        assert_eq!(rotation.successor(), Some(new.peer_id()));
+
+        let mut bytes = rotation.to_bytes();
+        assert_eq!(KeyCertificate::from_bytes(&mut bytes).unwrap(), rotation);
+
+        let revocation = KeyCertificate::new(KeyAction::Revocation, &old, None);
+        let mut bytes = revocation.to_bytes();
+        assert_eq!(KeyCertificate::from_bytes(&mut bytes).unwrap().verify(now), Ok(()));
+
+        // only the retired key can speak for itself
+        let mut forged = KeyCertificate::new(KeyAction::Rotation, &new, Some(&new));
+        forged.key = old.public_key_hex();
+        assert_eq!(forged.verify(now), Err(RecordError::BadSignature));
+    }
+}
@@ -0,0 +1,86 @@
+// smoothing factors from RFC 6298
+const ALPHA: f64 = 0.125;
+const BETA: f64 = 0.25;
+
+/// Rolling round trip estimate for a peer, updated from Ping/Pong.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Latency {
+    pub srtt: Option<f64>, // millis
+    pub jitter: f64,       // millis
+    pub samples: u32,
+    pub missed: u32,
+    pub healthy: bool,
+}
+
+impl Latency {
+    pub fn new() -> Self {
+        Latency {
+            srtt: None,
+            jitter: 0.0,
+            samples: 0,
+            missed: 0,
+            healthy: true,
+        }
+    }
+
+    pub fn sample(&mut self, rtt: f64) {
+        match self.srtt {
+            None => {
+                self.srtt = Some(rtt);
+                self.jitter = rtt / 2.0;
+            },
+            Some(srtt) => {
+                self.jitter = (1.0 - BETA) * self.jitter + BETA * (srtt - rtt).abs();
+                self.srtt = Some((1.0 - ALPHA) * srtt + ALPHA * rtt);
+            },
+        }
+        self.samples = self.samples.saturating_add(1);
+        self.missed = 0;
+        self.healthy = true;
+    }
+
+    /// a ping went unanswered past the deadline
+    pub fn timed_out(&mut self) {
+        self.missed = self.missed.saturating_add(1);
+        self.healthy = false;
+    }
+}
+
+impl Default for Latency {
+    fn default() -> Self {
+        Latency::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_estimate() {
+        let mut latency = Latency::new();
+        latency.sample(100.0);
+        assert_eq!(latency.srtt, Some(100.0));
+        assert_eq!(latency.jitter, 50.0);
+        latency.sample(200.0);
+        assert_eq!(latency.srtt, Some(112.5));
+        assert_eq!(latency.jitter, 62.5);
+        for _ in 0..100 {
+            latency.sample(20.0);
+        }
+        assert!((latency.srtt.unwrap() - 20.0).abs() < 1.0);
+        assert!(latency.jitter < 1.0);
+    }
+
+    #[test]
+    fn test_health() {
+        let mut latency = Latency::new();
+        latency.timed_out();
+        latency.timed_out();
+        assert_eq!(latency.missed, 2);
+        assert!(!latency.healthy);
+        latency.sample(10.0);
+        assert!(latency.healthy);
+        assert_eq!(latency.missed, 0);
+    }
+}
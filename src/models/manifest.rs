@@ -0,0 +1,127 @@
+use std::net::SocketAddr;
+
+use bytes::{BytesMut, BufMut};
+use chrono::{DateTime, Utc};
+use rustc_serialize::hex::{FromHex, ToHex};
+use serde::{Deserialize, Serialize};
+
+use super::{take_nstring, take_u8, take_u64, ArtistData, MessageCodecError, PeerId, RecordError, MAX_CLOCK_SKEW_SECS};
+use crate::identity::Identity;
+use crate::signature::verify_with_public_key;
+
+/// A peer's catalogue signed with its key, so it can be cached and relayed
+/// by others. Higher versions replace lower ones.
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Manifest {
+    owner_key: String, // hex encoded public key
+    pub version: u64,
+    pub timestamp: u64, // unix seconds
+    pub artists: Vec<ArtistData>,
+    signature: String,
+}
+
+impl Manifest {
+    pub fn signed(identity: &Identity, version: u64, artists: Vec<ArtistData>) -> Self {
+        let mut manifest = Manifest {
+            owner_key: identity.public_key_hex(),
+            version,
+            timestamp: Utc::now().timestamp() as u64,
+            artists,
+            signature: String::new(),
+        };
+        manifest.signature = identity.sign(&manifest.signed_bytes()).to_hex();
+        manifest
+    }
+
+    pub fn owner(&self) -> Option<PeerId> {
+        PeerId::from_public_key_hex(&self.owner_key)
+    }
+
+    fn signed_bytes(&self) -> BytesMut {
+        let mut buf = BytesMut::new();
+        buf.put_u64(self.owner_key.len() as u64);
+        buf.put(self.owner_key.as_bytes());
+        buf.put_u64(self.version);
+        buf.put_u64(self.timestamp);
+        buf.put_u64(self.artists.len() as u64);
+        for artist in &self.artists {
+            buf.extend_from_slice(&artist.to_bytes()[..]);
+        }
+        buf
+    }
+
+    /// Check the owner's signature, `now` in unix seconds.
+    pub fn verify(&self, now: u64) -> Result<(), RecordError> {
+        let public_key = self.owner_key.from_hex().map_err(|_| RecordError::Unsigned)?;
+        let signature = self.signature.from_hex().map_err(|_| RecordError::Unsigned)?;
+        verify_with_public_key(&public_key, &self.signed_bytes(), &signature)
+            .map_err(|_| RecordError::BadSignature)?;
+        if self.timestamp > now + MAX_CLOCK_SKEW_SECS {
+            return Err(RecordError::FromFuture);
+        }
+        Ok(())
+    }
+
+    pub fn to_bytes(&self) -> BytesMut {
+        let mut buf = self.signed_bytes();
+        buf.put_u8(self.signature.len() as u8);
+        buf.put(self.signature.as_bytes());
+        buf
+    }
+
+    pub fn from_bytes(buf: &mut BytesMut) -> Result<Self, MessageCodecError> {
+        let key_len = take_u64(buf)? as usize;
+        let owner_key = take_nstring(buf, key_len)?.unwrap_or_default();
+        let version = take_u64(buf)?;
+        let timestamp = take_u64(buf)?;
+        let mut artist_count = take_u64(buf)?;
+        let mut artists = vec![];
+        while artist_count > 0 {
+            artists.push(ArtistData::from_bytes(buf)?);
+            artist_count -= 1;
+        }
+        let signature_len = take_u8(buf)? as usize;
+        let signature = take_nstring(buf, signature_len)?.unwrap_or_default();
+        Ok(Manifest {
+            owner_key,
+            version,
+            timestamp,
+            artists,
+            signature,
+        })
+    }
+}
+
+/// Where and when we got a manifest, the owner or a relay.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Provenance {
+    pub from: SocketAddr,
+    pub received: DateTime<Utc>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_manifest() {
+        let identity = Identity::from_pkcs8(&crate::signature::generate_bytes()).unwrap();
+        let artists = vec![ArtistData::new("artist".to_string(), None)];
+        let manifest = Manifest::signed(&identity, 3, artists);
+        let now = manifest.timestamp;
+        assert_eq!(manifest.verify(now), Ok(()));
+        assert_eq!(manifest.owner(), Some(identity.peer_id()));
+
+        let mut bytes = manifest.to_bytes();
+        let relayed = Manifest::from_bytes(&mut bytes).unwrap();
+        assert_eq!(relayed, manifest);
+        assert_eq!(relayed.verify(now), Ok(()));
+
+        let mut tampered = manifest.clone();
+        tampered.artists.push(ArtistData::new("other".to_string(), None));
+        assert_eq!(tampered.verify(now), Err(RecordError::BadSignature));
+        let mut bumped = manifest;
+        bumped.version += 1;
+        assert_eq!(bumped.verify(now), Err(RecordError::BadSignature));
+    }
+}
@@ -1,7 +1,19 @@
 mod utils;
+mod ban;
+mod trust;
+mod reputation;
+mod heartbeat;
+mod latency;
 mod data;
+mod catalogue;
 mod service;
 mod peer;
+mod peer_id;
+mod manifest;
+mod delta;
+mod event;
+mod key_certificate;
+mod invite;
 mod peer_connection;
 
 pub use self::service::Service;
@@ -13,10 +25,22 @@ pub use self::data::{
 };
 
 pub use self::utils::{
-    get_nstring,
     take_u64,
-    bytes_to_ip_addr,
+    MessageCodecError,
 };
+pub(crate) use self::utils::{take_nstring, take_socket_addr, take_u8, take_u16, take_u32};
 
-pub use self::peer_connection::PeerConnection;
-pub use self::peer::Peer;
+pub use self::peer_connection::{Message, PeerConnection};
+pub use self::peer::{Peer, RecordError, MAX_CLOCK_SKEW_SECS};
+pub use self::peer_id::{PeerId, PeerKey};
+pub use self::manifest::{Manifest, Provenance};
+pub use self::catalogue::Catalogue;
+pub use self::delta::CollectionDelta;
+pub use self::event::{Event, EventFeed};
+pub use self::key_certificate::{KeyAction, KeyCertificate};
+pub use self::invite::{Invite, InviteError};
+pub use self::ban::{Ban, BanList};
+pub use self::trust::TrustStore;
+pub use self::reputation::Reputation;
+pub use self::heartbeat::Heartbeat;
+pub use self::latency::Latency;
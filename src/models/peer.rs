@@ -1,19 +1,52 @@
-use std::convert::TryInto;
-
 use bytes::{BytesMut, BufMut};
 
+use std::fmt;
 use std::str;
 use std::net::{
     SocketAddr,
     IpAddr,
     Ipv6Addr,
 };
+use chrono::Utc;
+use rustc_serialize::hex::{FromHex, ToHex};
 use serde::{Deserialize, Serialize};
 use super::{
+    take_nstring,
+    take_socket_addr,
+    take_u8,
+    take_u32,
     take_u64,
-    get_nstring,
-    bytes_to_ip_addr,
+    MessageCodecError,
+    PeerId,
 };
+use crate::identity::Identity;
+use crate::signature::verify_with_public_key;
+
+/// signed records older than this are refused, owners re-sign on every ping
+pub const MAX_RECORD_AGE_SECS: u64 = 24 * 60 * 60;
+/// how far ahead of our clock a record's timestamp may be
+pub const MAX_CLOCK_SKEW_SECS: u64 = 5 * 60;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum RecordError {
+    Unsigned,
+    BadSignature,
+    Stale,
+    FromFuture,
+    Revoked,
+}
+
+impl fmt::Display for RecordError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RecordError::Unsigned => write!(f, "unsigned peer record"),
+            RecordError::BadSignature => write!(f, "bad peer record signature"),
+            RecordError::Stale => write!(f, "stale peer record"),
+            RecordError::FromFuture => write!(f, "peer record from the future"),
+            RecordError::Revoked => write!(f, "revoked key"),
+        }
+    }
+}
 
 #[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 pub struct Peer {
@@ -21,7 +54,12 @@ pub struct Peer {
     accept_incoming: bool,
     pub name: Option<String>,
     public_key: Option<String>,
+    /// unix seconds when the owner signed the record
+    timestamp: u64,
     signature: Option<String>, // sign to prove they have private key
+    /// where else the peer can be reached, kept locally and not signed
+    #[serde(default)]
+    other_addresses: Vec<SocketAddr>,
 }
 
 impl Peer {
@@ -31,25 +69,105 @@ impl Peer {
             accept_incoming,
             name,
             public_key,
+            timestamp: 0,
             signature,
+            other_addresses: vec![],
         }
     }
 
-    pub fn get_self() -> Self {
-        // TODO: get ip address and port on init
-        let ip = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 2)), 8000);
-        Peer::new(
-            ip,
-            false,
-            Some("TEST".into()),
-            None,
-            Some("ZYX987".into()))
+    /// A record for `identity`, timestamped and signed with its key.
+    pub fn signed(address: SocketAddr, accept_incoming: bool, name: Option<String>, identity: &Identity) -> Self {
+        let mut peer = Peer::new(address, accept_incoming, name, Some(identity.public_key_hex()), None);
+        peer.timestamp = Utc::now().timestamp() as u64;
+        peer.signature = Some(identity.sign(&peer.signed_bytes()).to_hex());
+        peer
+    }
+
+    /// Our own contact card, freshly signed.
+    pub fn get_self(identity: &Identity, port: u16) -> Self {
+        // TODO: get the public ip address on init
+        let ip = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), port);
+        Peer::signed(ip, true, Some(identity.peer_id().to_string()), identity)
+    }
+
+    /// the fields covered by the signature
+    fn signed_bytes(&self) -> BytesMut {
+        let mut buf = BytesMut::new();
+        let ip = match self.address.ip() {
+            IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+            IpAddr::V6(ip) => ip,
+        };
+        buf.put(&ip.octets()[..]);
+        buf.put_u16(self.address.port());
+        for field in &[&self.name, &self.public_key] {
+            let field = field.as_deref().unwrap_or("");
+            buf.put_u64(field.len() as u64);
+            buf.put(field.as_bytes());
+        }
+        buf.put_u64(self.timestamp);
+        buf
+    }
+
+    /// Check the signature against the record's own public key and that
+    /// the record is neither stale nor from the future, `now` in unix seconds.
+    pub fn verify(&self, now: u64) -> Result<(), RecordError> {
+        let public_key = self.public_key.as_ref().and_then(|key| key.from_hex().ok());
+        let signature = self.signature.as_ref().and_then(|sig| sig.from_hex().ok());
+        let (public_key, signature) = match (public_key, signature) {
+            (Some(public_key), Some(signature)) => (public_key, signature),
+            _ => return Err(RecordError::Unsigned),
+        };
+        verify_with_public_key(&public_key, &self.signed_bytes(), &signature)
+            .map_err(|_| RecordError::BadSignature)?;
+        if self.timestamp > now + MAX_CLOCK_SKEW_SECS {
+            return Err(RecordError::FromFuture);
+        }
+        if self.timestamp + MAX_RECORD_AGE_SECS < now {
+            return Err(RecordError::Stale);
+        }
+        Ok(())
+    }
+
+    /// whether `proof` is `challenge` signed by the record's own key
+    pub fn verify_proof(&self, challenge: &[u8], proof: &[u8]) -> bool {
+        match self.public_key.as_ref().and_then(|key| key.from_hex().ok()) {
+            Some(public_key) => verify_with_public_key(&public_key, challenge, proof).is_ok(),
+            None => false,
+        }
+    }
+
+    pub fn addr(&self) -> SocketAddr {
+        self.address
+    }
+
+    pub fn other_addresses(&self) -> &[SocketAddr] {
+        &self.other_addresses
+    }
+
+    /// the same record, also reachable at `addresses`
+    pub fn with_other_addresses(mut self, addresses: Vec<SocketAddr>) -> Self {
+        self.other_addresses = addresses;
+        self
+    }
+
+    pub fn timestamp(&self) -> u64 {
+        self.timestamp
+    }
+
+    /// Key derived id, `None` for peers that have not sent a public key.
+    pub fn id(&self) -> Option<PeerId> {
+        self.public_key.as_ref().and_then(|key| PeerId::from_public_key_hex(key))
+    }
+
+    pub fn public_key(&self) -> Option<&str> {
+        self.public_key.as_deref()
     }
 
     pub fn to_bytes(&self) -> BytesMut {
         let mut buf = BytesMut::new();
         let ip_bytes = match self.address.ip() {
-            IpAddr::V4(ip) => ip.octets().to_vec(),
+            // always 16 bytes on the wire, see `bytes_to_ip_addr`
+            IpAddr::V4(ip) => ip.to_ipv6_mapped().octets().to_vec(),
             IpAddr::V6(ip) => ip.octets().to_vec(),
         };
         let len = ip_bytes.len();
@@ -62,49 +180,92 @@ impl Peer {
         } else {
             buf.put_u8(0);
         };
+        buf.put_u64(self.timestamp);
 
-        // TODO: factor this out
-        if let Some(name) = &self.name {
-            let name_length: u8 = name.len().try_into().unwrap();
-            buf.put_u8(name_length);
-            buf.put(name.as_bytes());
-        } else {
-            buf.put_u8(0);
-        };
-        if let Some(public_key) = &self.public_key {
-            let public_key_length: u8 = public_key.len().try_into().unwrap();
-            buf.put_u8(public_key_length);
-            buf.put(public_key.as_bytes());
-        } else {
-            buf.put_u8(0);
-        };
-        if let Some(signature) = &self.signature {
-            let signature_length: u8 = signature.len().try_into().unwrap();
-            buf.put_u8(signature_length);
-            buf.put(signature.as_bytes());
-        } else {
-            buf.put_u8(0);
-        };
+        for field in &[&self.name, &self.public_key, &self.signature] {
+            let field = field.as_deref().unwrap_or("");
+            buf.put_u32(field.len() as u32);
+            buf.put(field.as_bytes());
+        }
         buf
     }
 
-    pub fn from_bytes(buf: &mut BytesMut) -> Self {
-        let _ip_len = take_u64(buf).unwrap();
-        let address = bytes_to_ip_addr(buf);
-        let accept_incoming_byte = buf.split_to(1)[0] as u8;
-        let accept_incoming = accept_incoming_byte == 1u8;
-        let name_key = buf.split_to(1)[0] as usize;
-        let name = get_nstring(buf, name_key);
-        let pk_key = buf.split_to(1)[0] as usize;
-        let public_key = get_nstring(buf, pk_key);
-        let signature_key = buf.split_to(1)[0] as usize;
-        let signature = get_nstring(buf, signature_key);
-        Peer {
+    pub fn from_bytes(buf: &mut BytesMut) -> Result<Self, MessageCodecError> {
+        Peer::decode(buf, |buf| Ok(take_u32(buf)? as usize))
+    }
+
+    /// A record as saved before the name, key and signature lengths were
+    /// widened from a u8, see `storage::format`.
+    pub fn from_narrow_bytes(buf: &mut BytesMut) -> Result<Self, MessageCodecError> {
+        Peer::decode(buf, |buf| Ok(take_u8(buf)? as usize))
+    }
+
+    fn decode(buf: &mut BytesMut, take_len: fn(&mut BytesMut) -> Result<usize, MessageCodecError>) -> Result<Self, MessageCodecError> {
+        let _ip_len = take_u64(buf)?;
+        let address = take_socket_addr(buf)?;
+        let accept_incoming = take_u8(buf)? == 1u8;
+        let timestamp = take_u64(buf)?;
+        let name_len = take_len(buf)?;
+        let name = take_nstring(buf, name_len)?;
+        let public_key_len = take_len(buf)?;
+        let public_key = take_nstring(buf, public_key_len)?;
+        let signature_len = take_len(buf)?;
+        let signature = take_nstring(buf, signature_len)?;
+        Ok(Peer {
             address,
             accept_incoming,
             name,
             public_key,
+            timestamp,
             signature,
-        }
+            other_addresses: vec![],
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn identity() -> Identity {
+        Identity::from_pkcs8(&crate::signature::generate_bytes()).unwrap()
+    }
+
+    #[test]
+    fn test_signed_record() {
+        let identity = identity();
+        let addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 8000);
+        let peer = Peer::signed(addr, true, Some("me".into()), &identity);
+        let now = peer.timestamp();
+        assert_eq!(peer.verify(now), Ok(()));
+        assert_eq!(peer.id(), Some(identity.peer_id()));
+        assert_eq!(peer.verify(now + MAX_RECORD_AGE_SECS + 1), Err(RecordError::Stale));
+        assert_eq!(peer.verify(now - MAX_CLOCK_SKEW_SECS - 1), Err(RecordError::FromFuture));
+
+        let mut bytes = peer.to_bytes();
+        assert_eq!(Peer::from_bytes(&mut bytes).unwrap(), peer);
+        let mut truncated = peer.to_bytes();
+        truncated.truncate(40);
+        assert!(Peer::from_bytes(&mut truncated).is_err());
+    }
+
+    #[test]
+    fn test_forged_record() {
+        let identity = identity();
+        let addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 8000);
+        let peer = Peer::signed(addr, true, None, &identity);
+        let now = peer.timestamp();
+
+        let mut hijacked = peer.clone();
+        hijacked.address = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 9000);
+        assert_eq!(hijacked.verify(now), Err(RecordError::BadSignature));
+
+        // someone else's key with our signature
+        let mut borrowed = Peer::signed(addr, true, None, &self::identity());
+        borrowed.signature = peer.signature.clone();
+        assert_eq!(borrowed.verify(now), Err(RecordError::BadSignature));
+
+        let unsigned = Peer::new(addr, true, None, peer.public_key.clone(), None);
+        assert_eq!(unsigned.verify(now), Err(RecordError::Unsigned));
     }
 }
@@ -5,27 +5,35 @@
 /// get user's file list
 /// ask if user has file
 
-use super::service::{Service, Rx};
+use super::service::Service;
 
-use std::sync::Arc;
+use std::error::Error;
 use std::pin::Pin;
 use std::task::{Context, Poll};
 
 use futures::SinkExt;
 use futures::sink::Send;
-use tokio::prelude::*;
-use tokio::sync::{mpsc, Mutex};
+use tokio::sync::mpsc;
 use tokio::stream::Stream;
 use tokio_util::codec::Framed;
 
 use tokio::net::TcpStream;
 
+use crate::actors::Rx;
 use crate::codec::{
     MessageEvent,
     MessageCodec,
     MessageCodecError,
 };
 
+/// Either a message read off the socket, or one another task wants sent
+/// to this peer through the registry.
+#[derive(Debug, PartialEq)]
+pub enum Message {
+    Broadcast(MessageEvent),
+    Received(MessageEvent),
+}
+
 // TODO: rename this?
 pub struct PeerConnection {
     messages: Framed<TcpStream, MessageCodec>,
@@ -34,12 +42,12 @@ pub struct PeerConnection {
 
 impl PeerConnection {
     pub async fn new(
-        state: Arc<Mutex<Service>>,
+        service: &Service,
         messages: Framed<TcpStream, MessageCodec>,
-    ) -> io::Result<PeerConnection> {
+    ) -> Result<PeerConnection, Box<dyn Error>> {
         let addr = messages.get_ref().peer_addr()?;
         let (tx, rx) = mpsc::unbounded_channel();
-        state.lock().await.peers.insert(addr, tx);
+        service.registry.call(move |registry| registry.register(addr, tx)).await?;
         Ok( PeerConnection { messages, rx })
     }
 
@@ -48,19 +56,24 @@ impl PeerConnection {
     {
         self.messages.send(message)
     }
+
+    /// bytes sent and received since the last call
+    pub fn take_traffic(&mut self) -> (u64, u64) {
+        self.messages.codec_mut().take_traffic()
+    }
 }
 
 impl Stream for PeerConnection {
-    type Item = Result<MessageEvent, MessageCodecError>;
+    type Item = Result<Message, MessageCodecError>;
 
     fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<Self::Item>> {
         if let Poll::Ready(Some(v)) = Pin::new(&mut self.rx).poll_next(cx) {
-            return Poll::Ready(Some(Ok(v)));
+            return Poll::Ready(Some(Ok(Message::Broadcast(v))));
         }
 
         let result: Option<_> = futures::ready!(Pin::new(&mut self.messages).poll_next(cx));
         Poll::Ready(match result {
-            Some(Ok(message)) => Some(Ok(message)),
+            Some(Ok(message)) => Some(Ok(Message::Received(message))),
             Some(Err(e)) => Some(Err(e)),
             None => None,
         })
@@ -0,0 +1,91 @@
+use std::fmt;
+use std::net::SocketAddr;
+
+use ring::digest::{digest, SHA256};
+use rustc_serialize::hex::{FromHex, ToHex};
+use serde::{Deserialize, Serialize};
+
+pub const PEER_ID_LEN: usize = 16;
+
+/// Stable peer identifier, the first 16 bytes of the SHA-256 of the
+/// peer's Ed25519 public key.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, Ord, PartialOrd, Deserialize, Serialize)]
+pub struct PeerId([u8; PEER_ID_LEN]);
+
+impl PeerId {
+    pub fn from_public_key(public_key: &[u8]) -> Self {
+        let hash = digest(&SHA256, public_key);
+        let mut id = [0u8; PEER_ID_LEN];
+        id.copy_from_slice(&hash.as_ref()[..PEER_ID_LEN]);
+        PeerId(id)
+    }
+
+    /// from a hex encoded public key, as carried in `Peer`
+    pub fn from_public_key_hex(public_key: &str) -> Option<Self> {
+        public_key.from_hex().ok().map(|bytes| PeerId::from_public_key(&bytes))
+    }
+
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.0
+    }
+
+    pub fn from_bytes(bytes: &[u8]) -> Option<Self> {
+        if bytes.len() != PEER_ID_LEN {
+            return None;
+        }
+        let mut id = [0u8; PEER_ID_LEN];
+        id.copy_from_slice(bytes);
+        Some(PeerId(id))
+    }
+}
+
+impl fmt::Display for PeerId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0.to_hex())
+    }
+}
+
+/// What a peer is known by: its id, or its address if it has sent no
+/// public key. Its other addresses find it too, see `Node for Peer`.
+#[derive(Clone, Copy, Debug, Hash, Eq, PartialEq, Ord, PartialOrd)]
+pub enum PeerKey {
+    Id(PeerId),
+    Addr(SocketAddr),
+}
+
+impl From<PeerId> for PeerKey {
+    fn from(id: PeerId) -> Self {
+        PeerKey::Id(id)
+    }
+}
+
+impl From<SocketAddr> for PeerKey {
+    fn from(addr: SocketAddr) -> Self {
+        PeerKey::Addr(addr)
+    }
+}
+
+impl fmt::Display for PeerKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PeerKey::Id(id) => write!(f, "{}", id),
+            PeerKey::Addr(addr) => write!(f, "{}", addr),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_peer_id() {
+        let a = PeerId::from_public_key(b"first key");
+        let b = PeerId::from_public_key(b"second key");
+        assert_ne!(a, b);
+        assert_eq!(a, PeerId::from_public_key(b"first key"));
+        assert_eq!(Some(a), PeerId::from_public_key_hex(&b"first key".to_hex()));
+        assert_eq!(Some(a), PeerId::from_bytes(a.as_bytes()));
+        assert_eq!(a.to_string().len(), PEER_ID_LEN * 2);
+    }
+}
@@ -0,0 +1,186 @@
+use bytes::{BytesMut, BufMut};
+use chrono::{DateTime, TimeZone, Utc};
+
+use super::utils::{take_u32, take_u64};
+
+/// peers not heard from for longer than this are not given uptime credit
+pub const UPTIME_GAP_SECS: i64 = 60;
+
+const BASE_SCORE: f64 = 50.0;
+
+/// What we have learned about a peer from talking to it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Reputation {
+    pub uptime_secs: u64,
+    pub answered: u32,
+    pub bytes_received: u64,
+    pub transfer_millis: u64,
+    pub bad_chunks: u32,
+    pub last_seen: Option<DateTime<Utc>>,
+}
+
+impl Reputation {
+    pub fn new() -> Self {
+        Reputation {
+            uptime_secs: 0,
+            answered: 0,
+            bytes_received: 0,
+            transfer_millis: 0,
+            bad_chunks: 0,
+            last_seen: None,
+        }
+    }
+
+    /// the peer was online at `now`, credit the time since it was last seen
+    pub fn seen(&mut self, now: DateTime<Utc>) {
+        if let Some(last_seen) = self.last_seen {
+            let gap = (now - last_seen).num_seconds();
+            if gap > 0 && gap <= UPTIME_GAP_SECS {
+                self.uptime_secs += gap as u64;
+            }
+        }
+        self.last_seen = Some(now);
+    }
+
+    pub fn answered(&mut self) {
+        self.answered = self.answered.saturating_add(1);
+    }
+
+    pub fn transferred(&mut self, bytes: u64, millis: u64) {
+        self.bytes_received += bytes;
+        self.transfer_millis += millis;
+    }
+
+    pub fn bad_chunk(&mut self) {
+        self.bad_chunks = self.bad_chunks.saturating_add(1);
+    }
+
+    /// Fade what we learned towards a fresh peer, keeping `factor` of
+    /// every count, so old behaviour weighs less than recent.
+    pub fn decay(&mut self, factor: f64) {
+        let fade = |count: f64| (count * factor).floor();
+        self.uptime_secs = fade(self.uptime_secs as f64) as u64;
+        self.answered = fade(self.answered as f64) as u32;
+        self.bytes_received = fade(self.bytes_received as f64) as u64;
+        self.transfer_millis = fade(self.transfer_millis as f64) as u64;
+        self.bad_chunks = fade(self.bad_chunks as f64) as u32;
+    }
+
+    /// bytes per second over all transfers from this peer
+    pub fn throughput(&self) -> f64 {
+        if self.transfer_millis == 0 {
+            return 0.0;
+        }
+        self.bytes_received as f64 * 1000.0 / self.transfer_millis as f64
+    }
+
+    /// A score between 0 and 100, new peers start at 50. Each signal is
+    /// on a log scale so no single one can dominate.
+    ///
+    /// Throughput and bad chunks are kept but not scored: nothing
+    /// downloads yet, so they would only ever move in tests.
+    pub fn score(&self) -> f64 {
+        let uptime = (1.0 + self.uptime_secs as f64 / 3600.0).ln() * 5.0;
+        let answered = (1.0 + self.answered as f64).ln() * 3.0;
+        (BASE_SCORE + uptime + answered).max(0.0).min(100.0)
+    }
+
+    pub fn to_bytes(&self) -> BytesMut {
+        let mut buf = BytesMut::new();
+        buf.put_u64(self.uptime_secs);
+        buf.put_u32(self.answered);
+        buf.put_u64(self.bytes_received);
+        buf.put_u64(self.transfer_millis);
+        buf.put_u32(self.bad_chunks);
+        buf.put_i64(self.last_seen.map_or(0, |t| t.timestamp()));
+        buf
+    }
+
+    pub fn from_bytes(buf: &mut BytesMut) -> Self {
+        let uptime_secs = take_u64(buf).unwrap_or(0);
+        let answered = take_u32(buf).unwrap_or(0);
+        let bytes_received = take_u64(buf).unwrap_or(0);
+        let transfer_millis = take_u64(buf).unwrap_or(0);
+        let bad_chunks = take_u32(buf).unwrap_or(0);
+        let last_seen = match take_u64(buf).unwrap_or(0) as i64 {
+            0 => None,
+            secs => Some(Utc.timestamp(secs, 0)),
+        };
+        Reputation {
+            uptime_secs,
+            answered,
+            bytes_received,
+            transfer_millis,
+            bad_chunks,
+            last_seen,
+        }
+    }
+}
+
+impl Default for Reputation {
+    fn default() -> Self {
+        Reputation::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::Duration;
+
+    #[test]
+    fn test_score() {
+        let now = Utc::now();
+        let mut good = Reputation::new();
+        let fresh = Reputation::new();
+        assert_eq!(fresh.score(), BASE_SCORE);
+
+        good.seen(now);
+        good.seen(now + Duration::seconds(30));
+        good.answered();
+        good.transferred(1 << 20, 1000);
+        assert_eq!(good.uptime_secs, 30);
+        assert!(good.score() > fresh.score());
+
+        // not scored until downloads feed them
+        let mut bad = Reputation::new();
+        bad.bad_chunk();
+        bad.transferred(1 << 20, 1000);
+        assert_eq!(bad.score(), fresh.score());
+    }
+
+    #[test]
+    fn test_decay() {
+        let mut rep = Reputation::new();
+        for _ in 0..3 {
+            rep.answered();
+            rep.bad_chunk();
+        }
+        let before = rep.score();
+        rep.decay(0.5);
+        assert_eq!((rep.answered, rep.bad_chunks), (1, 1));
+        assert!(rep.score() < before);
+        rep.decay(0.5);
+        assert_eq!(rep, Reputation::new());
+    }
+
+    #[test]
+    fn test_uptime_gap() {
+        let now = Utc::now();
+        let mut rep = Reputation::new();
+        rep.seen(now);
+        rep.seen(now + Duration::seconds(UPTIME_GAP_SECS + 1));
+        assert_eq!(rep.uptime_secs, 0);
+    }
+
+    #[test]
+    fn test_bytes() {
+        let mut rep = Reputation::new();
+        rep.seen(Utc.timestamp(1_500_000_000, 0));
+        rep.answered();
+        rep.transferred(100, 10);
+        rep.bad_chunk();
+        let mut buf = rep.to_bytes();
+        assert_eq!(Reputation::from_bytes(&mut buf), rep);
+    }
+}
@@ -1,55 +1,79 @@
-use std::collections::HashMap;
-use std::net::SocketAddr;
-use tokio::sync::mpsc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
 
+use crate::actors::{Actor, Library, Registry, Transfers};
 use crate::storage::Db;
-use crate::models::{ArtistData, Peer};
-use crate::codec::MessageEvent;
-use crate::organizer::get_collection;
-use crate::args::Config;
+use tokio::sync::broadcast;
 
-type Tx = mpsc::UnboundedSender<MessageEvent>;
-pub type Rx = mpsc::UnboundedReceiver<MessageEvent>;
+use crate::models::{Event, EventFeed, Peer};
+use crate::args::Config;
+use crate::shutdown::Shutdown;
+use crate::identity::Identity;
 
+/// Handles to the node's actors. Cheap to clone, every connection and
+/// background task gets its own copy.
+#[derive(Clone)]
 pub struct Service {
-    pub peers: HashMap<SocketAddr, Tx>,
-    pub my_contact: Peer,
-    pub database: Db,
-    pub storage_dir: String,
-    pub counter: u8,
+    pub registry: Actor<Registry>,
+    pub database: Actor<Db>,
+    pub library: Actor<Library>,
+    pub transfers: Actor<Transfers>,
+    pub identity: Arc<Identity>,
+    pub db_path: String,
     pub port: u16,
+    pub friends_only: bool,
+    pub shutdown: Shutdown,
+    /// shared with the database, see `subscribe`
+    pub events: EventFeed,
+    counter: Arc<AtomicUsize>,
 }
 
 impl Service {
-    pub fn new(config: Config) -> Service {
-        // TODO: handle file errors
-        Service {
-            peers: HashMap::new(),
-            my_contact: Peer::get_self(),
-            database: Db::new_from_file(&config.config),
-            storage_dir: config.music,
-            counter: 0,
+    /// Loads the identity and spawns the actors, must be called from
+    /// within the runtime.
+    pub fn new(config: Config) -> Result<Service, Box<dyn std::error::Error>> {
+        let identity = Identity::load_or_create(&config.identity)?;
+        let mut db = Db::new_from_file(&config.config)?;
+        db.set_maintenance_intervals(&config.maintenance);
+        let events = db.events();
+        let database = Actor::spawn(db);
+        Ok(Service {
+            registry: Actor::spawn(Registry::new()),
+            transfers: Actor::spawn(Transfers::new(database.clone())),
+            database,
+            library: Actor::spawn_blocking(Library::new(&config.music)),
+            identity: Arc::new(identity),
+            db_path: config.config,
             port: config.port,
-        }
+            friends_only: config.friends_only,
+            shutdown: Shutdown::new(),
+            events,
+            counter: Arc::new(AtomicUsize::new(0)),
+        })
     }
 
-    pub fn get_collection(&self, track_data: bool, artist_filter: Option<&str>, album_filter: Option<&str>) -> Vec<ArtistData> {
-        get_collection(&self.storage_dir, track_data, artist_filter, album_filter)
+    /// persist the peer database, see `Db::save`
+    pub async fn save(&self) -> Result<(), Box<dyn std::error::Error>> {
+        let path = self.db_path.clone();
+        self.database.call(move |db| db.save(&path)).await??;
+        Ok(())
     }
 
-    pub fn get_peers(&self) -> Vec<Peer> {
-        self.database.all_peers()
+    /// Live `Event`s from the connections and the database.
+    pub fn subscribe(&self) -> broadcast::Receiver<Event> {
+        self.events.subscribe()
     }
 
-    pub fn incr(&mut self) {
-        self.counter += 1;
+    /// our contact card, signed with a fresh timestamp
+    pub fn my_contact(&self) -> Peer {
+        Peer::get_self(&self.identity, self.port)
     }
-}
 
-impl Service {
-    pub async fn broadcast(&mut self, message: &MessageEvent) {
-        for peer in self.peers.iter_mut() {
-            let _ = peer.1.send(message.clone());
-        }
+    pub fn incr(&self) -> usize {
+        self.counter.fetch_add(1, Ordering::SeqCst) + 1
+    }
+
+    pub fn counter(&self) -> usize {
+        self.counter.load(Ordering::SeqCst)
     }
 }
@@ -0,0 +1,118 @@
+use std::collections::BTreeMap;
+use std::io;
+use std::str;
+
+use super::PeerId;
+use crate::storage::atomic::{load_with_backup, write_atomic};
+
+/// A trusted contact, known locally by a petname of our choosing.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Contact {
+    pub petname: String,
+    pub public_key: String, // hex, as carried in `Peer`
+}
+
+/// Public keys allowed in friend-to-friend mode. Saved as one
+/// `<public key hex> <petname>` line per contact.
+#[derive(Clone, Debug, Default)]
+pub struct TrustStore {
+    contacts: BTreeMap<PeerId, Contact>,
+}
+
+impl TrustStore {
+    pub fn new() -> Self {
+        TrustStore::default()
+    }
+
+    /// the trust store lives next to the peer database
+    pub fn path_for(db_path: &str) -> String {
+        format!("{}.trust", db_path)
+    }
+
+    /// Trust `public_key`, replacing any petname it had. Returns its id,
+    /// or `None` if the key is not valid hex.
+    pub fn add(&mut self, public_key: &str, petname: &str) -> Option<PeerId> {
+        let id = PeerId::from_public_key_hex(public_key)?;
+        self.contacts.insert(id, Contact {
+            petname: petname.to_string(),
+            public_key: public_key.to_string(),
+        });
+        Some(id)
+    }
+
+    /// Remove a contact by petname, peer id or public key.
+    pub fn remove(&mut self, name: &str) -> Option<Contact> {
+        let id = self.contacts.iter()
+            .find(|(id, contact)| {
+                contact.petname == name || contact.public_key == name || id.to_string() == name
+            })
+            .map(|(id, _)| *id)?;
+        self.contacts.remove(&id)
+    }
+
+    pub fn is_trusted(&self, id: &PeerId) -> bool {
+        self.contacts.contains_key(id)
+    }
+
+    pub fn get(&self, id: &PeerId) -> Option<&Contact> {
+        self.contacts.get(id)
+    }
+
+    pub fn list(&self) -> Vec<(PeerId, Contact)> {
+        self.contacts.iter().map(|(id, contact)| (*id, contact.clone())).collect()
+    }
+
+    pub fn load(filename: &str) -> io::Result<Self> {
+        let store = load_with_backup(filename, |bytes| TrustStore::parse(filename, bytes))?;
+        Ok(store.unwrap_or_default())
+    }
+
+    fn parse(filename: &str, bytes: &[u8]) -> io::Result<Self> {
+        let mut store = TrustStore::new();
+        let text = str::from_utf8(bytes)
+            .map_err(|_| io::Error::new(io::ErrorKind::InvalidData, format!("{} is not text", filename)))?;
+        for line in text.lines().map(str::trim).filter(|l| !l.is_empty() && !l.starts_with('#')) {
+            let mut parts = line.splitn(2, ' ');
+            let public_key = parts.next().unwrap_or("");
+            let petname = parts.next().unwrap_or("").trim();
+            if store.add(public_key, petname).is_none() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("bad public key in {}: {}", filename, public_key),
+                ));
+            }
+        }
+        Ok(store)
+    }
+
+    pub fn save(&self, filename: &str) -> io::Result<()> {
+        let mut text = String::new();
+        for contact in self.contacts.values() {
+            text.push_str(&format!("{} {}\n", contact.public_key, contact.petname));
+        }
+        write_atomic(filename, text.as_bytes())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_trust_store() {
+        let mut store = TrustStore::new();
+        let alice = store.add("aa01", "alice").unwrap();
+        let bob = store.add("bb02", "bob smith").unwrap();
+        assert!(store.add("not hex", "eve").is_none());
+        assert!(store.is_trusted(&alice));
+
+        store.save("/tmp/test.trust").unwrap();
+        let mut store = TrustStore::load("/tmp/test.trust").unwrap();
+        assert_eq!(store.get(&bob).unwrap().petname, "bob smith");
+
+        assert_eq!(store.remove("alice").unwrap().public_key, "aa01");
+        assert!(!store.is_trusted(&alice));
+        assert!(store.remove(&bob.to_string()).is_some());
+        assert!(store.list().is_empty());
+    }
+}
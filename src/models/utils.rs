@@ -4,6 +4,7 @@ use serde::{Deserialize, Serialize};
 use std::net::{
     SocketAddr,
     IpAddr,
+    Ipv6Addr,
 };
 
 pub fn bytes_to_ip_addr(src: &mut BytesMut) -> SocketAddr {
@@ -12,7 +13,10 @@ pub fn bytes_to_ip_addr(src: &mut BytesMut) -> SocketAddr {
     for (x, y) in addr_slice.iter().zip(addr.iter_mut()) {
         *y = *x;
     }
-    let ip_addr: IpAddr = addr.into();
+    let ip_addr = match Ipv6Addr::from(addr) {
+        ip if ip.segments()[..6] == [0, 0, 0, 0, 0, 0xffff] => IpAddr::V4(ip.to_ipv4().unwrap()),
+        ip => IpAddr::V6(ip),
+    };
     let mut port_slice: &[u8] = &src.split_to(2)[..];
     let port = port_slice.read_u16::<BigEndian>().unwrap() as u16;
     SocketAddr::new(ip_addr, port)
@@ -29,6 +33,29 @@ pub fn get_nstring(src: &mut BytesMut, n: usize) -> Option<String> {
     Some(String::from_utf8_lossy(&target).trim_matches(char::from(0)).to_string())
 }
 
+/// `get_nstring` that checks the buffer holds `n` bytes
+pub(crate) fn take_nstring(src: &mut BytesMut, n: usize) -> Result<Option<String>, MessageCodecError> {
+    if src.len() < n {
+        return Err(MessageCodecError::DataLengthMismatch)
+    }
+    Ok(get_nstring(src, n))
+}
+
+pub(crate) fn take_u8(src: &mut BytesMut) -> Result<u8, MessageCodecError> {
+    if src.is_empty() {
+        return Err(MessageCodecError::SerializationError)
+    }
+    Ok(src.split_to(1)[0])
+}
+
+/// `bytes_to_ip_addr` that checks the buffer holds an address
+pub(crate) fn take_socket_addr(src: &mut BytesMut) -> Result<SocketAddr, MessageCodecError> {
+    if src.len() < 18 {
+        return Err(MessageCodecError::DataLengthMismatch)
+    }
+    Ok(bytes_to_ip_addr(src))
+}
+
 pub fn take_u64(src: &mut BytesMut) -> Result<u64, MessageCodecError> {
     if src.len() < 8 {
         return Err(MessageCodecError::SerializationError)
@@ -46,7 +73,6 @@ pub(crate) fn take_u32(src: &mut BytesMut) -> Result<u32, MessageCodecError> {
     Ok(buf.read_u32::<BigEndian>().unwrap())
 }
 
-#[allow(dead_code)]
 pub(crate) fn take_u16(src: &mut BytesMut) -> Result<u16, MessageCodecError> {
     if src.len() < 2 {
         return Err(MessageCodecError::SerializationError)
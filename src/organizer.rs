@@ -1,8 +1,15 @@
-use std::fs::metadata;
+//! Scans a music folder into artists and albums. Layouts vary, so the
+//! folder is walked to any depth and tracks are grouped by their ID3
+//! artist and album. Untagged tracks fall back to the folders they sit
+//! in: the nearest names the album and the one above it the artist.
+
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
 
 use crate::formats::mp3::{
     get_mp3_data,
-    // MusicFileData,
+    MusicFileData,
 };
 
 use crate::models::{
@@ -11,72 +18,138 @@ use crate::models::{
     TrackData,
 };
 
+pub const UNKNOWN_ARTIST: &str = "Unknown Artist";
+pub const UNKNOWN_ALBUM: &str = "Unknown Album";
 
+/// Every artist under `dir_name` with its albums, in the order first met
+/// walking the folder by name. Unreadable files and folders are skipped.
 pub fn get_collection(dir_name: &str, track_data: bool, maybe_artist_filter: Option<&str>, maybe_album_filter: Option<&str>) -> Vec<ArtistData> {
-    let mut artist_vec: Vec<ArtistData> = Vec::with_capacity(2048);
-    let artists = std::fs::read_dir(dir_name).unwrap();
-
-    for artist in artists {
-        let entry = artist.unwrap();
-        let path = entry.path();
-        let artist_name = path.file_stem().unwrap().to_str().unwrap();
-        if let Some(artist_filter) = maybe_artist_filter {
-            if artist_name != artist_filter {
-                continue
-            }
+    let root = Path::new(dir_name);
+    let mut files = vec![];
+    let mut visited = HashSet::new();
+    find_tracks(root, &mut visited, &mut files);
+
+    // artist -> albums -> (title, track count, tracks)
+    let mut artist_vec: Vec<(String, Vec<(String, u8, Vec<TrackData>)>)> = Vec::with_capacity(2048);
+    let mut artist_index: HashMap<String, usize> = HashMap::new();
+    let mut album_index: HashMap<(String, String), usize> = HashMap::new();
+    for path in files {
+        let mp3_data = match get_mp3_data(&path) {
+            Ok(data) => data,
+            _ => continue,
+        };
+        let (artist_name, album_name) = names(root, &path, &mp3_data);
+        if maybe_artist_filter.map_or(false, |filter| filter != artist_name) {
+            continue
         }
-        if metadata(path.clone()).unwrap().is_dir() {
-            let mut album_vec: Vec<AlbumData> = vec!();
-            let albums = std::fs::read_dir(path.clone()).unwrap();
-            for album in albums {
-                let entry = album.unwrap();
-                let path = entry.path();
-                if metadata(path.clone()).unwrap().is_dir() {
-                    let album_name = path.file_stem().unwrap().to_str().unwrap();
-                    if let Some(album_filter) = maybe_album_filter {
-                        if album_name != album_filter {
-                            continue
-                        }
-                    }
-
-                    let mut track_vec: Vec<TrackData> = vec![];
-
-                    let tracks = std::fs::read_dir(path.clone()).unwrap();
-                    let mut track_count: u8 = 0;
-                    for track in tracks {
-                        let entry = track.unwrap();
-                        let path = entry.path();
-                        let mp3_data = match get_mp3_data(&path) {
-                            Ok(data) => data,
-                            _ => continue,
-                        };
-                        if track_data {
-                            track_vec.push(TrackData::new(
-                                mp3_data.title,
-                                mp3_data.bitrate,
-                                0,
-                            ));
-                            track_count += 1;
-                        }
-                    }
-                    let tracks = if track_data {
-                        Some(track_vec)
-                    } else {
-                        None
-                    };
-                    album_vec.push(AlbumData::new(
-                        None,
-                        album_name.to_string(),
-                        track_count,
-                        tracks,
-                    ));
-                }
-            }
-            let artist = ArtistData::new(artist_name.into(), Some(album_vec));
-            artist_vec.push(artist);
+        if maybe_album_filter.map_or(false, |filter| filter != album_name) {
+            continue
+        }
+
+        let artist = *artist_index.entry(artist_name.clone()).or_insert_with(|| {
+            artist_vec.push((artist_name.clone(), vec![]));
+            artist_vec.len() - 1
+        });
+        let album_vec = &mut artist_vec[artist].1;
+        let album = *album_index.entry((artist_name, album_name.clone())).or_insert_with(|| {
+            album_vec.push((album_name, 0, vec![]));
+            album_vec.len() - 1
+        });
+        let (_, track_count, track_vec) = &mut album_vec[album];
+        *track_count = track_count.saturating_add(1);
+        if track_data {
+            let title = match tag(&mp3_data.title) {
+                Some(title) => title.to_string(),
+                None => file_name(&path),
+            };
+            track_vec.push(TrackData::new(title, mp3_data.bitrate, 0));
+        }
+    }
+
+    artist_vec.into_iter().map(|(artist_name, albums)| {
+        let album_vec = albums.into_iter().map(|(album_name, track_count, track_vec)| {
+            let tracks = if track_data {
+                Some(track_vec)
+            } else {
+                None
+            };
+            AlbumData::new(None, album_name, track_count, tracks)
+        }).collect();
+        ArtistData::new(artist_name, Some(album_vec))
+    }).collect()
+}
+
+/// Collect the .mp3 files below `dir` sorted by path. Hidden entries are
+/// skipped and each folder is read once, so symlink loops end.
+fn find_tracks(dir: &Path, visited: &mut HashSet<PathBuf>, files: &mut Vec<PathBuf>) {
+    let real = match dir.canonicalize() {
+        Ok(real) => real,
+        Err(_) => return,
+    };
+    if !visited.insert(real) {
+        return
+    }
+    let entries = match fs::read_dir(dir) {
+        Ok(entries) => entries,
+        Err(e) => {
+            println!("can't read {}: {}", dir.display(), e);
+            return
+        },
+    };
+    let mut paths: Vec<PathBuf> = entries.filter_map(|entry| entry.ok())
+        .map(|entry| entry.path())
+        .filter(|path| !file_name(path).starts_with('.'))
+        .collect();
+    paths.sort();
+    for path in paths {
+        if path.is_dir() {
+            find_tracks(&path, visited, files);
+        } else if is_mp3(&path) {
+            files.push(path);
         }
     }
-    artist_vec
+}
+
+fn is_mp3(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .map_or(false, |extension| extension.eq_ignore_ascii_case("mp3"))
+}
+
+/// the tagged artist and album, else ones taken from the folders between
+/// `root` and the track
+fn names(root: &Path, path: &Path, data: &MusicFileData) -> (String, String) {
+    let folders: Vec<String> = path.parent()
+        .and_then(|parent| parent.strip_prefix(root).ok())
+        .map_or(vec![], |parent| parent.iter().map(|name| name.to_string_lossy().into_owned()).collect());
+    let mut folders = folders.iter().rev().map(|name| name.as_str()).peekable();
+    let (artist, album) = match (tag(&data.artist), tag(&data.album)) {
+        (Some(artist), Some(album)) => (Some(artist), Some(album)),
+        // the artist's folder holds the album's, or the tracks directly
+        (None, Some(album)) => {
+            if folders.peek().map_or(false, |folder| folder.trim().eq_ignore_ascii_case(album)) {
+                folders.next();
+            }
+            (folders.next(), Some(album))
+        },
+        (artist, None) => {
+            let album = folders.next();
+            (artist.or_else(|| folders.next()), album)
+        },
+    };
+    (
+        artist.unwrap_or(UNKNOWN_ARTIST).to_string(),
+        album.unwrap_or(UNKNOWN_ALBUM).to_string(),
+    )
+}
+
+/// a tag field, None when blank
+fn tag(value: &str) -> Option<&str> {
+    Some(value.trim()).filter(|value| !value.is_empty())
+}
+
+fn file_name(path: &Path) -> String {
+    path.file_stem().map_or(String::new(), |stem| stem.to_string_lossy().into_owned())
 }
 
 
@@ -112,4 +185,67 @@ mod tests {
         get_collection(&format!("{}/{}", home_dir, "Documents/music"), false, None, None);
     }
 
+    #[test]
+    fn test_messy_folder() {
+        const MP3_FILE: &str = "./static/01 - mirror.mp3";
+        let tagged = fs::read(MP3_FILE).unwrap();
+        // without the ID3v2 tag in front, sized in 7 bit bytes, and the
+        // ID3v1 tag at the end
+        let v2_size = tagged[6..10].iter().fold(0, |size, byte| size << 7 | *byte as usize) + 10;
+        let untagged = &tagged[v2_size..tagged.len() - 128];
+        // an ID3v1 tag naming only the album
+        let mut album_only = untagged.to_vec();
+        let mut v1 = vec![0u8; 128];
+        v1[..3].copy_from_slice(b"TAG");
+        v1[63..75].copy_from_slice(b"Frigid Stars");
+        v1[93..97].copy_from_slice(b"1990");
+        album_only.extend_from_slice(&v1);
+
+        let root = Path::new("/tmp/test_messy_folder");
+        let _ = fs::remove_dir_all(root);
+        let files: [(&str, &[u8]); 8] = [
+            // tags win over folder names, however deep
+            ("a/b/c/d/mirror.mp3", &tagged),
+            ("loose.mp3", &tagged),
+            ("Slint/Spiderland/Breadcrumb Trail.MP3", untagged),
+            ("Slint/Spiderland/Nosferatu Man.mp3", untagged),
+            ("Slint/Tweez/Ron.mp3", untagged),
+            ("flat.mp3", untagged),
+            ("Slint/Spiderland/.hidden.mp3", untagged),
+            ("Codeine/Frigid Stars/D.mp3", &album_only),
+        ];
+        for (name, bytes) in files.iter() {
+            let path = root.join(name);
+            fs::create_dir_all(path.parent().unwrap()).unwrap();
+            fs::write(path, bytes).unwrap();
+        }
+        fs::write(root.join("Slint/Spiderland/cover.jpg"), b"not music").unwrap();
+
+        let artists = get_collection(root.to_str().unwrap(), true, None, None);
+        let summary: Vec<(String, Vec<(String, Vec<String>)>)> = artists.iter().map(|artist| {
+            let albums = artist.albums.as_ref().unwrap().iter().map(|album| {
+                let titles = album.tracks.as_ref().unwrap().iter().map(|t| t.title.clone()).collect();
+                (album.album_title.clone(), titles)
+            }).collect();
+            (artist.artist.clone(), albums)
+        }).collect();
+        let s = |v: &str| v.to_string();
+        assert_eq!(summary, vec![
+            // only the album is tagged, the artist is the folder above it
+            (s("Codeine"), vec![(s("Frigid Stars"), vec![s("D")])]),
+            (s("Slint"), vec![
+                (s("Spiderland"), vec![s("Breadcrumb Trail"), s("Nosferatu Man")]),
+                (s("Tweez"), vec![s("Ron")]),
+            ]),
+            (s("Moss Icon"), vec![(s("Lyburnum"), vec![s("Mirror"), s("Mirror")])]),
+            (s(UNKNOWN_ARTIST), vec![(s(UNKNOWN_ALBUM), vec![s("flat")])]),
+        ]);
+        assert_eq!(artists[2].albums.as_ref().unwrap()[0].track_count(), 2);
+
+        let filtered = get_collection(root.to_str().unwrap(), false, Some("Slint"), Some("Tweez"));
+        assert_eq!(filtered, vec![ArtistData::new(s("Slint"), Some(vec![
+            AlbumData::new(None, s("Tweez"), 1, None),
+        ]))]);
+        fs::remove_dir_all(root).unwrap();
+    }
 }
@@ -0,0 +1,78 @@
+use std::sync::Arc;
+
+use tokio::sync::watch;
+
+/// Cloneable shutdown flag shared by the accept loop, the scheduler,
+/// every connection and the TUI.
+#[derive(Clone)]
+pub struct Shutdown {
+    tx: Arc<watch::Sender<bool>>,
+    rx: watch::Receiver<bool>,
+}
+
+impl Shutdown {
+    pub fn new() -> Self {
+        let (tx, rx) = watch::channel(false);
+        Shutdown {
+            tx: Arc::new(tx),
+            rx,
+        }
+    }
+
+    pub fn trigger(&self) {
+        let _ = self.tx.broadcast(true);
+    }
+
+    pub fn is_triggered(&self) -> bool {
+        *self.rx.borrow()
+    }
+
+    /// resolves once `trigger` has been called
+    pub async fn wait(&self) {
+        let mut rx = self.rx.clone();
+        while let Some(triggered) = rx.recv().await {
+            if triggered {
+                return;
+            }
+        }
+    }
+}
+
+/// resolves on SIGINT or SIGTERM
+#[cfg(unix)]
+pub async fn wait_for_signal() {
+    use futures::future;
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let ctrl_c = Box::pin(tokio::signal::ctrl_c());
+    match signal(SignalKind::terminate()) {
+        Ok(mut term) => {
+            let term = Box::pin(async move { term.recv().await; });
+            let _ = future::select(ctrl_c, term).await;
+        },
+        Err(_) => {
+            let _ = ctrl_c.await;
+        },
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn wait_for_signal() {
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_trigger() {
+        let shutdown = Shutdown::new();
+        let waiter = shutdown.clone();
+        assert!(!waiter.is_triggered());
+        let handle = tokio::spawn(async move { waiter.wait().await; });
+        shutdown.trigger();
+        handle.await.unwrap();
+        assert!(shutdown.is_triggered());
+    }
+}
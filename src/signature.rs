@@ -13,7 +13,7 @@ pub enum MyError {
     BadSignature,
 }
 
-fn generate_bytes() -> Vec<u8> {
+pub(crate) fn generate_bytes() -> Vec<u8> {
     let rng = SystemRandom::new();
     Ed25519KeyPair::generate_pkcs8(&rng).unwrap().as_ref().to_vec()
 }
@@ -31,8 +31,13 @@ pub fn sign(key_pair: &Ed25519KeyPair, message: &[u8]) -> Vec<u8> {
 }
 
 pub fn verify(key_pair: &Ed25519KeyPair, msg: &[u8], sig: &[u8]) -> Result<(), MyError> {
-    let peer_public_key = UnparsedPublicKey::new(&signature::ED25519, key_pair.public_key().as_ref());
-    peer_public_key.verify(msg, sig.as_ref())
+    verify_with_public_key(key_pair.public_key().as_ref(), msg, sig)
+}
+
+/// verify a signature made by someone else's key
+pub fn verify_with_public_key(public_key: &[u8], msg: &[u8], sig: &[u8]) -> Result<(), MyError> {
+    let peer_public_key = UnparsedPublicKey::new(&signature::ED25519, public_key);
+    peer_public_key.verify(msg, sig)
         .map_err(|_| MyError::BadSignature)
 }
 
@@ -60,4 +65,14 @@ mod tests {
             Err(MyError::BadSignature) => assert!(true),
         };
     }
+
+    #[test]
+    fn test_verify_public_key() {
+        let msg = "test12";
+        let key = new_key();
+        let other = new_key();
+        let signature = sign(&key, msg.as_bytes());
+        assert!(verify_with_public_key(key.public_key().as_ref(), msg.as_bytes(), &signature).is_ok());
+        assert!(verify_with_public_key(other.public_key().as_ref(), msg.as_bytes(), &signature).is_err());
+    }
 }
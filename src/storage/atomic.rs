@@ -0,0 +1,170 @@
+//! Crash-safe file replacement. A new version is written to a temp file,
+//! synced and renamed over the old one, which is kept as `<path>.bak`.
+//! Loaders go through `load_with_backup` to fall back to it.
+
+use std::fmt;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+/// where the previous version of `path` is kept
+pub fn backup_path(path: &str) -> String {
+    format!("{}.bak", path)
+}
+
+/// Replace `path` with `bytes`, keeping the old file as its backup.
+pub fn write_atomic(path: &str, bytes: &[u8]) -> io::Result<()> {
+    replace(path, bytes, None)
+}
+
+/// `write_atomic` for files only the owner may read, like private keys.
+pub fn write_private(path: &str, bytes: &[u8]) -> io::Result<()> {
+    replace(path, bytes, Some(0o600))
+}
+
+fn replace(path: &str, bytes: &[u8], mode: Option<u32>) -> io::Result<()> {
+    let tmp = write_tmp(path, bytes, mode)?;
+    match fs::rename(path, backup_path(path)) {
+        Ok(()) => {},
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+        Err(e) => return Err(e),
+    }
+    // a crash here leaves only the backup, which loading falls back to
+    fs::rename(&tmp, path)?;
+    sync_dir(path)
+}
+
+fn write_tmp(path: &str, bytes: &[u8], mode: Option<u32>) -> io::Result<String> {
+    let tmp = format!("{}.tmp", path);
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::OpenOptionsExt;
+        if let Some(mode) = mode {
+            options.mode(mode);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = mode;
+    let mut file = options.open(&tmp)?;
+    file.write_all(bytes)?;
+    file.sync_all()?;
+    Ok(tmp)
+}
+
+/// make the renames in the directory holding `path` durable
+fn sync_dir(path: &str) -> io::Result<()> {
+    #[cfg(unix)]
+    {
+        let dir = Path::new(path).parent()
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .unwrap_or_else(|| Path::new("."));
+        File::open(dir)?.sync_all()?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+fn read(path: &str) -> io::Result<Option<Vec<u8>>> {
+    let mut bytes = Vec::new();
+    match File::open(path) {
+        Ok(mut file) => file.read_to_end(&mut bytes)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    Ok(Some(bytes))
+}
+
+/// Read `path` with `parse`, `None` if there is nothing saved yet. When
+/// the file is missing or `parse` rejects it the backup is tried, and if
+/// that loads it is restored in place. A rejected file is kept as
+/// `<path>.corrupt`.
+pub fn load_with_backup<T, E, F>(path: &str, parse: F) -> Result<Option<T>, E>
+where
+    F: Fn(&[u8]) -> Result<T, E>,
+    E: From<io::Error> + fmt::Display,
+{
+    let error = match read(path)? {
+        Some(bytes) => match parse(&bytes) {
+            Ok(value) => return Ok(Some(value)),
+            Err(e) => Some(e),
+        },
+        None => None,
+    };
+    let backup = backup_path(path);
+    let bytes = match read(&backup)? {
+        Some(bytes) => bytes,
+        None => return match error {
+            Some(e) => Err(e),
+            None => Ok(None),
+        },
+    };
+    let value = match parse(&bytes) {
+        Ok(value) => value,
+        // the file we were asked for is the more useful complaint
+        Err(e) => return Err(error.unwrap_or(e)),
+    };
+    match &error {
+        Some(e) => {
+            println!("{} is unreadable ({}), restoring {}", path, e, backup);
+            fs::rename(path, format!("{}.corrupt", path))?;
+        },
+        None => println!("{} is missing, restoring {}", path, backup),
+    }
+    let tmp = write_tmp(path, &bytes, None)?;
+    fs::rename(&tmp, path)?;
+    sync_dir(path)?;
+    Ok(Some(value))
+}
+
+/// Remove `path` and its backup, to start over from nothing.
+pub fn remove(path: &str) -> io::Result<()> {
+    for path in &[path.to_string(), backup_path(path)] {
+        match fs::remove_file(path) {
+            Ok(()) => {},
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => {},
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(bytes: &[u8]) -> io::Result<String> {
+        match bytes.first() {
+            Some(b'v') => Ok(String::from_utf8_lossy(bytes).to_string()),
+            _ => Err(io::Error::new(io::ErrorKind::InvalidData, "bad version")),
+        }
+    }
+
+    #[test]
+    fn test_backup_fallback() {
+        let path = "/tmp/test_atomic.bin";
+        remove(path).unwrap();
+        assert_eq!(load_with_backup(path, parse).unwrap(), None);
+
+        write_atomic(path, b"v1").unwrap();
+        write_atomic(path, b"v2").unwrap();
+        assert_eq!(fs::read(backup_path(path)).unwrap(), b"v1");
+        assert_eq!(load_with_backup(path, parse).unwrap(), Some("v2".to_string()));
+
+        // torn write of the main file
+        fs::write(path, b"").unwrap();
+        assert_eq!(load_with_backup(path, parse).unwrap(), Some("v1".to_string()));
+        assert_eq!(fs::read(path).unwrap(), b"v1");
+        assert_eq!(fs::read(format!("{}.corrupt", path)).unwrap(), b"");
+
+        // crash between the two renames
+        fs::remove_file(path).unwrap();
+        assert_eq!(load_with_backup(path, parse).unwrap(), Some("v1".to_string()));
+
+        fs::write(backup_path(path), b"").unwrap();
+        fs::write(path, b"").unwrap();
+        assert!(load_with_backup(path, parse).is_err());
+    }
+}
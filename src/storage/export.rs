@@ -0,0 +1,222 @@
+//! Collections as JSON or CSV files, for spreadsheets and scripts. JSON
+//! keeps everything, CSV has a row per track, album or bare artist and
+//! reads back empty lists as absent ones.
+
+use std::fs;
+use std::io;
+use std::mem;
+use std::path::Path;
+use std::str::FromStr;
+
+use super::DbError;
+use crate::models::{AlbumData, ArtistData, Catalogue, Collection, TrackData};
+
+const CSV_HEADER: [&str; 6] = ["artist", "album", "track_count", "track", "bitrate", "length"];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Format {
+    Json,
+    Csv,
+}
+
+impl Format {
+    /// picked by the file extension
+    pub fn of(path: &str) -> Option<Format> {
+        match Path::new(path).extension()?.to_str()?.to_lowercase().as_str() {
+            "json" => Some(Format::Json),
+            "csv" => Some(Format::Csv),
+            _ => None,
+        }
+    }
+}
+
+fn format_of(path: &str) -> Result<Format, DbError> {
+    Format::of(path).ok_or_else(|| {
+        io::Error::new(io::ErrorKind::InvalidInput, format!("{} is neither .json nor .csv", path)).into()
+    })
+}
+
+/// Write `collection` to `path` in the format its extension names.
+pub fn export(path: &str, collection: &Collection) -> Result<(), DbError> {
+    let text = match format_of(path)? {
+        Format::Json => to_json(collection),
+        Format::Csv => to_csv(collection),
+    };
+    Ok(fs::write(path, text)?)
+}
+
+/// Read a collection written by `export`, or by hand in the same layout.
+pub fn import(path: &str) -> Result<Collection, DbError> {
+    let format = format_of(path)?;
+    let text = fs::read_to_string(path)?;
+    let parsed = match format {
+        Format::Json => from_json(&text),
+        Format::Csv => from_csv(&text),
+    };
+    parsed.map_err(|e| DbError::Corrupt(format!("{}: {}", path, e)))
+}
+
+pub fn to_json(collection: &Collection) -> String {
+    serde_json::to_string_pretty(collection).expect("collections always serialize")
+}
+
+pub fn from_json(text: &str) -> Result<Collection, String> {
+    serde_json::from_str(text).map_err(|e| e.to_string())
+}
+
+pub fn to_csv(collection: &Collection) -> String {
+    let mut out = String::new();
+    write_row(&mut out, &CSV_HEADER.iter().map(|s| s.to_string()).collect::<Vec<_>>());
+    for artist in &collection.artists {
+        let albums = match &artist.albums {
+            Some(albums) if !albums.is_empty() => albums,
+            _ => {
+                write_row(&mut out, &[artist.artist.clone(), String::new(), String::new(), String::new(), String::new(), String::new()]);
+                continue;
+            },
+        };
+        for album in albums {
+            let prefix = [artist.artist.clone(), album.album_title.clone(), album.track_count().to_string()];
+            let tracks = match &album.tracks {
+                Some(tracks) if !tracks.is_empty() => tracks,
+                _ => {
+                    write_row(&mut out, &[&prefix[..], &[String::new(), String::new(), String::new()]].concat());
+                    continue;
+                },
+            };
+            for track in tracks {
+                let fields = [track.title.clone(), track.bitrate().to_string(), track.length().to_string()];
+                write_row(&mut out, &[&prefix[..], &fields[..]].concat());
+            }
+        }
+    }
+    out
+}
+
+/// Rows are grouped back by artist and album in the order first seen.
+pub fn from_csv(text: &str) -> Result<Collection, String> {
+    let rows = parse_csv(text.trim_start_matches('\u{feff}'))?;
+    let mut rows = rows.into_iter().enumerate();
+    match rows.next() {
+        Some((_, header)) if header == CSV_HEADER => {},
+        _ => return Err(format!("the header row must be {}", CSV_HEADER.join(","))),
+    }
+    let mut catalogue = Catalogue::new();
+    for (i, row) in rows {
+        let row_number = i + 1;
+        if row.len() != CSV_HEADER.len() {
+            return Err(format!("row {} has {} fields, expected {}", row_number, row.len(), CSV_HEADER.len()));
+        }
+        let albums = match row[1].as_str() {
+            "" => None,
+            title => {
+                let tracks = match row[3].as_str() {
+                    "" => None,
+                    track => Some(vec![TrackData::new(track.to_string(), number(&row, 4, row_number)?, number(&row, 5, row_number)?)]),
+                };
+                Some(vec![AlbumData::new(None, title.to_string(), number(&row, 2, row_number)?, tracks)])
+            },
+        };
+        catalogue.merge(&ArtistData::new(row[0].clone(), albums));
+    }
+    Ok(catalogue.to_collection())
+}
+
+/// the number in column `index`, 0 when left empty
+fn number<T: FromStr + Default>(row: &[String], index: usize, row_number: usize) -> Result<T, String> {
+    match row[index].as_str() {
+        "" => Ok(T::default()),
+        field => field.parse().map_err(|_| format!("row {}: {} is not a valid {}", row_number, field, CSV_HEADER[index])),
+    }
+}
+
+fn write_row(out: &mut String, fields: &[String]) {
+    let fields: Vec<String> = fields.iter().map(|field| {
+        if field.contains(|c| c == ',' || c == '"' || c == '\n' || c == '\r') {
+            format!("\"{}\"", field.replace('"', "\"\""))
+        } else {
+            field.clone()
+        }
+    }).collect();
+    out.push_str(&fields.join(","));
+    out.push('\n');
+}
+
+/// RFC 4180 fields, quoted ones may hold commas, quotes and line breaks
+fn parse_csv(text: &str) -> Result<Vec<Vec<String>>, String> {
+    let mut rows = vec![];
+    let mut row = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if quoted {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                },
+                '"' => quoted = false,
+                c => field.push(c),
+            }
+            continue;
+        }
+        match c {
+            '"' => quoted = true,
+            ',' => row.push(mem::take(&mut field)),
+            '\r' => {},
+            '\n' => {
+                row.push(mem::take(&mut field));
+                rows.push(mem::take(&mut row));
+            },
+            c => field.push(c),
+        }
+    }
+    if quoted {
+        return Err("a quoted field is not closed".to_string());
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn collection() -> Collection {
+        Collection::new(vec![
+            ArtistData::new("Tom, \"the\" Band".to_string(), Some(vec![
+                AlbumData::new(None, "First\nLine".to_string(), 2, Some(vec![
+                    TrackData::new("one".to_string(), 320, 200),
+                    TrackData::new("two".to_string(), 128, 10),
+                ])),
+                AlbumData::new(None, "Second".to_string(), 0, None),
+            ])),
+            ArtistData::new("Solo".to_string(), None),
+        ])
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let collection = collection();
+        assert_eq!(from_json(&to_json(&collection)).unwrap(), collection);
+        assert_eq!(from_csv(&to_csv(&collection)).unwrap(), collection);
+
+        let path = "/tmp/test_export.csv";
+        export(path, &collection).unwrap();
+        assert_eq!(import(path).unwrap(), collection);
+        assert!(export("/tmp/test_export.txt", &collection).is_err());
+    }
+
+    #[test]
+    fn test_bad_csv() {
+        assert!(from_csv("artist,album\n").is_err());
+        assert!(from_csv("artist,album,track_count,track,bitrate,length\na,b,lots,,,\n").is_err());
+        assert!(from_csv("artist,album,track_count,track,bitrate,length\n\"a,b,,,,\n").is_err());
+        let collection = from_csv("artist,album,track_count,track,bitrate,length\r\na,b,1,c,,\r\n").unwrap();
+        assert_eq!(collection.artists[0].albums.as_ref().unwrap()[0].tracks.as_ref().unwrap()[0].title, "c");
+    }
+}
@@ -0,0 +1,465 @@
+//! Encodings of saved records, and the single file layout the database
+//! used before the key-value store in `kv`. Files in that layout are
+//! migrated on load.
+//!
+//! ```text
+//! magic "SNOB" | version u16 | record count u64 | records...
+//! record: length u32 | payload | crc32 u32 of the payload
+//! payload: peer u32+bytes | reputation u32+bytes | updated u64 | collection u32+bytes
+//! ```
+//!
+//! Before version 3 the peer's name, key and signature had u8 lengths, see
+//! `Peer::from_narrow_bytes`. Files without the magic are the original layout.
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use bytes::{Buf, BufMut, BytesMut};
+use chrono::{DateTime, TimeZone, Utc};
+
+use crate::models::{
+    take_nstring, take_u16, take_u32, take_u64, take_u8, Ban, Collection, MessageCodecError, Peer, Reputation,
+};
+
+pub const MAGIC: &[u8; 4] = b"SNOB";
+pub const VERSION: u16 = 3;
+
+/// everything saved about one peer
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    pub peer: Peer,
+    pub reputation: Reputation,
+    pub collection: Collection,
+    pub updated: u64,
+}
+
+#[derive(Debug)]
+pub enum DbError {
+    Io(io::Error),
+    UnsupportedVersion(u16),
+    Corrupt(String),
+    /// the store and the pid holding it, if known
+    Locked(String, Option<u32>),
+}
+
+impl fmt::Display for DbError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DbError::Io(e) => write!(f, "{}", e),
+            DbError::UnsupportedVersion(v) => write!(f, "database version {} is newer than this build", v),
+            DbError::Corrupt(what) => write!(f, "corrupt database: {}", what),
+            DbError::Locked(path, Some(pid)) => write!(f, "{} is in use by process {}, stop it first", path, pid),
+            DbError::Locked(path, None) => write!(f, "{} is in use by another process, stop it first or remove {}.lock", path, path),
+        }
+    }
+}
+
+impl Error for DbError {}
+
+impl From<io::Error> for DbError {
+    fn from(e: io::Error) -> Self {
+        DbError::Io(e)
+    }
+}
+
+fn corrupt<E: fmt::Debug>(what: &str) -> impl Fn(E) -> DbError + '_ {
+    move |e| DbError::Corrupt(format!("{} ({:?})", what, e))
+}
+
+/// CRC-32 (IEEE), as used by zip and png
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (!(crc & 1)).wrapping_add(1));
+        }
+    }
+    !crc
+}
+
+pub fn put_section(buf: &mut BytesMut, bytes: &[u8]) {
+    buf.put_u32(bytes.len() as u32);
+    buf.put(bytes);
+}
+
+pub fn take_section(buf: &mut BytesMut) -> Result<BytesMut, MessageCodecError> {
+    let len = take_u32(buf)? as usize;
+    if buf.len() < len {
+        return Err(MessageCodecError::DataLengthMismatch);
+    }
+    Ok(buf.split_to(len))
+}
+
+pub fn encode(records: &[Record]) -> BytesMut {
+    let mut buf = BytesMut::new();
+    buf.put(&MAGIC[..]);
+    buf.put_u16(VERSION);
+    buf.put_u64(records.len() as u64);
+    for record in records {
+        let mut payload = encode_peer_entry(record);
+        put_section(&mut payload, &record.collection.to_bytes());
+
+        buf.put_u32(payload.len() as u32);
+        buf.put(&payload[..]);
+        buf.put_u32(crc32(&payload));
+    }
+    buf
+}
+
+pub fn decode(bytes: &[u8]) -> Result<Vec<Record>, DbError> {
+    if bytes.is_empty() {
+        return Ok(vec![]);
+    }
+    if !bytes.starts_with(MAGIC) {
+        return decode_legacy(bytes);
+    }
+    let mut buf = BytesMut::from(&bytes[MAGIC.len()..]);
+    if buf.len() < 10 {
+        return Err(DbError::Corrupt("truncated header".to_string()));
+    }
+    let version = buf.get_u16();
+    if version > VERSION {
+        return Err(DbError::UnsupportedVersion(version));
+    }
+    let count = buf.get_u64();
+    let mut records = vec![];
+    for i in 0..count {
+        let what = format!("record {}", i);
+        let mut payload = take_section(&mut buf).map_err(corrupt(&what))?;
+        let crc = take_u32(&mut buf).map_err(corrupt(&what))?;
+        if crc != crc32(&payload) {
+            return Err(DbError::Corrupt(format!("{} fails its checksum", what)));
+        }
+        records.push(decode_record(&mut payload, version).map_err(corrupt(&what))?);
+    }
+    if !buf.is_empty() {
+        return Err(DbError::Corrupt("trailing bytes after the last record".to_string()));
+    }
+    Ok(records)
+}
+
+fn decode_record(payload: &mut BytesMut, version: u16) -> Result<Record, MessageCodecError> {
+    let mut record = match version {
+        0..=2 => decode_entry_with(payload, Peer::from_narrow_bytes)?,
+        _ => decode_entry_with(payload, Peer::from_bytes)?,
+    };
+    record.collection = Collection::from_bytes(&mut take_section(payload)?)?;
+    Ok(record)
+}
+
+/// A record without its collection, as kept in the store's `peers` tree.
+pub fn encode_peer_entry(record: &Record) -> BytesMut {
+    let mut buf = BytesMut::new();
+    put_section(&mut buf, &record.peer.to_bytes());
+    put_section(&mut buf, &record.reputation.to_bytes());
+    buf.put_u64(record.updated);
+    buf
+}
+
+/// See `encode_peer_entry`, the collection is left empty. Entries saved
+/// before the peer's lengths were widened are narrow, try both.
+pub fn decode_peer_entry(buf: &mut BytesMut) -> Result<Record, MessageCodecError> {
+    let mut wide = buf.clone();
+    match decode_entry_with(&mut wide, Peer::from_bytes) {
+        Ok(record) => {
+            *buf = wide;
+            Ok(record)
+        },
+        Err(_) => decode_entry_with(buf, Peer::from_narrow_bytes),
+    }
+}
+
+fn decode_entry_with(
+    buf: &mut BytesMut,
+    decode_peer: fn(&mut BytesMut) -> Result<Peer, MessageCodecError>,
+) -> Result<Record, MessageCodecError> {
+    let mut section = take_section(buf)?;
+    let peer = decode_peer(&mut section)?;
+    if !section.is_empty() {
+        return Err(MessageCodecError::DataLengthMismatch);
+    }
+    let reputation = Reputation::from_bytes(&mut take_section(buf)?);
+    let updated = take_u64(buf)?;
+    Ok(Record { peer, reputation, collection: Collection::new(vec![]), updated })
+}
+
+/// The original layout: a u8 count, then per peer a length and the peer
+/// bytes, optionally followed by its reputation and collection. Lengths
+/// were a u8 at first, with peers in `decode_original_peer`'s layout, and
+/// a u64 once records were signed, with narrow peers, try both.
+fn decode_legacy(bytes: &[u8]) -> Result<Vec<Record>, DbError> {
+    decode_legacy_with(bytes, true)
+        .or_else(|_| decode_legacy_with(bytes, false))
+        .map_err(corrupt("unrecognised legacy database"))
+}
+
+fn decode_legacy_with(bytes: &[u8], wide_lengths: bool) -> Result<Vec<Record>, MessageCodecError> {
+    let mut buf = BytesMut::from(bytes);
+    let count = buf.get_u8();
+    let mut records = vec![];
+    for _ in 0..count {
+        let len = if wide_lengths {
+            take_u64(&mut buf)? as usize
+        } else if buf.is_empty() {
+            return Err(MessageCodecError::DataLengthMismatch);
+        } else {
+            buf.get_u8() as usize
+        };
+        if buf.len() < len {
+            return Err(MessageCodecError::DataLengthMismatch);
+        }
+        let mut record = buf.split_to(len);
+        let peer = if wide_lengths {
+            Peer::from_narrow_bytes(&mut record)?
+        } else {
+            decode_original_peer(&mut record)?
+        };
+        let reputation = if record.len() > 0 {
+            Reputation::from_bytes(&mut record)
+        } else {
+            Reputation::new()
+        };
+        let (collection, updated) = if record.len() > 0 {
+            let updated = take_u64(&mut record)?;
+            (Collection::from_bytes(&mut record)?, updated)
+        } else {
+            (Collection::new(vec![]), 0)
+        };
+        records.push(Record { peer, reputation, collection, updated });
+    }
+    if !buf.is_empty() {
+        return Err(MessageCodecError::DataLengthMismatch);
+    }
+    Ok(records)
+}
+
+/// A peer as written before records were signed, without a timestamp and
+/// with IPv4 addresses in 4 bytes:
+///
+/// ```text
+/// ip length u64 | ip | port u16 | accept u8 | name, public key, signature: u8 length + bytes
+/// ```
+fn decode_original_peer(buf: &mut BytesMut) -> Result<Peer, MessageCodecError> {
+    let ip = match take_u64(buf)? {
+        4 if buf.len() >= 4 => {
+            let mut octets = [0u8; 4];
+            octets.copy_from_slice(&buf.split_to(4));
+            IpAddr::V4(Ipv4Addr::from(octets))
+        },
+        16 if buf.len() >= 16 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&buf.split_to(16));
+            match Ipv6Addr::from(octets) {
+                ip if ip.segments()[..6] == [0, 0, 0, 0, 0, 0xffff] => IpAddr::V4(ip.to_ipv4().unwrap()),
+                ip => IpAddr::V6(ip),
+            }
+        },
+        _ => return Err(MessageCodecError::DataLengthMismatch),
+    };
+    let port = take_u16(buf)?;
+    let accept_incoming = take_u8(buf)? == 1;
+    let mut field = || -> Result<Option<String>, MessageCodecError> {
+        let len = take_u8(buf)? as usize;
+        take_nstring(buf, len)
+    };
+    let name = field()?;
+    let public_key = field()?;
+    let signature = field()?;
+    Ok(Peer::new(SocketAddr::new(ip, port), accept_incoming, name, public_key, signature))
+}
+
+/// A download in flight, as kept in the store's `transfers` tree.
+pub fn encode_transfer(peer: &SocketAddr, artist: &str, album: &str) -> Vec<u8> {
+    let mut buf = BytesMut::new();
+    put_section(&mut buf, peer.to_string().as_bytes());
+    put_section(&mut buf, artist.as_bytes());
+    put_section(&mut buf, album.as_bytes());
+    buf.to_vec()
+}
+
+pub fn decode_transfer(bytes: &[u8]) -> Option<(SocketAddr, String, String)> {
+    let mut buf = BytesMut::from(bytes);
+    let mut text = || take_section(&mut buf).ok().and_then(|b| String::from_utf8(b.to_vec()).ok());
+    let peer = text()?.parse().ok()?;
+    Some((peer, text()?, text()?))
+}
+
+/// offences u32 | banned u8 | reason u32+bytes | since i64 | permanent u8 | until i64
+pub fn encode_ban(ban: Option<&Ban>, offences: u32) -> Vec<u8> {
+    let mut buf = BytesMut::new();
+    buf.put_u32(offences);
+    match ban {
+        Some(ban) => {
+            buf.put_u8(1);
+            put_section(&mut buf, ban.reason.as_bytes());
+            buf.put_i64(ban.since.timestamp());
+            match ban.until {
+                Some(until) => {
+                    buf.put_u8(0);
+                    buf.put_i64(until.timestamp());
+                },
+                None => buf.put_u8(1),
+            }
+        },
+        None => buf.put_u8(0),
+    }
+    buf.to_vec()
+}
+
+pub fn decode_ban(bytes: &[u8]) -> Option<(Option<Ban>, u32)> {
+    let mut buf = BytesMut::from(bytes);
+    let offences = take_u32(&mut buf).ok()?;
+    if take_u8(&mut buf).ok()? == 0 {
+        return Some((None, offences));
+    }
+    let reason = String::from_utf8(take_section(&mut buf).ok()?.to_vec()).ok()?;
+    let since = take_time(&mut buf)?;
+    let until = match take_u8(&mut buf).ok()? {
+        0 => Some(take_time(&mut buf)?),
+        _ => None,
+    };
+    Some((Some(Ban { reason, since, until }), offences))
+}
+
+fn take_time(buf: &mut BytesMut) -> Option<DateTime<Utc>> {
+    let secs = take_u64(buf).ok()? as i64;
+    Utc.timestamp_opt(secs, 0).single()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv6Addr};
+    use crate::models::ArtistData;
+
+    fn record(name: &str) -> Record {
+        let addr = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 8000);
+        Record {
+            peer: Peer::new(addr, true, Some(name.to_string()), None, None),
+            reputation: Reputation::new(),
+            collection: Collection::new(vec![ArtistData::new("artist".to_string(), None)]),
+            updated: 42,
+        }
+    }
+
+    /// a peer as `Peer::to_bytes` wrote it with u8 lengths
+    fn narrow_bytes(peer: &Peer) -> BytesMut {
+        let mut buf = peer.to_bytes();
+        let fixed = buf.len() - 12 - peer.name.as_deref().unwrap_or("").len() - peer.public_key().unwrap_or("").len();
+        let mut narrow = buf.split_to(fixed);
+        for field in &[peer.name.as_deref(), peer.public_key(), None] {
+            let field = field.unwrap_or("");
+            narrow.put_u8(field.len() as u8);
+            narrow.put(field.as_bytes());
+        }
+        narrow
+    }
+
+    #[test]
+    fn test_crc32() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        // more than a u8 count, a u8 record length or a u8 name length could hold
+        let mut records: Vec<Record> = (0..300).map(|_| record(&"x".repeat(250))).collect();
+        records.push(record(&"y".repeat(256)));
+        records.push(record(&"z".repeat(70_000)));
+        let bytes = encode(&records);
+        assert_eq!(decode(&bytes).unwrap(), records);
+
+        let entry = encode_peer_entry(&records[301]);
+        assert_eq!(decode_peer_entry(&mut entry.clone()).unwrap().peer, records[301].peer);
+    }
+
+    #[test]
+    fn test_narrow_entries() {
+        // store entries and version 2 files from before the lengths were widened
+        let record = Record { collection: Collection::new(vec![]), ..record("alice") };
+        let mut entry = BytesMut::new();
+        put_section(&mut entry, &narrow_bytes(&record.peer));
+        put_section(&mut entry, &record.reputation.to_bytes());
+        entry.put_u64(record.updated);
+        assert_eq!(decode_peer_entry(&mut entry.clone()).unwrap(), record);
+
+        let mut payload = entry;
+        put_section(&mut payload, &record.collection.to_bytes());
+        let mut file = BytesMut::new();
+        file.put(&MAGIC[..]);
+        file.put_u16(2);
+        file.put_u64(1);
+        file.put_u32(payload.len() as u32);
+        file.put(&payload[..]);
+        file.put_u32(crc32(&payload));
+        assert_eq!(decode(&file).unwrap(), vec![record]);
+    }
+
+    #[test]
+    fn test_ban_round_trip() {
+        let now = Utc.timestamp(1_600_000_000, 0);
+        let temporary = Ban { reason: "spam".to_string(), since: now, until: Some(now + chrono::Duration::minutes(10)) };
+        let permanent = Ban { reason: String::new(), since: now, until: None };
+        assert_eq!(decode_ban(&encode_ban(Some(&temporary), 2)), Some((Some(temporary), 2)));
+        assert_eq!(decode_ban(&encode_ban(Some(&permanent), 4)), Some((Some(permanent), 4)));
+        assert_eq!(decode_ban(&encode_ban(None, 1)), Some((None, 1)));
+        assert_eq!(decode_ban(&[0, 0, 0, 1, 1]), None);
+    }
+
+    #[test]
+    fn test_corruption() {
+        let bytes = encode(&[record("a")]);
+        let mut flipped = bytes.to_vec();
+        let last = flipped.len() - 6;
+        flipped[last] ^= 0xFF;
+        match decode(&flipped) {
+            Err(DbError::Corrupt(_)) => {},
+            other => panic!("expected corruption, got {:?}", other),
+        }
+        match decode(&bytes[..bytes.len() - 3]) {
+            Err(DbError::Corrupt(_)) => {},
+            other => panic!("expected corruption, got {:?}", other),
+        }
+        let mut newer = bytes.to_vec();
+        newer[5] = 99;
+        match decode(&newer) {
+            Err(DbError::UnsupportedVersion(99)) => {},
+            other => panic!("expected a version error, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_migrate_legacy() {
+        // two peers as the first release saved them: u8 count, u8 lengths,
+        // no timestamps and an IPv4 address in 4 bytes
+        let legacy: &[u8] = &[
+            2,
+            21,
+            0, 0, 0, 0, 0, 0, 0, 4, 192, 168, 1, 5, 0x1f, 0x91, 1, 3, b'b', b'o', b'b', 0, 0,
+            32,
+            0, 0, 0, 0, 0, 0, 0, 16, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1, 0x1f, 0x40, 0,
+            0, 2, b'a', b'b', 0,
+        ];
+        let records = decode(legacy).unwrap();
+        let ipv4 = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(192, 168, 1, 5)), 8081);
+        let ipv6 = SocketAddr::new(IpAddr::V6(Ipv6Addr::LOCALHOST), 8000);
+        assert_eq!(records[0].peer, Peer::new(ipv4, true, Some("bob".to_string()), None, None));
+        assert_eq!(records[1].peer, Peer::new(ipv6, false, None, Some("ab".to_string()), None));
+        assert_eq!(records[0].collection, Collection::new(vec![]));
+
+        // later ones used u64 lengths and signed records with a reputation
+        let record = record("a");
+        let mut bytes = narrow_bytes(&record.peer);
+        bytes.put(&record.reputation.to_bytes()[..]);
+        let mut legacy = BytesMut::new();
+        legacy.put_u8(1);
+        legacy.put_u64(bytes.len() as u64);
+        legacy.put(&bytes[..]);
+        let records = decode(&legacy).unwrap();
+        assert_eq!(records[0].peer, record.peer);
+        assert_eq!(records[0].reputation, record.reputation);
+    }
+}
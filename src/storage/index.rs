@@ -0,0 +1,210 @@
+use std::collections::{HashMap, HashSet};
+use crate::models::{AlbumData, ArtistData, Collection, PeerKey};
+
+/// An artist, album or track, named case and spacing insensitively.
+#[derive(Clone, Debug, Hash, Eq, PartialEq)]
+pub enum ItemKey {
+    Artist(String),
+    Album(String, String),
+    Track(String, String, String),
+}
+
+impl ItemKey {
+    pub fn artist(artist: &str) -> Self {
+        ItemKey::Artist(normalize(artist))
+    }
+
+    pub fn album(artist: &str, album: &str) -> Self {
+        ItemKey::Album(normalize(artist), normalize(album))
+    }
+
+    pub fn track(artist: &str, album: &str, title: &str) -> Self {
+        ItemKey::Track(normalize(artist), normalize(album), normalize(title))
+    }
+}
+
+/// lower case with runs of whitespace collapsed
+pub fn normalize(name: &str) -> String {
+    name.split_whitespace()
+        .map(str::to_lowercase)
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// every key a collection holds
+fn keys_of(collection: &Collection) -> HashSet<ItemKey> {
+    collection.artists.iter().flat_map(artist_keys).collect()
+}
+
+/// keys of one artist and all its albums
+fn artist_keys(artist: &ArtistData) -> HashSet<ItemKey> {
+    let mut keys = HashSet::new();
+    keys.insert(ItemKey::artist(&artist.artist));
+    for album in artist.albums.iter().flatten() {
+        keys.extend(album_keys(&artist.artist, album));
+    }
+    keys
+}
+
+/// keys of one album of `artist`
+fn album_keys(artist: &str, album: &AlbumData) -> HashSet<ItemKey> {
+    let mut keys = HashSet::new();
+    keys.insert(ItemKey::album(artist, &album.album_title));
+    for track in album.tracks.iter().flatten() {
+        keys.insert(ItemKey::track(artist, &album.album_title, &track.title));
+    }
+    keys
+}
+
+/// Which peers hold each artist, album and track. Kept in step with the
+/// peers' collections by `Db`.
+#[derive(Default)]
+pub struct CatalogueIndex {
+    holders: HashMap<ItemKey, HashSet<PeerKey>>,
+    by_peer: HashMap<PeerKey, HashSet<ItemKey>>,
+}
+
+impl CatalogueIndex {
+    pub fn new() -> Self {
+        CatalogueIndex::default()
+    }
+
+    /// Index `peer` as holding `collection`, touching only what changed.
+    pub fn set(&mut self, peer: PeerKey, collection: &Collection) {
+        let keys = keys_of(collection);
+        let old = self.by_peer.remove(&peer).unwrap_or_default();
+        for key in old.difference(&keys) {
+            self.unlink(key, &peer);
+        }
+        for key in keys.difference(&old) {
+            self.holders.entry(key.clone()).or_default().insert(peer);
+        }
+        if !keys.is_empty() {
+            self.by_peer.insert(peer, keys);
+        }
+    }
+
+    /// `peer` replaced `old` with `album` under `artist`, touching only
+    /// that album's keys.
+    pub fn set_album(&mut self, peer: PeerKey, artist: &str, old: Option<&AlbumData>, album: &AlbumData) {
+        let keys = album_keys(artist, album);
+        let held = self.by_peer.entry(peer).or_default();
+        let gone: Vec<ItemKey> = old.map(|old| album_keys(artist, old)).unwrap_or_default().into_iter()
+            .filter(|key| !keys.contains(key) && held.remove(key))
+            .collect();
+        let fresh: Vec<ItemKey> = keys.into_iter().chain(Some(ItemKey::artist(artist)))
+            .filter(|key| held.insert(key.clone()))
+            .collect();
+        for key in gone {
+            self.unlink(&key, &peer);
+        }
+        for key in fresh {
+            self.holders.entry(key).or_default().insert(peer);
+        }
+    }
+
+    /// `peer`'s artist went from `old` to `new`, either missing when the
+    /// peer did not or no longer holds it, touching only that artist's keys.
+    pub fn set_artist(&mut self, peer: PeerKey, old: Option<&ArtistData>, new: Option<&ArtistData>) {
+        let keys = new.map(artist_keys).unwrap_or_default();
+        let held = self.by_peer.entry(peer).or_default();
+        let gone: Vec<ItemKey> = old.map(artist_keys).unwrap_or_default().into_iter()
+            .filter(|key| !keys.contains(key) && held.remove(key))
+            .collect();
+        let fresh: Vec<ItemKey> = keys.into_iter()
+            .filter(|key| held.insert(key.clone()))
+            .collect();
+        if held.is_empty() {
+            self.by_peer.remove(&peer);
+        }
+        for key in gone {
+            self.unlink(&key, &peer);
+        }
+        for key in fresh {
+            self.holders.entry(key).or_default().insert(peer);
+        }
+    }
+
+    pub fn remove_peer(&mut self, peer: &PeerKey) {
+        for key in self.by_peer.remove(peer).unwrap_or_default() {
+            self.unlink(&key, peer);
+        }
+    }
+
+    fn unlink(&mut self, key: &ItemKey, peer: &PeerKey) {
+        if let Some(peers) = self.holders.get_mut(key) {
+            peers.remove(peer);
+            if peers.is_empty() {
+                self.holders.remove(key);
+            }
+        }
+    }
+
+    /// peers holding `key`, in no particular order
+    pub fn holders(&self, key: &ItemKey) -> Vec<PeerKey> {
+        self.holders.get(key).map_or(vec![], |peers| peers.iter().copied().collect())
+    }
+
+    /// distinct artists, albums and tracks known across the network
+    pub fn len(&self) -> usize {
+        self.holders.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+    use crate::models::{ArtistData, TrackData};
+
+    fn collection(album: &str) -> Collection {
+        Collection::new(vec![ArtistData::new("The  Artist".to_string(), Some(vec![AlbumData::new(
+            None,
+            album.to_string(),
+            1,
+            Some(vec![TrackData::new("Song".to_string(), 320, 200)]),
+        )]))])
+    }
+
+    #[test]
+    fn test_index() {
+        let ip1 = PeerKey::Addr(SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 8000));
+        let ip2 = PeerKey::Addr(SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 2)), 8000));
+        let mut index = CatalogueIndex::new();
+        index.set(ip1, &collection("First"));
+        index.set(ip2, &collection("Second"));
+
+        let mut artist = index.holders(&ItemKey::artist("the artist"));
+        artist.sort();
+        assert_eq!(artist, vec![ip1, ip2]);
+        assert_eq!(index.holders(&ItemKey::album("THE ARTIST", "first")), vec![ip1]);
+        assert_eq!(index.holders(&ItemKey::track("the artist", "second", " song ")), vec![ip2]);
+
+        // the first album went away
+        index.set(ip1, &collection("Second"));
+        assert!(index.holders(&ItemKey::album("the artist", "first")).is_empty());
+        assert_eq!(index.holders(&ItemKey::album("the artist", "second")).len(), 2);
+
+        // the second album of ip2 lost its track
+        let mut album = collection("Second").artists[0].albums.clone().unwrap().remove(0);
+        let old = album.clone();
+        album.tracks = None;
+        index.set_album(ip2, "The  Artist", Some(&old), &album);
+        assert_eq!(index.holders(&ItemKey::track("the artist", "second", "song")), vec![ip1]);
+        assert_eq!(index.holders(&ItemKey::album("the artist", "second")).len(), 2);
+
+        // ip2 no longer holds the artist, ip1 gets a new album of it
+        let artist = collection("Second").artists.remove(0);
+        index.set_artist(ip2, Some(&artist), None);
+        assert_eq!(index.holders(&ItemKey::artist("the artist")), vec![ip1]);
+        let mut more = artist.clone();
+        more.albums.as_mut().unwrap().push(AlbumData::new(None, "Third".to_string(), 1, None));
+        index.set_artist(ip1, Some(&artist), Some(&more));
+        assert_eq!(index.holders(&ItemKey::album("the artist", "third")), vec![ip1]);
+        assert_eq!(index.holders(&ItemKey::track("the artist", "second", "song")), vec![ip1]);
+
+        index.remove_peer(&ip1);
+        index.set(ip2, &Collection::new(vec![]));
+        assert_eq!(index.len(), 0);
+    }
+}
@@ -0,0 +1,562 @@
+//! A small embedded key-value store: named trees of byte keys and values,
+//! kept in memory and persisted as an append-only log of batches.
+//!
+//! ```text
+//! magic "SNKV" | version u16 | frames...
+//! frame: length u32 | crc32 u32 of the length | ops | crc32 u32 of the ops
+//! op: kind u8 | tree u8+bytes | key u32+bytes | value u32+bytes (inserts only)
+//! ```
+//!
+//! A batch is one frame, so it lands whole or not at all. A frame whose
+//! length checks out but runs past the end of the file is a write cut
+//! short by a crash and is dropped on open. A bad length, or a bad frame
+//! with more log after it, makes the whole store corrupt so its backup is
+//! loaded instead. Version 1 logs have no length check and are rewritten
+//! on open. The log is rewritten once it grows well past the live data.
+//! Only one process may have a store open at a time, an open store holds
+//! `<path>.lock` with its pid.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::Path;
+use std::process;
+
+use bytes::{Buf, BufMut, BytesMut};
+
+use super::atomic::{load_with_backup, write_atomic};
+use super::format::{crc32, DbError};
+
+pub const MAGIC: &[u8; 4] = b"SNKV";
+const VERSION: u16 = 2;
+const HEADER_LEN: u64 = 6;
+/// length and its checksum before the ops, the ops checksum after
+const FRAME_HEADER_LEN: usize = 8;
+const FRAME_OVERHEAD: usize = FRAME_HEADER_LEN + 4;
+
+const INSERT: u8 = 1;
+const REMOVE: u8 = 2;
+
+/// compact once the log is this many times the size of the live data
+const COMPACT_RATIO: u64 = 4;
+/// never bother compacting logs smaller than this
+const COMPACT_MIN_BYTES: u64 = 1 << 20;
+
+pub type Tree = BTreeMap<Vec<u8>, Vec<u8>>;
+
+#[derive(Clone, Debug, PartialEq)]
+enum Op {
+    Insert(String, Vec<u8>, Vec<u8>),
+    Remove(String, Vec<u8>),
+}
+
+/// Changes applied together by `Store::apply`.
+#[derive(Clone, Debug, Default)]
+pub struct Batch {
+    ops: Vec<Op>,
+}
+
+impl Batch {
+    pub fn new() -> Self {
+        Batch::default()
+    }
+
+    pub fn insert(&mut self, tree: &str, key: &[u8], value: &[u8]) {
+        self.ops.push(Op::Insert(tree.to_string(), key.to_vec(), value.to_vec()));
+    }
+
+    pub fn remove(&mut self, tree: &str, key: &[u8]) {
+        self.ops.push(Op::Remove(tree.to_string(), key.to_vec()));
+    }
+
+    /// Make `tree` hold exactly `entries`, writing only what differs.
+    pub fn sync_tree(&mut self, store: &Store, tree: &str, entries: Tree) {
+        for (key, _) in store.iter(tree) {
+            if !entries.contains_key(key) {
+                self.remove(tree, key);
+            }
+        }
+        for (key, value) in entries {
+            if store.get(tree, &key) != Some(&value[..]) {
+                self.insert(tree, &key, &value);
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ops.is_empty()
+    }
+
+    fn to_frame(&self) -> BytesMut {
+        let mut ops = BytesMut::new();
+        for op in &self.ops {
+            let (kind, tree, key, value) = match op {
+                Op::Insert(tree, key, value) => (INSERT, tree, key, Some(value)),
+                Op::Remove(tree, key) => (REMOVE, tree, key, None),
+            };
+            ops.put_u8(kind);
+            ops.put_u8(tree.len() as u8);
+            ops.put(tree.as_bytes());
+            ops.put_u32(key.len() as u32);
+            ops.put(&key[..]);
+            if let Some(value) = value {
+                ops.put_u32(value.len() as u32);
+                ops.put(&value[..]);
+            }
+        }
+        let mut frame = BytesMut::with_capacity(ops.len() + FRAME_OVERHEAD);
+        let len = (ops.len() as u32).to_be_bytes();
+        frame.put(&len[..]);
+        frame.put_u32(crc32(&len));
+        frame.put(&ops[..]);
+        frame.put_u32(crc32(&ops));
+        frame
+    }
+
+    fn from_ops(mut ops: BytesMut) -> Option<Batch> {
+        fn take(buf: &mut BytesMut, len: usize) -> Option<Vec<u8>> {
+            if buf.len() < len {
+                return None;
+            }
+            Some(buf.split_to(len).to_vec())
+        }
+        fn take_len(buf: &mut BytesMut) -> Option<usize> {
+            if buf.len() < 4 {
+                return None;
+            }
+            Some(buf.get_u32() as usize)
+        }
+        let mut batch = Batch::new();
+        while !ops.is_empty() {
+            let kind = ops.get_u8();
+            let tree_len = take(&mut ops, 1)?[0] as usize;
+            let tree = String::from_utf8(take(&mut ops, tree_len)?).ok()?;
+            let key_len = take_len(&mut ops)?;
+            let key = take(&mut ops, key_len)?;
+            match kind {
+                INSERT => {
+                    let value_len = take_len(&mut ops)?;
+                    batch.ops.push(Op::Insert(tree, key, take(&mut ops, value_len)?));
+                },
+                REMOVE => batch.ops.push(Op::Remove(tree, key)),
+                _ => return None,
+            }
+        }
+        Some(batch)
+    }
+}
+
+/// Where an open store notes who has it.
+pub fn lock_path(path: &str) -> String {
+    format!("{}.lock", path)
+}
+
+/// The claim on a store, released when the store is dropped.
+struct Lock {
+    path: String,
+}
+
+impl Lock {
+    /// Fails with `DbError::Locked` while another running process holds
+    /// the store. A lock left behind by a process that died is taken over.
+    fn take(store: &str) -> Result<Lock, DbError> {
+        let path = lock_path(store);
+        match OpenOptions::new().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                write!(file, "{}", process::id())?;
+                Ok(Lock { path })
+            },
+            Err(ref e) if e.kind() == io::ErrorKind::AlreadyExists => {
+                let owner = fs::read_to_string(&path).ok().and_then(|pid| pid.trim().parse().ok());
+                match owner {
+                    Some(pid) if !is_running(pid) => {
+                        println!("taking over the lock on {} left by stopped process {}", store, pid);
+                        fs::remove_file(&path)?;
+                        Lock::take(store)
+                    },
+                    _ => Err(DbError::Locked(store.to_string(), owner)),
+                }
+            },
+            Err(e) => Err(e.into()),
+        }
+    }
+}
+
+impl Drop for Lock {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// without `/proc` there is no telling, so the process is assumed alive
+fn is_running(pid: u32) -> bool {
+    !Path::new("/proc/self").exists() || Path::new(&format!("/proc/{}", pid)).exists()
+}
+
+pub struct Store {
+    path: String,
+    file: File,
+    trees: HashMap<String, Tree>,
+    /// bytes in the log, and what a fresh log of the live data would take
+    log_bytes: u64,
+    live_bytes: u64,
+    _lock: Lock,
+}
+
+impl Store {
+    /// Replace whatever is at `path` with a store holding `batch`, the
+    /// old file is kept as its backup, see `write_atomic`.
+    pub fn create(path: &str, batch: &Batch) -> io::Result<Store> {
+        let into_io = |e| match e {
+            DbError::Io(e) => e,
+            e => io::Error::new(io::ErrorKind::Other, e.to_string()),
+        };
+        let lock = Lock::take(path).map_err(into_io)?;
+        write_log(path, batch)?;
+        Store::load(path, lock).map_err(into_io)
+    }
+
+    /// Open the store at `path`, creating an empty one if there is none.
+    pub fn open(path: &str) -> Result<Store, DbError> {
+        let lock = Lock::take(path)?;
+        Store::load(path, lock)
+    }
+
+    fn load(path: &str, lock: Lock) -> Result<Store, DbError> {
+        let (trees, good, len, version) = match load_with_backup(path, |bytes| replay(path, bytes))? {
+            Some(replayed) => replayed,
+            None => {
+                write_log(path, &Batch::new())?;
+                return Store::load(path, lock);
+            },
+        };
+        let file = OpenOptions::new().append(true).open(path)?;
+        if good < len {
+            println!("dropping {} bytes of an unfinished write at the end of {}", len - good, path);
+            file.set_len(good)?;
+            file.sync_all()?;
+        }
+        let mut store = Store {
+            path: path.to_string(),
+            file,
+            trees,
+            log_bytes: good,
+            live_bytes: 0,
+            _lock: lock,
+        };
+        store.live_bytes = store.snapshot().to_frame().len() as u64 + HEADER_LEN;
+        if version < VERSION {
+            store.compact()?;
+        }
+        Ok(store)
+    }
+
+    pub fn path(&self) -> &str {
+        &self.path
+    }
+
+    pub fn get(&self, tree: &str, key: &[u8]) -> Option<&[u8]> {
+        self.trees.get(tree)?.get(key).map(|value| &value[..])
+    }
+
+    /// entries of `tree` in key order
+    pub fn iter<'a>(&'a self, tree: &str) -> impl Iterator<Item = (&'a [u8], &'a [u8])> + 'a {
+        self.trees.get(tree)
+            .into_iter()
+            .flat_map(|tree| tree.iter().map(|(key, value)| (&key[..], &value[..])))
+    }
+
+    pub fn len(&self, tree: &str) -> usize {
+        self.trees.get(tree).map_or(0, |tree| tree.len())
+    }
+
+    /// Write `batch` to disk, then apply it. Nothing is applied if the
+    /// write fails.
+    pub fn apply(&mut self, batch: Batch) -> io::Result<()> {
+        if batch.is_empty() {
+            return Ok(());
+        }
+        let frame = batch.to_frame();
+        self.file.write_all(&frame[..])?;
+        self.file.sync_data()?;
+        self.log_bytes += frame.len() as u64;
+        for op in &batch.ops {
+            let (tree, key) = match op {
+                Op::Insert(tree, key, _) | Op::Remove(tree, key) => (tree, key),
+            };
+            if let Some(old) = self.get(tree, key) {
+                self.live_bytes -= entry_bytes(tree, key, old);
+            }
+            if let Op::Insert(tree, key, value) = op {
+                self.live_bytes += entry_bytes(tree, key, value);
+            }
+        }
+        apply_to(&mut self.trees, batch);
+        if self.log_bytes > COMPACT_MIN_BYTES && self.log_bytes > self.live_bytes * COMPACT_RATIO {
+            self.compact()?;
+        }
+        Ok(())
+    }
+
+    /// Rewrite the log as a single batch of the live entries.
+    pub fn compact(&mut self) -> io::Result<()> {
+        write_log(&self.path, &self.snapshot())?;
+        self.file = OpenOptions::new().append(true).open(&self.path)?;
+        self.log_bytes = self.file.metadata()?.len();
+        self.live_bytes = self.log_bytes;
+        Ok(())
+    }
+
+    fn snapshot(&self) -> Batch {
+        let mut batch = Batch::new();
+        for (name, tree) in &self.trees {
+            for (key, value) in tree {
+                batch.insert(name, key, value);
+            }
+        }
+        batch
+    }
+}
+
+/// Replace `path` with a log of just `batch`.
+fn write_log(path: &str, batch: &Batch) -> io::Result<()> {
+    let mut bytes = BytesMut::new();
+    bytes.put(&MAGIC[..]);
+    bytes.put_u16(VERSION);
+    bytes.put(&batch.to_frame()[..]);
+    write_atomic(path, &bytes)
+}
+
+/// The trees in a log, with the length of its intact part and of the
+/// whole file, and the log's version.
+fn replay(path: &str, bytes: &[u8]) -> Result<(HashMap<String, Tree>, u64, u64, u16), DbError> {
+    if !bytes.starts_with(MAGIC) || bytes.len() < HEADER_LEN as usize {
+        return Err(DbError::Corrupt(format!("{} is not a key-value store", path)));
+    }
+    let version = (&bytes[4..6]).get_u16();
+    if version > VERSION {
+        return Err(DbError::UnsupportedVersion(version));
+    }
+
+    // version 1 frames have no checksum of their length
+    let (header_len, overhead) = match version {
+        1 => (4, 8),
+        _ => (FRAME_HEADER_LEN, FRAME_OVERHEAD),
+    };
+    let mut trees = HashMap::new();
+    let mut log = BytesMut::from(&bytes[HEADER_LEN as usize..]);
+    let mut good = HEADER_LEN;
+    while log.len() >= header_len {
+        let len = (&log[..4]).get_u32() as usize;
+        if header_len == FRAME_HEADER_LEN && (&log[4..8]).get_u32() != crc32(&log[..4]) {
+            return Err(DbError::Corrupt(format!("a batch length in {} fails its checksum", path)));
+        }
+        // the length is good, so the file ends inside the frame
+        if log.len() < len + overhead {
+            break;
+        }
+        log.advance(header_len);
+        let ops = log.split_to(len);
+        let crc = log.get_u32();
+        if crc != crc32(&ops) {
+            // a crash only tears the last write
+            if !log.is_empty() {
+                return Err(DbError::Corrupt(format!("a batch in {} fails its checksum", path)));
+            }
+            break;
+        }
+        let batch = Batch::from_ops(ops)
+            .ok_or_else(|| DbError::Corrupt(format!("bad batch in {}", path)))?;
+        apply_to(&mut trees, batch);
+        good += (len + overhead) as u64;
+    }
+    Ok((trees, good, bytes.len() as u64, version))
+}
+
+/// size of an entry's insert op in the log
+fn entry_bytes(tree: &str, key: &[u8], value: &[u8]) -> u64 {
+    (1 + 1 + tree.len() + 4 + key.len() + 4 + value.len()) as u64
+}
+
+fn apply_to(trees: &mut HashMap<String, Tree>, batch: Batch) {
+    for op in batch.ops {
+        match op {
+            Op::Insert(tree, key, value) => {
+                trees.entry(tree).or_default().insert(key, value);
+            },
+            Op::Remove(tree, key) => {
+                if let Some(tree) = trees.get_mut(&tree) {
+                    tree.remove(&key);
+                }
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+    use crate::storage::atomic::{backup_path, remove};
+
+    #[test]
+    fn test_store() {
+        let path = "/tmp/test_store.kv";
+        remove(path).unwrap();
+        let mut store = Store::open(path).unwrap();
+        let mut batch = Batch::new();
+        batch.insert("peers", b"a", b"1");
+        batch.insert("peers", b"b", b"2");
+        batch.insert("trust", b"a", b"3");
+        store.apply(batch).unwrap();
+        let mut batch = Batch::new();
+        batch.remove("peers", b"a");
+        batch.insert("peers", b"b", b"4");
+        store.apply(batch).unwrap();
+
+        drop(store);
+        let store = Store::open(path).unwrap();
+        assert_eq!(store.get("peers", b"a"), None);
+        assert_eq!(store.get("peers", b"b"), Some(&b"4"[..]));
+        assert_eq!(store.get("trust", b"a"), Some(&b"3"[..]));
+        assert_eq!(store.len("peers"), 1);
+
+        // only the difference is written
+        let mut entries = Tree::new();
+        entries.insert(b"b".to_vec(), b"4".to_vec());
+        entries.insert(b"c".to_vec(), b"5".to_vec());
+        let mut batch = Batch::new();
+        batch.sync_tree(&store, "peers", entries);
+        assert_eq!(batch.ops, vec![Op::Insert("peers".to_string(), b"c".to_vec(), b"5".to_vec())]);
+    }
+
+    #[test]
+    fn test_torn_write() {
+        let path = "/tmp/test_torn.kv";
+        remove(path).unwrap();
+        let mut store = Store::open(path).unwrap();
+        let mut batch = Batch::new();
+        batch.insert("peers", b"a", b"1");
+        store.apply(batch).unwrap();
+        let complete = fs::metadata(path).unwrap().len();
+
+        // a second batch cut short by a crash
+        let mut batch = Batch::new();
+        batch.insert("peers", b"b", b"2");
+        let frame = batch.to_frame();
+        let mut file = OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(&frame[..frame.len() - 3]).unwrap();
+
+        drop(store);
+        let store = Store::open(path).unwrap();
+        assert_eq!(store.get("peers", b"a"), Some(&b"1"[..]));
+        assert_eq!(store.get("peers", b"b"), None);
+        assert_eq!(fs::metadata(path).unwrap().len(), complete);
+    }
+
+    #[test]
+    fn test_corrupt_batch() {
+        let path = "/tmp/test_corrupt.kv";
+        remove(path).unwrap();
+        let mut store = Store::open(path).unwrap();
+        let mut batch = Batch::new();
+        batch.insert("peers", b"a", b"1");
+        store.apply(batch).unwrap();
+        fs::copy(path, backup_path(path)).unwrap();
+        let mut batch = Batch::new();
+        batch.insert("peers", b"b", b"2");
+        store.apply(batch).unwrap();
+
+        // flip a byte of the first batch, the second one is still intact
+        let mut bytes = fs::read(path).unwrap();
+        let last = bytes.len() - 1;
+        bytes[HEADER_LEN as usize + FRAME_HEADER_LEN + 2] ^= 0xFF;
+        let corrupt = |bytes: &[u8]| match replay(path, bytes) {
+            Err(DbError::Corrupt(_)) => {},
+            other => panic!("expected corruption, got {:?}", other.map(|(_, good, len, _)| (good, len))),
+        };
+        corrupt(&bytes);
+        // a length running past the end is no torn write if it is wrong
+        let mut long = fs::read(path).unwrap();
+        long[HEADER_LEN as usize] = 0x7F;
+        corrupt(&long);
+        // the same damage in the last batch is a torn write
+        let mut torn = fs::read(path).unwrap();
+        torn[last - 6] ^= 0xFF;
+        assert!(replay(path, &torn).is_ok());
+
+        fs::write(path, &bytes).unwrap();
+        drop(store);
+        let store = Store::open(path).unwrap();
+        assert_eq!(store.get("peers", b"a"), Some(&b"1"[..]));
+        assert_eq!(store.get("peers", b"b"), None);
+    }
+
+    #[test]
+    fn test_version_1() {
+        let path = "/tmp/test_version_1.kv";
+        remove(path).unwrap();
+        let mut bytes = BytesMut::new();
+        bytes.put(&MAGIC[..]);
+        bytes.put_u16(1);
+        for (key, value) in &[(b"a", b"1"), (b"b", b"2")] {
+            let mut ops = BytesMut::new();
+            ops.put_u8(INSERT);
+            ops.put_u8(5);
+            ops.put(&b"peers"[..]);
+            ops.put_u32(1);
+            ops.put(&key[..]);
+            ops.put_u32(1);
+            ops.put(&value[..]);
+            bytes.put_u32(ops.len() as u32);
+            bytes.put(&ops[..]);
+            bytes.put_u32(crc32(&ops));
+        }
+        fs::write(path, &bytes).unwrap();
+
+        // read, then rewritten with checked lengths
+        let store = Store::open(path).unwrap();
+        assert_eq!(store.get("peers", b"b"), Some(&b"2"[..]));
+        let rewritten = fs::read(path).unwrap();
+        assert_eq!(replay(path, &rewritten).unwrap().3, VERSION);
+        drop(store);
+        assert_eq!(Store::open(path).unwrap().get("peers", b"a"), Some(&b"1"[..]));
+    }
+
+    #[test]
+    fn test_compact() {
+        let path = "/tmp/test_compact.kv";
+        remove(path).unwrap();
+        let mut store = Store::open(path).unwrap();
+        let value = vec![0u8; 64 * 1024];
+        for _ in 0..80 {
+            let mut batch = Batch::new();
+            batch.insert("collections", b"a", &value);
+            store.apply(batch).unwrap();
+        }
+        assert!(fs::metadata(path).unwrap().len() < COMPACT_MIN_BYTES);
+        drop(store);
+        assert_eq!(Store::open(path).unwrap().get("collections", b"a"), Some(&value[..]));
+    }
+
+    #[test]
+    fn test_lock() {
+        let path = "/tmp/test_lock.kv";
+        remove(path).unwrap();
+        let store = Store::open(path).unwrap();
+        match Store::open(path) {
+            Err(DbError::Locked(locked, pid)) => assert_eq!((locked.as_str(), pid), (path, Some(process::id()))),
+            other => panic!("expected the store to be locked, got {:?}", other.map(|_| ())),
+        }
+        assert!(Store::create(path, &Batch::new()).is_err());
+        drop(store);
+        assert!(!Path::new(&lock_path(path)).exists());
+
+        // left behind by a process that is gone
+        fs::write(lock_path(path), format!("{}", u32::MAX)).unwrap();
+        if Path::new("/proc/self").exists() {
+            drop(Store::open(path).unwrap());
+        }
+        let _ = fs::remove_file(lock_path(path));
+    }
+}
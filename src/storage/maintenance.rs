@@ -0,0 +1,250 @@
+//! Periodic upkeep of the world. Each job is a list of systems run in
+//! order once its interval has passed, `Db::run_maintenance` is ticked by
+//! `handlers::scheduler`. Adding a job is writing a system and passing it
+//! to `Maintenance::add_job`.
+//!
+//! Jobs hold their systems rather than a specs `Dispatcher`: a dispatcher
+//! is not `Send`, and the database moves to its own thread.
+
+use std::time::{Duration, Instant};
+
+use specs::prelude::{Join, Read, ReadStorage, RunNow, System, World, WorldExt, Write, WriteStorage};
+
+use super::index::CatalogueIndex;
+use super::stats::{ConnectionState, LastSeen};
+use crate::args::MaintenanceIntervals;
+use crate::ecs::Node;
+use crate::models::{Catalogue, Peer, Reputation};
+
+pub const LIVENESS: &str = "liveness";
+pub const REPUTATION_DECAY: &str = "reputation decay";
+pub const INDEX_REBUILD: &str = "index rebuild";
+pub const CHECKPOINT: &str = "checkpoint";
+
+/// online peers not seen for this long are taken to be offline
+pub const OFFLINE_AFTER_SECS: u64 = 60;
+/// share of a reputation kept on every decay
+pub const REPUTATION_KEPT: f64 = 0.9;
+
+/// unix seconds when the jobs being run were started
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Clock(pub u64);
+
+/// Set by `CheckpointSystem`, the database saves and clears it.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Checkpoint {
+    pub due: bool,
+}
+
+/// Marks peers offline once they stop pinging, for when the connection
+/// never said goodbye.
+pub struct LivenessSystem;
+
+impl<'a> System<'a> for LivenessSystem {
+    type SystemData = (Read<'a, Clock>, ReadStorage<'a, LastSeen>, WriteStorage<'a, ConnectionState>);
+
+    fn run(&mut self, (clock, seen, mut states): Self::SystemData) {
+        for (seen, state) in (&seen, &mut states).join() {
+            if state.is_online() && seen.0 + OFFLINE_AFTER_SECS < clock.0 {
+                *state = ConnectionState::Offline;
+            }
+        }
+    }
+}
+
+/// Lets peers live down old behaviour, see `Reputation::decay`.
+pub struct ReputationDecaySystem;
+
+impl<'a> System<'a> for ReputationDecaySystem {
+    type SystemData = WriteStorage<'a, Reputation>;
+
+    fn run(&mut self, mut reputations: Self::SystemData) {
+        for reputation in (&mut reputations).join() {
+            reputation.decay(REPUTATION_KEPT);
+        }
+    }
+}
+
+/// Rebuilds the `CatalogueIndex` from every catalogue, in case the
+/// incremental updates ever drift from them.
+pub struct IndexRebuildSystem;
+
+impl<'a> System<'a> for IndexRebuildSystem {
+    type SystemData = (ReadStorage<'a, Peer>, ReadStorage<'a, Catalogue>, Write<'a, CatalogueIndex>);
+
+    fn run(&mut self, (peers, catalogues, mut index): Self::SystemData) {
+        let mut rebuilt = CatalogueIndex::new();
+        for (peer, catalogue) in (&peers, &catalogues).join() {
+            rebuilt.set(peer.key(), &catalogue.to_collection());
+        }
+        *index = rebuilt;
+    }
+}
+
+/// Asks for a save. The store sits outside the world, so the database
+/// does the writing once the job has run.
+pub struct CheckpointSystem;
+
+impl<'a> System<'a> for CheckpointSystem {
+    type SystemData = Write<'a, Checkpoint>;
+
+    fn run(&mut self, mut checkpoint: Self::SystemData) {
+        checkpoint.due = true;
+    }
+}
+
+/// One system of a job, run on the database's own thread so it has to
+/// be `Send`.
+pub type JobSystem = Box<dyn for<'a> RunNow<'a> + Send>;
+
+struct Job {
+    name: &'static str,
+    every: Duration,
+    /// when it was added or last ran on schedule
+    last_run: Instant,
+    next: Instant,
+    systems: Vec<JobSystem>,
+}
+
+impl Job {
+    fn run(&mut self, world: &mut World) {
+        for system in &mut self.systems {
+            system.run_now(world);
+        }
+    }
+}
+
+/// The registered jobs and when each is next due.
+pub struct Maintenance {
+    jobs: Vec<Job>,
+}
+
+impl Maintenance {
+    /// the built in jobs at the given intervals
+    pub fn new(world: &mut World, intervals: &MaintenanceIntervals) -> Self {
+        let mut maintenance = Maintenance { jobs: vec![] };
+        maintenance.add_job(world, LIVENESS, intervals.liveness, vec![Box::new(LivenessSystem)]);
+        maintenance.add_job(world, REPUTATION_DECAY, intervals.reputation_decay, vec![Box::new(ReputationDecaySystem)]);
+        maintenance.add_job(world, INDEX_REBUILD, intervals.index_rebuild, vec![Box::new(IndexRebuildSystem)]);
+        maintenance.add_job(world, CHECKPOINT, intervals.checkpoint, vec![Box::new(CheckpointSystem)]);
+        maintenance
+    }
+
+    /// Run `systems` in order every `every`, first one interval from now.
+    /// A job of the same name is replaced.
+    pub fn add_job(&mut self, world: &mut World, name: &'static str, every: Duration, mut systems: Vec<JobSystem>) {
+        for system in &mut systems {
+            system.setup(world);
+        }
+        self.jobs.retain(|job| job.name != name);
+        let now = Instant::now();
+        self.jobs.push(Job {
+            name,
+            every,
+            last_run: now,
+            next: now + every,
+            systems,
+        });
+    }
+
+    /// Change how often a job runs, counting from its last run, false if
+    /// there is no such job. A job already overdue runs at `now`.
+    pub fn set_interval(&mut self, name: &str, every: Duration, now: Instant) -> bool {
+        match self.jobs.iter_mut().find(|job| job.name == name) {
+            Some(job) => {
+                job.next = now.max(job.last_run + every);
+                job.every = every;
+                true
+            },
+            None => false,
+        }
+    }
+
+    /// Run the jobs due at `now` in the order they were added, returns
+    /// their names.
+    pub fn run_due(&mut self, world: &mut World, now: Instant) -> Vec<&'static str> {
+        let mut ran = vec![];
+        for job in &mut self.jobs {
+            if job.next > now {
+                continue;
+            }
+            job.run(world);
+            job.last_run = now;
+            job.next = now + job.every;
+            ran.push(job.name);
+        }
+        world.maintain();
+        ran
+    }
+
+    /// Run a job now whether it is due or not, false if there is no such job.
+    pub fn run(&mut self, world: &mut World, name: &str) -> bool {
+        match self.jobs.iter_mut().find(|job| job.name == name) {
+            Some(job) => {
+                job.run(world);
+                world.maintain();
+                true
+            },
+            None => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use specs::prelude::Builder;
+
+    #[test]
+    fn test_run_due() {
+        let mut world = World::new();
+        let mut intervals = MaintenanceIntervals::default();
+        intervals.liveness = Duration::from_secs(10);
+        let mut maintenance = Maintenance::new(&mut world, &intervals);
+        let online = world.create_entity()
+            .with(ConnectionState::Online(0))
+            .with(LastSeen(100))
+            .build();
+        let quiet = world.create_entity()
+            .with(ConnectionState::Online(0))
+            .with(LastSeen(10))
+            .build();
+        world.insert(Clock(100));
+
+        let start = Instant::now();
+        assert!(maintenance.run_due(&mut world, start).is_empty());
+        assert_eq!(maintenance.run_due(&mut world, start + Duration::from_secs(11)), vec![LIVENESS]);
+        let states = world.read_storage::<ConnectionState>();
+        assert!(states.get(online).unwrap().is_online());
+        assert!(!states.get(quiet).unwrap().is_online());
+        drop(states);
+
+        let at = |secs| start + Duration::from_secs(secs);
+        assert!(maintenance.set_interval(CHECKPOINT, Duration::from_secs(0), at(12)));
+        assert_eq!(maintenance.run_due(&mut world, at(12)), vec![CHECKPOINT]);
+        assert!(world.fetch::<Checkpoint>().due);
+        assert!(!maintenance.run(&mut world, "no such job"));
+    }
+
+    #[test]
+    fn test_set_interval() {
+        let mut world = World::new();
+        world.insert(Clock(0));
+        let mut intervals = MaintenanceIntervals::default();
+        intervals.liveness = Duration::from_secs(10);
+        let mut maintenance = Maintenance::new(&mut world, &intervals);
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        assert_eq!(maintenance.run_due(&mut world, at(11)), vec![LIVENESS]);
+
+        // longer, counted from the last run
+        assert!(maintenance.set_interval(LIVENESS, Duration::from_secs(60), at(12)));
+        assert!(maintenance.run_due(&mut world, at(70)).is_empty());
+        assert_eq!(maintenance.run_due(&mut world, at(71)), vec![LIVENESS]);
+
+        // shorter, and already overdue
+        assert!(maintenance.set_interval(LIVENESS, Duration::from_secs(5), at(100)));
+        assert_eq!(maintenance.run_due(&mut world, at(100)), vec![LIVENESS]);
+        assert!(!maintenance.set_interval("no such job", Duration::from_secs(5), at(100)));
+    }
+}
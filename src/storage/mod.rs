@@ -0,0 +1,1891 @@
+use bytes::BytesMut;
+use std::io;
+use std::io::prelude::*;
+use std::fs::{self, File};
+
+use hibitset::BitSetLike;
+use shrev::EventChannel;
+use specs::prelude::{BitSet, Component, ComponentEvent, DenseVecStorage, Entity, FlaggedStorage, ReaderId, Tracked, World};
+use specs::world::Index;
+use specs::{RunNow, WorldExt};
+use specs::join::Join;
+use specs::world::Builder;
+
+use chrono::{Duration, Utc};
+
+pub mod atomic;
+pub mod export;
+pub mod format;
+pub mod index;
+pub mod kv;
+pub mod maintenance;
+pub mod stats;
+pub use self::format::DbError;
+use self::format::Record;
+use self::index::{CatalogueIndex, ItemKey};
+use self::kv::{Batch, Store, Tree};
+use self::maintenance::{Checkpoint, Clock, Maintenance};
+use self::stats::{ActiveTransfer, ConnectionEvent, ConnectionState, LastSeen, PeerStatsSystem, Traffic, TransferSystem};
+
+use std::collections::{HashMap, HashSet};
+use std::net::{IpAddr, SocketAddr};
+use std::time::Instant;
+use crate::models::{
+    take_u64, AlbumData, Ban, BanList, Catalogue, Collection, CollectionDelta, Event, EventFeed, Invite, KeyAction, KeyCertificate, Latency,
+    Manifest, Peer, PeerId, PeerKey, Provenance, Reputation, TrustStore,
+};
+use crate::args::{CollectionCommand, IdentityCommand, MaintenanceIntervals, TrustCommand};
+use crate::identity::Identity;
+use crate::organizer;
+use crate::ecs::{
+    Node,
+    NodeEvent,
+    NodeSystem,
+    WorldState,
+};
+
+impl Component for Catalogue {
+    type Storage = FlaggedStorage<Self, DenseVecStorage<Self>>;
+}
+
+impl Component for Peer {
+    type Storage = FlaggedStorage<Self, DenseVecStorage<Self>>;
+}
+
+impl Component for Reputation {
+    type Storage = FlaggedStorage<Self, DenseVecStorage<Self>>;
+}
+
+impl Component for Latency {
+    type Storage = DenseVecStorage<Self>;
+}
+
+/// When a peer's `Collection` last changed, unix seconds, 0 if never.
+/// Saved with it so catalogues of offline peers stay browsable.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CollectionUpdated(pub u64);
+
+impl Component for CollectionUpdated {
+    type Storage = FlaggedStorage<Self, DenseVecStorage<Self>>;
+}
+
+/// The owner's version of the `Collection` we hold, 0 if we have none
+/// from it yet. Deltas apply on top of it.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CollectionVersion(pub u64);
+
+impl Component for CollectionVersion {
+    type Storage = DenseVecStorage<Self>;
+}
+
+fn now_secs() -> u64 {
+    Utc::now().timestamp() as u64
+}
+
+/// most a slow round trip can cost a download source, in reputation points
+const MAX_LATENCY_PENALTY: f64 = 20.0;
+
+/// Reputation minus a penalty for latency, unhealthy peers go last.
+fn source_rank(reputation: &Reputation, latency: &Latency) -> f64 {
+    let penalty = latency.srtt.map_or(0.0, |srtt| {
+        ((srtt + 4.0 * latency.jitter) / 50.0).min(MAX_LATENCY_PENALTY)
+    });
+    let rank = reputation.score() - penalty;
+    if latency.healthy {
+        rank
+    } else {
+        rank - 1000.0
+    }
+}
+
+impl Node for Peer {
+    type Key = PeerKey;
+
+    /// the id, so a peer that moves keeps its entity
+    fn key(&self) -> PeerKey {
+        self.id().map_or(PeerKey::Addr(self.addr()), PeerKey::Id)
+    }
+
+    fn secondary_keys(&self) -> Vec<PeerKey> {
+        let mut keys: Vec<PeerKey> = self.other_addresses().iter().copied().map(PeerKey::Addr).collect();
+        if self.id().is_some() {
+            keys.push(PeerKey::Addr(self.addr()));
+        }
+        keys
+    }
+}
+
+pub struct Db {
+    world: World,
+    system: NodeSystem<Peer>,
+    reader_id: ReaderId<NodeEvent>,
+    stats: PeerStatsSystem,
+    transfers: TransferSystem,
+    bans: BanList,
+    /// contacts allowed in friend-to-friend mode, edited from the command line
+    trust: TrustStore,
+    /// latest signed manifest per owner, also kept for offline peers
+    manifests: HashMap<PeerId, (Manifest, Provenance)>,
+    /// accepted rotation and revocation certificates, in arrival order
+    certificates: Vec<KeyCertificate>,
+    revoked: HashSet<PeerId>,
+    /// successor key -> the key it replaced
+    predecessors: HashMap<PeerId, PeerId>,
+    /// where all of the above is kept, `None` until loaded or saved
+    store: Option<Store>,
+    /// peers changed since they were last written to `store`
+    changes: Option<PeerChanges>,
+    /// downloads still running when the node last stopped
+    interrupted: Vec<(SocketAddr, String, String)>,
+    /// periodic jobs, see `run_maintenance`
+    maintenance: Maintenance,
+    events: EventFeed,
+}
+
+/// trees of the key-value store, peers and collections are keyed by `PeerKey`
+const PEERS: &str = "peers";
+const COLLECTIONS: &str = "collections";
+const TRUST: &str = "trust";
+const CERTIFICATES: &str = "certificates";
+const TRANSFERS: &str = "transfers";
+/// keyed by IP, bans and offence counts so repeat offenders stay banned
+const BANS: &str = "bans";
+/// the other addresses of a peer, space separated
+const ADDRESSES: &str = "addresses";
+
+/// longest chain of rotations followed when checking trust
+const MAX_KEY_CHAIN: usize = 16;
+/// how long a cached manifest is kept before asking its owner for a new one
+const MANIFEST_REFRESH_SECS: i64 = 60 * 60;
+
+impl Db {
+    pub fn new() -> Self {
+        let mut world = World::new();
+        world.register::<Peer>();
+        world.register::<Catalogue>();
+        world.register::<Reputation>();
+        world.register::<Latency>();
+        world.register::<CollectionUpdated>();
+        world.register::<CollectionVersion>();
+        let system = NodeSystem::<Peer>::new(&mut world);
+        let reader_id = world.write_resource::<WorldState<Peer>>().track();
+        let stats = PeerStatsSystem::new(&mut world);
+        let transfers = TransferSystem::new(&mut world);
+        // who holds what, follows every `Catalogue` change
+        world.insert(CatalogueIndex::new());
+        let maintenance = Maintenance::new(&mut world, &MaintenanceIntervals::default());
+        Db {
+            world,
+            system,
+            reader_id,
+            stats,
+            transfers,
+            bans: BanList::new(),
+            trust: TrustStore::new(),
+            manifests: HashMap::new(),
+            certificates: Vec::new(),
+            revoked: HashSet::new(),
+            predecessors: HashMap::new(),
+            store: None,
+            changes: None,
+            interrupted: Vec::new(),
+            maintenance,
+            events: EventFeed::new(),
+        }
+    }
+
+    pub fn maintain(&mut self) {
+        self.system.run_now(&mut self.world);
+        // entities replaced by a newer record for the same key
+        let removed: Vec<Entity> = self.world.fetch::<WorldState<Peer>>()
+            .changed()
+            .read(&mut self.reader_id)
+            .filter_map(|event| match event {
+                NodeEvent::Removed(entity) => Some(*entity),
+                _ => None,
+            })
+            .collect();
+        for entity in removed {
+            let _ = self.world.delete_entity(entity);
+        }
+        self.world.maintain();
+    }
+
+    /// Run the maintenance jobs due at `now` and save if a checkpoint was
+    /// among them. Returns the names of the jobs run.
+    pub fn run_maintenance(&mut self, now: Instant) -> io::Result<Vec<&'static str>> {
+        self.world.insert(Clock(now_secs()));
+        let ran = self.maintenance.run_due(&mut self.world, now);
+        if self.world.fetch::<Checkpoint>().due {
+            self.world.fetch_mut::<Checkpoint>().due = false;
+            self.flush()?;
+        }
+        Ok(ran)
+    }
+
+    pub fn set_maintenance_intervals(&mut self, intervals: &MaintenanceIntervals) {
+        let now = Instant::now();
+        self.maintenance.set_interval(maintenance::LIVENESS, intervals.liveness, now);
+        self.maintenance.set_interval(maintenance::REPUTATION_DECAY, intervals.reputation_decay, now);
+        self.maintenance.set_interval(maintenance::INDEX_REBUILD, intervals.index_rebuild, now);
+        self.maintenance.set_interval(maintenance::CHECKPOINT, intervals.checkpoint, now);
+    }
+
+    /// the feed collection changes are published on, see `Event`
+    pub fn events(&self) -> EventFeed {
+        self.events.clone()
+    }
+
+    pub fn all_peers(&self) -> Vec<Peer> {
+        self.world.read_storage::<Peer>().join().map(|x| x.clone()).collect()
+    }
+
+    /// all peers with their reputation score, best first
+    pub fn ranked_peers(&self) -> Vec<(Peer, f64)> {
+        let peers = self.world.read_storage::<Peer>();
+        let reputations = self.world.read_storage::<Reputation>();
+        let mut ranked: Vec<(Peer, f64)> = (&peers, &reputations).join()
+            .map(|(peer, reputation)| (peer.clone(), reputation.score()))
+            .collect();
+        ranked.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+        ranked
+    }
+
+    /// Peers to share in a `PeersResponse`, highest reputation first.
+    /// Only records that still verify, receivers punish the rest, so none
+    /// from invites, imports or old files.
+    pub fn exchange_peers(&self, limit: usize) -> Vec<Peer> {
+        let now = now_secs();
+        self.ranked_peers().into_iter()
+            .filter(|(peer, _)| peer.verify(now).is_ok())
+            .take(limit)
+            .map(|(peer, _)| peer)
+            .collect()
+    }
+
+    /// like `exchange_peers` but only our trusted contacts
+    pub fn exchange_trusted(&self, limit: usize) -> Vec<Peer> {
+        let now = now_secs();
+        self.ranked_peers().into_iter()
+            .filter(|(peer, _)| peer.id().map_or(false, |id| self.trust.is_trusted(&id)))
+            .filter(|(peer, _)| peer.verify(now).is_ok())
+            .take(limit)
+            .map(|(peer, _)| peer)
+            .collect()
+    }
+
+    /// Peers whose collection has the album. Healthy, reputable and
+    /// close peers come first, see `source_rank`.
+    pub fn download_sources(&self, artist: &str, album: &str) -> Vec<Peer> {
+        self.who_has(&ItemKey::album(artist, album))
+    }
+
+    /// Peers holding an artist, album or track, ranked like
+    /// `download_sources`.
+    pub fn who_has(&self, key: &ItemKey) -> Vec<Peer> {
+        let state = self.world.fetch::<WorldState<Peer>>();
+        let peers = self.world.read_storage::<Peer>();
+        let reputations = self.world.read_storage::<Reputation>();
+        let latencies = self.world.read_storage::<Latency>();
+        let mut holders = self.world.fetch::<CatalogueIndex>().holders(key);
+        holders.sort();
+        let mut sources: Vec<(Peer, f64)> = holders.iter()
+            .filter_map(|key| state.get_primary(key))
+            .filter_map(|entity| Some((peers.get(entity)?, reputations.get(entity)?, latencies.get(entity)?)))
+            .map(|(peer, reputation, latency)| (peer.clone(), source_rank(reputation, latency)))
+            .collect();
+        sources.sort_by(|a, b| b.1.partial_cmp(&a.1).unwrap_or(std::cmp::Ordering::Equal));
+        sources.into_iter().map(|(peer, _)| peer).collect()
+    }
+
+    pub fn add_peer(&mut self, p: Peer, c: Collection) {
+        if self.is_outdated(&p) {
+            return;
+        }
+        let reputation = self.previous_component(&p).unwrap_or_default();
+        self.create_peer(p, c, CollectionUpdated(now_secs()), reputation);
+        self.maintain()
+    }
+
+    pub fn add_peers(&mut self, peers: Vec<Peer>) {
+        for peer in peers {
+            if self.is_outdated(&peer) {
+                continue;
+            }
+            let collection = self.previous_component::<Catalogue>(&peer)
+                .map_or(Collection::new(vec![]), |catalogue| catalogue.to_collection());
+            let updated = self.previous_component(&peer).unwrap_or_default();
+            let reputation = self.previous_component(&peer).unwrap_or_default();
+            self.create_peer(peer, collection, updated, reputation);
+        }
+        self.maintain()
+    }
+
+    /// a peer as saved by `save`
+    fn restore_peer(&mut self, p: Peer, c: Collection, updated: CollectionUpdated, r: Reputation) {
+        self.create_peer(p, c, updated, r);
+        self.maintain()
+    }
+
+    /// new entity for the peer, replacing any older one on `maintain`,
+    /// see `previous_entity`
+    fn create_peer(&mut self, p: Peer, c: Collection, updated: CollectionUpdated, r: Reputation) {
+        let previous = self.previous_entity(&p);
+        let old = previous.and_then(|entity| self.component_of::<Peer>(entity));
+        // records from the peer itself only carry the address it signed
+        let p = match &old {
+            Some(old) if p.other_addresses().is_empty() => {
+                let others = old.other_addresses().iter().copied().filter(|addr| *addr != p.addr()).collect();
+                p.with_other_addresses(others)
+            },
+            _ => p,
+        };
+        let latency = self.previous_component::<Latency>(&p).unwrap_or_default();
+        let version = self.previous_component::<CollectionVersion>(&p).unwrap_or_default();
+        let state = self.previous_component::<ConnectionState>(&p).unwrap_or_default();
+        let seen = self.previous_component::<LastSeen>(&p).unwrap_or_default();
+        let traffic = self.previous_component::<Traffic>(&p).unwrap_or_default();
+        if let (Some(entity), Some(old)) = (previous, old) {
+            // an unsigned record under another key, dropping the node lets
+            // `maintain` delete the whole entity
+            if old.key() != p.key() {
+                self.world.write_storage::<Peer>().remove(entity);
+                self.world.fetch_mut::<CatalogueIndex>().remove_peer(&old.key());
+            }
+        }
+        self.world.fetch_mut::<CatalogueIndex>().set(p.key(), &c);
+        self.world.create_entity()
+            .with(p)
+            .with(Catalogue::from(c))
+            .with(updated)
+            .with(r)
+            .with(latency)
+            .with(version)
+            .with(state)
+            .with(seen)
+            .with(traffic)
+            .build();
+    }
+
+    /// The entity a new record of `p` takes over: the one under the same
+    /// key, or an unsigned record at its address. Another identity at the
+    /// same address is a peer of its own.
+    fn previous_entity(&self, p: &Peer) -> Option<Entity> {
+        let state = self.world.fetch::<WorldState<Peer>>();
+        state.get_primary(&p.key())
+            .or_else(|| state.get_primary(&PeerKey::Addr(p.addr())))
+    }
+
+    fn previous_component<C: Component + Clone>(&self, p: &Peer) -> Option<C> {
+        self.component_of(self.previous_entity(p)?)
+    }
+
+    /// a revoked key, or we already hold a newer record for the same
+    /// identity, e.g. a replay
+    fn is_outdated(&self, p: &Peer) -> bool {
+        let id = match p.id() {
+            Some(id) => id,
+            None => return false,
+        };
+        self.revoked.contains(&id) || self.peer_by_id(&id)
+            .map_or(false, |known| known.timestamp() > p.timestamp())
+    }
+
+    pub fn is_revoked(&self, id: &PeerId) -> bool {
+        self.revoked.contains(id)
+    }
+
+    /// Apply a verified rotation or revocation, returns false if it told
+    /// us nothing new so it need not be passed on.
+    pub fn apply_certificate(&mut self, certificate: KeyCertificate) -> bool {
+        let subject = match certificate.subject() {
+            Some(subject) => subject,
+            None => return false,
+        };
+        if self.revoked.contains(&subject) {
+            return false;
+        }
+        let rotated_to = self.predecessors.iter()
+            .find(|(_, old)| **old == subject)
+            .map(|(new, _)| *new);
+        match certificate.action {
+            // the first rotation wins, a thief has to revoke instead
+            KeyAction::Rotation if rotated_to.is_some() => return false,
+            KeyAction::Rotation => {},
+            KeyAction::Revocation => {
+                self.revoked.insert(subject);
+                self.manifests.remove(&subject);
+                // a rotation the revocation does not vouch for came from the thief
+                if let Some(rotated_to) = rotated_to {
+                    if certificate.successor() != Some(rotated_to) {
+                        self.predecessors.remove(&rotated_to);
+                        self.revoked.insert(rotated_to);
+                    }
+                }
+            },
+        }
+        // a revocation may be the thief's, so its successor earns no trust,
+        // contacts have to trust the new key by hand
+        if let (KeyAction::Rotation, Some(successor)) = (certificate.action, certificate.successor()) {
+            if !self.revoked.contains(&successor) {
+                self.predecessors.insert(successor, subject);
+            }
+        }
+        self.certificates.push(certificate);
+        true
+    }
+
+    pub fn certificates(&self) -> Vec<KeyCertificate> {
+        self.certificates.clone()
+    }
+
+    /// The key an identity's record is under, or while a rotated to key
+    /// has not announced itself, that of the key it replaced. `None` for
+    /// keys a certificate retired.
+    fn key_of(&self, id: &PeerId) -> Option<PeerKey> {
+        if self.revoked.contains(id) || self.predecessors.values().any(|old| old == id) {
+            return None;
+        }
+        let state = self.world.fetch::<WorldState<Peer>>();
+        let key = PeerKey::Id(*id);
+        if state.get_primary(&key).is_some() {
+            return Some(key);
+        }
+        let previous = PeerKey::Id(*self.predecessors.get(id)?);
+        state.get_primary(&previous).map(|_| previous)
+    }
+
+    /// current address of an identity, see `key_of`
+    pub fn addr_of(&self, id: &PeerId) -> Option<SocketAddr> {
+        self.get_component::<Peer, _>(&self.key_of(id)?).map(|peer| peer.addr())
+    }
+
+    pub fn peer_by_id(&self, id: &PeerId) -> Option<Peer> {
+        self.get_component::<Peer, _>(&self.key_of(id)?)
+            .filter(|peer| peer.id().as_ref() == Some(id))
+    }
+
+    fn component_of<C: Component + Clone>(&self, entity: Entity) -> Option<C> {
+        self.world.read_storage::<C>().get(entity).cloned()
+    }
+
+    /// the component of the peer under `key`, an id or any of its addresses
+    fn get_component<C: Component + Clone, K: Into<PeerKey> + Copy>(&self, key: &K) -> Option<C> {
+        let entity = self.world.fetch::<WorldState<Peer>>().get_entity(&(*key).into())?;
+        self.component_of(entity)
+    }
+
+    fn update_component<C: Component, K: Into<PeerKey> + Copy, F: FnOnce(&mut C)>(&mut self, key: &K, f: F) {
+        let entity = match self.world.fetch::<WorldState<Peer>>().get_entity(&(*key).into()) {
+            Some(entity) => entity,
+            None => return,
+        };
+        if let Some(component) = self.world.write_storage::<C>().get_mut(entity) {
+            f(component);
+        }
+    }
+
+    pub fn get_reputation(&self, addr: &SocketAddr) -> Reputation {
+        self.get_component(addr).unwrap_or_default()
+    }
+
+    pub fn get_latency(&self, addr: &SocketAddr) -> Latency {
+        self.get_component(addr).unwrap_or_default()
+    }
+
+    /// Queue `event` and bring the components it touches up to date.
+    pub fn connection_event(&mut self, event: ConnectionEvent) {
+        self.world.write_resource::<EventChannel<ConnectionEvent>>().single_write(event);
+        self.stats.run_now(&self.world);
+        self.transfers.run_now(&self.world);
+        self.world.maintain();
+    }
+
+    pub fn record_seen(&mut self, addr: &SocketAddr) {
+        self.update_component(addr, |r: &mut Reputation| r.seen(Utc::now()));
+        self.connection_event(ConnectionEvent::Seen((*addr).into()));
+    }
+
+    /// our last connection to the peer closed
+    pub fn record_disconnected(&mut self, addr: &SocketAddr) {
+        self.connection_event(ConnectionEvent::Disconnected((*addr).into()));
+    }
+
+    pub fn record_traffic(&mut self, addr: &SocketAddr, sent: u64, received: u64) {
+        self.connection_event(ConnectionEvent::Traffic { peer: (*addr).into(), sent, received });
+    }
+
+    pub fn record_answer(&mut self, addr: &SocketAddr) {
+        self.update_component(addr, |r: &mut Reputation| r.answered());
+    }
+
+    pub fn record_transfer(&mut self, addr: &SocketAddr, bytes: u64, millis: u64) {
+        self.update_component(addr, |r: &mut Reputation| r.transferred(bytes, millis));
+    }
+
+    pub fn record_bad_chunk(&mut self, addr: &SocketAddr) {
+        self.update_component(addr, |r: &mut Reputation| r.bad_chunk());
+    }
+
+    pub fn record_rtt(&mut self, addr: &SocketAddr, millis: f64) {
+        self.connection_event(ConnectionEvent::Rtt((*addr).into(), millis));
+    }
+
+    pub fn record_timeout(&mut self, addr: &SocketAddr) {
+        self.connection_event(ConnectionEvent::PingTimeout((*addr).into()));
+    }
+
+    pub fn connection_state(&self, addr: &SocketAddr) -> ConnectionState {
+        self.get_component(addr).unwrap_or_default()
+    }
+
+    pub fn get_traffic(&self, addr: &SocketAddr) -> Traffic {
+        self.get_component(addr).unwrap_or_default()
+    }
+
+    /// unix seconds, 0 if not seen this run
+    pub fn last_seen(&self, addr: &SocketAddr) -> u64 {
+        self.get_component::<LastSeen, _>(addr).unwrap_or_default().0
+    }
+
+    /// Online peers holding `key`, lowest round trip first. Peers we have
+    /// not timed yet come last.
+    pub fn fastest_online(&self, key: &ItemKey) -> Vec<Peer> {
+        let holders: HashSet<PeerKey> = self.world.fetch::<CatalogueIndex>().holders(key).into_iter().collect();
+        let peers = self.world.read_storage::<Peer>();
+        let states = self.world.read_storage::<ConnectionState>();
+        let latencies = self.world.read_storage::<Latency>();
+        let mut online: Vec<(Peer, f64)> = (&peers, &states, &latencies).join()
+            .filter(|(peer, state, _)| state.is_online() && holders.contains(&peer.key()))
+            .map(|(peer, _, latency)| (peer.clone(), latency.srtt.unwrap_or(std::f64::INFINITY)))
+            .collect();
+        online.sort_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal));
+        online.into_iter().map(|(peer, _)| peer).collect()
+    }
+
+    pub fn add_tracks(&mut self, addr: &SocketAddr, album_data: AlbumData) {
+        if self.find(addr).is_none() {
+            return;
+        }
+        if !self.insert_album(addr, album_data.clone()) {
+            println!("album {} from {} has no artist", album_data.album_title, addr);
+            return;
+        }
+        self.events.publish(Event::TracksAdded(*addr, album_data));
+    }
+
+    /// Put the album in the peer's catalogue, replacing one of the same
+    /// title. False if it names no artist.
+    fn insert_album(&mut self, addr: &SocketAddr, album_data: AlbumData) -> bool {
+        let artist = match &album_data.artist {
+            Some(artist) => artist.clone(),
+            None => return false,
+        };
+        let (entity, key, _) = match self.find(addr) {
+            Some(found) => found,
+            None => return false,
+        };
+        let mut catalogues = self.world.write_storage::<Catalogue>();
+        let catalogue = match catalogues.get_mut(entity) {
+            Some(catalogue) => catalogue,
+            None => return false,
+        };
+        let old = catalogue.upsert_album(&artist, album_data.clone());
+        self.world.fetch_mut::<CatalogueIndex>().set_album(key, &artist, old.as_ref(), &album_data);
+        self.events.publish(Event::CollectionUpdated(*addr));
+        self.world.write_storage::<CollectionUpdated>()
+            .insert(entity, CollectionUpdated(now_secs()))
+            .unwrap();
+        true
+    }
+
+    /// Store a verified manifest received from `from`, if it is newer than
+    /// the one we hold, and update the owner's collection when we know it.
+    pub fn update_manifest(&mut self, manifest: Manifest, from: SocketAddr) -> bool {
+        let owner = match manifest.owner() {
+            Some(owner) if !self.revoked.contains(&owner) => owner,
+            _ => return false,
+        };
+        if let Some((known, _)) = self.manifests.get(&owner) {
+            if known.version >= manifest.version {
+                return false;
+            }
+        }
+        if let Some(key) = self.key_of(&owner) {
+            self.set_collection(&key, Collection::new(manifest.artists.clone()), manifest.timestamp);
+            self.set_collection_version(&key, manifest.version);
+        }
+        let provenance = Provenance { from, received: Utc::now() };
+        self.manifests.insert(owner, (manifest, provenance));
+        true
+    }
+
+    pub fn get_manifest(&self, owner: &PeerId) -> Option<Manifest> {
+        self.manifests.get(owner).map(|(manifest, _)| manifest.clone())
+    }
+
+    /// whether to ask `owner` for its manifest, we have none or it is old
+    pub fn wants_manifest(&self, owner: &PeerId) -> bool {
+        self.manifests.get(owner).map_or(true, |(_, provenance)| {
+            Utc::now() - provenance.received > Duration::seconds(MANIFEST_REFRESH_SECS)
+        })
+    }
+
+    pub fn get_provenance(&self, owner: &PeerId) -> Option<Provenance> {
+        self.manifests.get(owner).map(|(_, provenance)| provenance.clone())
+    }
+
+    pub fn update_collection(&mut self, addr: &SocketAddr, c: Collection) {
+        self.set_collection(addr, c, now_secs());
+    }
+
+    /// Apply changes the peer sent. Returns false when they do not start
+    /// from the version we hold, the peer should be asked for a snapshot.
+    pub fn update_collection_delta(&mut self, addr: &SocketAddr, delta: &CollectionDelta) -> bool {
+        if !delta.snapshot && delta.since != self.collection_version(addr) {
+            return false;
+        }
+        if delta.snapshot {
+            self.update_collection(addr, Collection::new(delta.added.clone()));
+        } else if !delta.is_empty() {
+            self.apply_delta(addr, delta);
+        }
+        self.set_collection_version(addr, delta.version);
+        true
+    }
+
+    /// Change the peer's catalogue in place and re-index only the artists
+    /// the delta names.
+    fn apply_delta(&mut self, addr: &SocketAddr, delta: &CollectionDelta) {
+        let (entity, key, _) = match self.find(addr) {
+            Some(found) => found,
+            None => return,
+        };
+        let mut catalogues = self.world.write_storage::<Catalogue>();
+        let catalogue = match catalogues.get_mut(entity) {
+            Some(catalogue) => catalogue,
+            None => return,
+        };
+        let names = delta.artists();
+        let before: Vec<_> = names.iter().map(|name| catalogue.artist(name)).collect();
+        delta.apply_to(catalogue);
+        let mut index = self.world.fetch_mut::<CatalogueIndex>();
+        for (name, before) in names.iter().zip(before) {
+            index.set_artist(key, before.as_ref(), catalogue.artist(name).as_ref());
+        }
+        self.events.publish(Event::CollectionUpdated(*addr));
+        self.world.write_storage::<CollectionUpdated>()
+            .insert(entity, CollectionUpdated(now_secs()))
+            .unwrap();
+    }
+
+    pub fn collection_version(&self, addr: &SocketAddr) -> u64 {
+        self.get_component::<CollectionVersion, _>(addr).unwrap_or_default().0
+    }
+
+    fn set_collection_version<K: Into<PeerKey> + Copy>(&mut self, key: &K, version: u64) {
+        self.update_component(key, |v: &mut CollectionVersion| v.0 = version);
+    }
+
+    fn set_collection<K: Into<PeerKey> + Copy>(&mut self, key: &K, c: Collection, updated: u64) {
+        // unknown peers have to introduce themselves with a Ping first
+        let (entity, key, addr) = match self.find(key) {
+            Some(found) => found,
+            None => return,
+        };
+        self.world.fetch_mut::<CatalogueIndex>().set(key, &c);
+        self.events.publish(Event::CollectionUpdated(addr));
+        self.world.write_storage::<Catalogue>()
+            .insert(entity, Catalogue::from(c))
+            .unwrap();
+        self.world.write_storage::<CollectionUpdated>()
+            .insert(entity, CollectionUpdated(updated))
+            .unwrap();
+    }
+
+    /// Keep `c` as what the peer at `addr` holds, e.g. a snapshot read
+    /// from a file. Peers we do not know yet are added for it.
+    pub fn import_collection(&mut self, addr: SocketAddr, c: Collection) {
+        if self.find(&addr).is_none() {
+            self.add_peer(Peer::new(addr, false, None, None, None), c);
+        } else {
+            self.update_collection(&addr, c);
+        }
+    }
+
+    /// when the peer's collection last changed, unix seconds, 0 if never
+    pub fn collection_updated(&self, addr: &SocketAddr) -> u64 {
+        self.get_component::<CollectionUpdated, _>(addr).unwrap_or_default().0
+    }
+
+    /// the entity under `key`, with the primary key it is indexed by and
+    /// its address
+    fn find<K: Into<PeerKey> + Copy>(&self, key: &K) -> Option<(Entity, PeerKey, SocketAddr)> {
+        let entity = self.world.fetch::<WorldState<Peer>>().get_entity(&(*key).into())?;
+        let peer = self.world.read_storage::<Peer>().get(entity)?.clone();
+        Some((entity, peer.key(), peer.addr()))
+    }
+
+    /// every known catalogue, online or not, most recently updated first
+    pub fn collections(&self) -> Vec<(Peer, Collection, u64)> {
+        let peers = self.world.read_storage::<Peer>();
+        let catalogues = self.world.read_storage::<Catalogue>();
+        let updated = self.world.read_storage::<CollectionUpdated>();
+        let mut all: Vec<(Peer, Collection, u64)> = (&peers, &catalogues, &updated).join()
+            .filter(|(_, catalogue, _)| !catalogue.is_empty())
+            .map(|(peer, catalogue, updated)| (peer.clone(), catalogue.to_collection(), updated.0))
+            .collect();
+        all.sort_by(|a, b| b.2.cmp(&a.2));
+        all
+    }
+
+    pub fn get_collection(&mut self, addr: &SocketAddr) -> Collection {
+        self.get_component::<Catalogue, _>(addr)
+            .map_or(Collection::new(vec![]), |catalogue| catalogue.to_collection())
+    }
+
+    /// Trusted directly or through the keys it rotated from.
+    pub fn is_trusted(&self, id: &PeerId) -> bool {
+        if self.revoked.contains(id) {
+            return false;
+        }
+        let mut id = *id;
+        for _ in 0..MAX_KEY_CHAIN {
+            if self.trust.is_trusted(&id) {
+                return true;
+            }
+            match self.predecessors.get(&id) {
+                Some(previous) => id = *previous,
+                None => return false,
+            }
+        }
+        false
+    }
+
+    pub fn set_trust(&mut self, trust: TrustStore) {
+        self.trust = trust;
+    }
+
+    /// Trust the inviting peer under `petname` and remember its addresses.
+    /// Saved straight away.
+    pub fn redeem_invite(&mut self, invite: &Invite, petname: &str) -> io::Result<()> {
+        self.trust.add(&invite.public_key_hex(), petname);
+        self.add_peers(invite.peer().into_iter().collect());
+        self.flush()
+    }
+
+    pub fn is_banned(&self, ip: &IpAddr) -> bool {
+        self.bans.is_banned(ip, Utc::now())
+    }
+
+    /// Record misbehaviour from `ip`, returns true if it is now banned.
+    pub fn penalize(&mut self, ip: IpAddr, points: u32, reason: &str) -> bool {
+        self.bans.penalize(ip, points, reason, Utc::now())
+    }
+
+    pub fn ban(&mut self, ip: IpAddr, minutes: Option<i64>, reason: &str) {
+        let now = Utc::now();
+        let until = minutes.map(|m| now + chrono::Duration::minutes(m));
+        self.bans.ban(ip, until, reason, now);
+    }
+
+    pub fn unban(&mut self, ip: &IpAddr) -> Option<Ban> {
+        self.bans.unban(ip)
+    }
+
+    pub fn bans(&mut self) -> Vec<(IpAddr, Ban)> {
+        self.bans.expire(Utc::now());
+        self.bans.all()
+    }
+
+    /// Load the database at `filename`. Files from before the key-value
+    /// store are migrated, along with the trust store and certificates
+    /// that used to sit next to them.
+    pub fn new_from_file(filename: &str) -> Result<Self, DbError> {
+        let legacy = atomic::load_with_backup(filename, |bytes| match bytes.starts_with(kv::MAGIC) {
+            true => Ok(None),
+            false => format::decode(bytes).map(Some),
+        })?;
+        let records = match legacy {
+            Some(Some(records)) => records,
+            // first run, or already a store
+            _ => return Db::from_store(Store::open(filename)?),
+        };
+        let mut db = Db::new();
+        db.set_trust(TrustStore::load(&TrustStore::path_for(filename))?);
+        for certificate in load_certificates(&keys_path(filename))? {
+            db.apply_certificate(certificate);
+        }
+        for record in records {
+            db.restore_peer(record.peer, record.collection, CollectionUpdated(record.updated), record.reputation);
+        }
+        db.save(filename)?;
+        println!("migrated {} to the key-value store", filename);
+        Ok(db)
+    }
+
+    fn from_store(mut store: Store) -> Result<Self, DbError> {
+        let corrupt = |tree: &str| DbError::Corrupt(format!("bad entry in the {} tree of {}", tree, store.path()));
+        let mut db = Db::new();
+        for (_, value) in store.iter(TRUST) {
+            let line = String::from_utf8_lossy(value);
+            let mut parts = line.splitn(2, ' ');
+            let public_key = parts.next().unwrap_or("");
+            db.trust.add(public_key, parts.next().unwrap_or("")).ok_or_else(|| corrupt(TRUST))?;
+        }
+        for (_, value) in store.iter(CERTIFICATES) {
+            let certificate = KeyCertificate::from_bytes(&mut BytesMut::from(value))
+                .map_err(|_| corrupt(CERTIFICATES))?;
+            db.apply_certificate(certificate);
+        }
+        for (key, value) in store.iter(PEERS) {
+            let mut record = format::decode_peer_entry(&mut BytesMut::from(value))
+                .map_err(|_| corrupt(PEERS))?;
+            if let Some(collection) = store.get(COLLECTIONS, key) {
+                record.collection = Collection::from_bytes(&mut BytesMut::from(collection))
+                    .map_err(|_| corrupt(COLLECTIONS))?;
+            }
+            let mut peer = record.peer;
+            if let Some(addresses) = store.get(ADDRESSES, key) {
+                let addresses = String::from_utf8_lossy(addresses).split_whitespace()
+                    .map(|addr| addr.parse())
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| corrupt(ADDRESSES))?;
+                peer = peer.with_other_addresses(addresses);
+            }
+            db.restore_peer(peer, record.collection, CollectionUpdated(record.updated), record.reputation);
+        }
+        for (key, value) in store.iter(BANS) {
+            let ip = String::from_utf8_lossy(key).parse().map_err(|_| corrupt(BANS))?;
+            let (ban, offences) = format::decode_ban(value).ok_or_else(|| corrupt(BANS))?;
+            db.bans.restore(ip, ban, offences);
+        }
+        for (_, value) in store.iter(TRANSFERS) {
+            db.interrupted.push(format::decode_transfer(value).ok_or_else(|| corrupt(TRANSFERS))?);
+        }
+        // transfer ids start over with every run
+        let mut batch = Batch::new();
+        batch.sync_tree(&store, TRANSFERS, Tree::new());
+        store.apply(batch)?;
+        db.store = Some(store);
+        db.changes = Some(PeerChanges::new(&mut db.world));
+        Ok(db)
+    }
+
+    /// Write what changed since the last save, only changed peers are
+    /// encoded again. Saving under another name starts a fresh store there.
+    pub fn save(&mut self, filename: &str) -> io::Result<()> {
+        let others = self.other_trees();
+        if let (Some(store), Some(changes)) = (&mut self.store, &mut self.changes) {
+            if store.path() == filename {
+                let mut batch = Batch::new();
+                let keys = changes.changes(&self.world, store, &mut batch);
+                for (name, entries) in others {
+                    batch.sync_tree(store, name, entries);
+                }
+                store.apply(batch)?;
+                changes.saved(keys);
+                return Ok(());
+            }
+        }
+        let mut batch = Batch::new();
+        for (name, entries) in self.peer_trees().into_iter().chain(others) {
+            for (key, value) in entries {
+                batch.insert(name, &key, &value);
+            }
+        }
+        self.store = Some(Store::create(filename, &batch)?);
+        self.changes = Some(PeerChanges::new(&mut self.world));
+        Ok(())
+    }
+
+    /// save to wherever the database was loaded from, if anywhere
+    pub fn flush(&mut self) -> io::Result<()> {
+        match self.store.as_ref().map(|store| store.path().to_string()) {
+            Some(path) => self.save(&path),
+            None => Ok(()),
+        }
+    }
+
+    /// the peer, collection and address trees in full
+    fn peer_trees(&self) -> Vec<(&'static str, Tree)> {
+        let peers = self.world.read_storage::<Peer>();
+        let reputations = self.world.read_storage::<Reputation>();
+        let catalogues = self.world.read_storage::<Catalogue>();
+        let updated = self.world.read_storage::<CollectionUpdated>();
+        let mut trees: Vec<(&'static str, Tree)> = PEER_TREES.iter().map(|name| (*name, Tree::new())).collect();
+        for (peer, reputation, catalogue, updated) in (&peers, &reputations, &catalogues, &updated).join() {
+            let (key, entries) = peer_entries(peer, reputation, catalogue, updated);
+            for ((_, tree), value) in trees.iter_mut().zip(entries.iter()) {
+                if let Some(value) = value {
+                    tree.insert(key.clone(), value.clone());
+                }
+            }
+        }
+        trees
+    }
+
+    /// everything else `save` keeps, small enough to compare whole
+    fn other_trees(&self) -> Vec<(&'static str, Tree)> {
+        let trust = self.trust.list().into_iter()
+            .map(|(id, contact)| (id.as_bytes().to_vec(), format!("{} {}", contact.public_key, contact.petname).into_bytes()))
+            .collect();
+        let certificates = self.certificates.iter().enumerate()
+            .map(|(i, certificate)| ((i as u64).to_be_bytes().to_vec(), certificate.to_bytes().to_vec()))
+            .collect();
+        let bans = self.bans.records().into_iter()
+            .map(|(ip, ban, offences)| (ip.to_string().into_bytes(), format::encode_ban(ban.as_ref(), offences)))
+            .collect();
+        vec![
+            (TRUST, trust),
+            (CERTIFICATES, certificates),
+            (BANS, bans),
+        ]
+    }
+
+    /// Note a download so it is reported if the node stops before it ends.
+    pub fn transfer_started(&mut self, id: u64, peer: SocketAddr, artist: &str, album: &str) -> io::Result<()> {
+        self.connection_event(ConnectionEvent::TransferStarted {
+            id,
+            peer,
+            artist: artist.to_string(),
+            album: album.to_string(),
+        });
+        match &mut self.store {
+            Some(store) => {
+                let mut batch = Batch::new();
+                batch.insert(TRANSFERS, &id.to_be_bytes(), &format::encode_transfer(&peer, artist, album));
+                store.apply(batch)
+            },
+            None => Ok(()),
+        }
+    }
+
+    pub fn transfer_progress(&mut self, id: u64, bytes: u64) {
+        self.connection_event(ConnectionEvent::TransferProgress { id, bytes });
+    }
+
+    pub fn transfer_finished(&mut self, id: u64) -> io::Result<()> {
+        self.connection_event(ConnectionEvent::TransferFinished(id));
+        match &mut self.store {
+            Some(store) => {
+                let mut batch = Batch::new();
+                batch.remove(TRANSFERS, &id.to_be_bytes());
+                store.apply(batch)
+            },
+            None => Ok(()),
+        }
+    }
+
+    /// downloads in flight, oldest first
+    pub fn active_transfers(&self) -> Vec<ActiveTransfer> {
+        let mut transfers: Vec<ActiveTransfer> = self.world.read_storage::<ActiveTransfer>().join().cloned().collect();
+        transfers.sort_by_key(|t| t.id);
+        transfers
+    }
+
+    /// peer, artist and album of downloads cut short last time
+    pub fn interrupted_transfers(&self) -> Vec<(SocketAddr, String, String)> {
+        self.interrupted.clone()
+    }
+}
+
+/// the trees holding a peer's entries, in the order `peer_entries` gives them
+const PEER_TREES: [&str; 3] = [PEERS, COLLECTIONS, ADDRESSES];
+
+/// A peer's entries in `PEER_TREES` under its key, `None` for a collection
+/// or other addresses it does not have.
+fn peer_entries(peer: &Peer, reputation: &Reputation, catalogue: &Catalogue, updated: &CollectionUpdated) -> (Vec<u8>, [Option<Vec<u8>>; 3]) {
+    let record = Record {
+        peer: peer.clone(),
+        reputation: reputation.clone(),
+        collection: Collection::new(vec![]),
+        updated: updated.0,
+    };
+    let collection = match catalogue.is_empty() {
+        true => None,
+        false => Some(catalogue.to_collection().to_bytes().to_vec()),
+    };
+    let addresses = match peer.other_addresses() {
+        [] => None,
+        others => {
+            let addresses: Vec<String> = others.iter().map(|addr| addr.to_string()).collect();
+            Some(addresses.join(" ").into_bytes())
+        },
+    };
+    let key = peer.key().to_string().into_bytes();
+    (key, [Some(format::encode_peer_entry(&record).to_vec()), collection, addresses])
+}
+
+/// Follows the components `peer_entries` reads, so a save only encodes
+/// the peers that changed since the last one.
+struct PeerChanges {
+    peers: ReaderId<ComponentEvent>,
+    reputations: ReaderId<ComponentEvent>,
+    catalogues: ReaderId<ComponentEvent>,
+    updated: ReaderId<ComponentEvent>,
+    changed: BitSet,
+    /// the key each entity was last saved under
+    saved: HashMap<Index, Vec<u8>>,
+}
+
+impl PeerChanges {
+    /// start following `world`, whose peers are all saved
+    fn new(world: &mut World) -> Self {
+        let saved = (
+            &world.entities(),
+            &world.read_storage::<Peer>(),
+            &world.read_storage::<Reputation>(),
+            &world.read_storage::<Catalogue>(),
+            &world.read_storage::<CollectionUpdated>(),
+        ).join()
+            .map(|(entity, peer, ..)| (entity.id(), peer.key().to_string().into_bytes()))
+            .collect();
+        PeerChanges {
+            peers: world.write_storage::<Peer>().register_reader(),
+            reputations: world.write_storage::<Reputation>().register_reader(),
+            catalogues: world.write_storage::<Catalogue>().register_reader(),
+            updated: world.write_storage::<CollectionUpdated>().register_reader(),
+            changed: BitSet::new(),
+            saved,
+        }
+    }
+
+    fn read<C: Component>(world: &World, reader: &mut ReaderId<ComponentEvent>, changed: &mut BitSet)
+    where
+        C::Storage: Tracked,
+    {
+        for event in world.read_storage::<C>().channel().read(reader) {
+            match event {
+                ComponentEvent::Inserted(id) | ComponentEvent::Modified(id) | ComponentEvent::Removed(id) => {
+                    changed.add(*id);
+                },
+            }
+        }
+    }
+
+    /// Add the entries of changed peers that differ from `store` to
+    /// `batch`, and remove those of peers that are gone or now saved under
+    /// another key. Returns what to pass `saved` once `batch` is written.
+    fn changes(&mut self, world: &World, store: &Store, batch: &mut Batch) -> HashMap<Index, Option<Vec<u8>>> {
+        PeerChanges::read::<Peer>(world, &mut self.peers, &mut self.changed);
+        PeerChanges::read::<Reputation>(world, &mut self.reputations, &mut self.changed);
+        PeerChanges::read::<Catalogue>(world, &mut self.catalogues, &mut self.changed);
+        PeerChanges::read::<CollectionUpdated>(world, &mut self.updated, &mut self.changed);
+
+        let mut keys = HashMap::new();
+        let (peers, reputations, catalogues, updated) = (
+            world.read_storage::<Peer>(),
+            world.read_storage::<Reputation>(),
+            world.read_storage::<Catalogue>(),
+            world.read_storage::<CollectionUpdated>(),
+        );
+        for (entity, peer, reputation, catalogue, updated, _) in (&world.entities(), &peers, &reputations, &catalogues, &updated, &self.changed).join() {
+            let (key, entries) = peer_entries(peer, reputation, catalogue, updated);
+            for (tree, value) in PEER_TREES.iter().zip(entries.iter()) {
+                match value {
+                    Some(value) if store.get(tree, &key) != Some(&value[..]) => batch.insert(tree, &key, value),
+                    None if store.get(tree, &key).is_some() => batch.remove(tree, &key),
+                    _ => {},
+                }
+            }
+            keys.insert(entity.id(), Some(key));
+        }
+
+        let live: HashSet<&Vec<u8>> = keys.values().flatten().collect();
+        let gone: Vec<Vec<u8>> = (&self.changed).iter()
+            .filter_map(|id| self.saved.get(&id))
+            .filter(|key| !live.contains(key))
+            .cloned()
+            .collect();
+        if !gone.is_empty() {
+            // another entity may have taken the key over unchanged
+            let kept: HashSet<&Vec<u8>> = self.saved.iter()
+                .filter(|(id, _)| !self.changed.contains(**id))
+                .map(|(_, key)| key)
+                .collect();
+            for key in gone.iter().filter(|key| !kept.contains(key)) {
+                for tree in PEER_TREES.iter() {
+                    if store.get(tree, key).is_some() {
+                        batch.remove(tree, key);
+                    }
+                }
+            }
+        }
+        for id in (&self.changed).iter() {
+            keys.entry(id).or_insert(None);
+        }
+        keys
+    }
+
+    /// the batch from `changes` is written
+    fn saved(&mut self, keys: HashMap<Index, Option<Vec<u8>>>) {
+        for (id, key) in keys {
+            match key {
+                Some(key) => self.saved.insert(id, key),
+                None => self.saved.remove(&id),
+            };
+        }
+        self.changed.clear();
+    }
+}
+
+/// where key certificates were kept before the key-value store
+fn keys_path(db_path: &str) -> String {
+    format!("{}.keys", db_path)
+}
+
+fn load_certificates(filename: &str) -> Result<Vec<KeyCertificate>, DbError> {
+    let mut buffer = Vec::new();
+    match File::open(filename) {
+        Ok(mut f) => f.read_to_end(&mut buffer)?,
+        Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+        Err(e) => return Err(e.into()),
+    };
+    let mut bytes = BytesMut::from(&buffer[..]);
+    let mut certificates = vec![];
+    while bytes.len() > 0 {
+        let length = take_u64(&mut bytes).map_err(|_| DbError::Corrupt(format!("{} is truncated", filename)))? as usize;
+        if bytes.len() < length {
+            return Err(DbError::Corrupt(format!("{} is truncated", filename)));
+        }
+        let mut certificate_bytes = bytes.split_to(length);
+        let certificate = KeyCertificate::from_bytes(&mut certificate_bytes)
+            .map_err(|e| DbError::Corrupt(format!("certificate in {}: {:?}", filename, e)))?;
+        certificates.push(certificate);
+    }
+    Ok(certificates)
+}
+
+/// Retire our key for a fresh one, see `KeyCertificate`. The certificate is
+/// stored with the database so the node spreads it on its next start.
+/// Commands edit the database directly, run them while the node is stopped.
+pub fn identity_command(db_path: &str, identity_path: &str, command: &IdentityCommand) -> Result<(), DbError> {
+    let mut db = Db::new_from_file(db_path)?;
+    let old = Identity::load_or_create(identity_path)?;
+    let new_path = format!("{}.new", identity_path);
+    let new = Identity::create(&new_path)?;
+    let action = match command {
+        IdentityCommand::Rotate => KeyAction::Rotation,
+        IdentityCommand::Revoke => KeyAction::Revocation,
+    };
+    db.apply_certificate(KeyCertificate::new(action, &old, Some(&new)));
+    db.save(db_path)?;
+    fs::rename(&new_path, identity_path)?;
+    println!("{:?} {} -> {}", action, old.peer_id(), new.peer_id());
+    if action == KeyAction::Revocation {
+        println!("contacts have to trust the new key by hand: {}", new.public_key_hex());
+    }
+    Ok(())
+}
+
+/// Apply a `trust` subcommand to the database at `db_path`.
+pub fn trust_command(db_path: &str, command: &TrustCommand) -> Result<(), DbError> {
+    let mut db = Db::new_from_file(db_path)?;
+    match command {
+        TrustCommand::Add { public_key, petname } => match db.trust.add(public_key, petname) {
+            Some(id) => println!("trusted {} as {}", id, petname),
+            None => return Err(io::Error::new(io::ErrorKind::InvalidInput, "public key must be hex").into()),
+        },
+        TrustCommand::Remove(name) => match db.trust.remove(name) {
+            Some(contact) => println!("removed {}", contact.petname),
+            None => println!("no contact named {}", name),
+        },
+        TrustCommand::List => {
+            for (id, contact) in db.trust.list() {
+                println!("{}  {}  {}", id, contact.petname, contact.public_key);
+            }
+            return Ok(());
+        },
+    }
+    Ok(db.save(db_path)?)
+}
+
+/// Export our library from `music_dir` or a stored peer collection, or
+/// import one for a peer, see `export`.
+pub fn collection_command(db_path: &str, music_dir: &str, command: &CollectionCommand) -> Result<(), DbError> {
+    let parse_addr = |peer: &str| peer.parse::<SocketAddr>()
+        .map_err(|_| io::Error::new(io::ErrorKind::InvalidInput, format!("{} is not IP:PORT", peer)));
+    match command {
+        CollectionCommand::Export { file, peer: None } => {
+            let collection = Collection::new(organizer::get_collection(music_dir, true, None, None));
+            export::export(file, &collection)?;
+            println!("exported {} artists to {}", collection.artists.len(), file);
+        },
+        CollectionCommand::Export { file, peer: Some(peer) } => {
+            let addr = parse_addr(peer)?;
+            let collection = Db::new_from_file(db_path)?.get_collection(&addr);
+            export::export(file, &collection)?;
+            println!("exported {} artists of {} to {}", collection.artists.len(), addr, file);
+        },
+        CollectionCommand::Import { file, peer } => {
+            let addr = parse_addr(peer)?;
+            let collection = export::import(file)?;
+            let mut db = Db::new_from_file(db_path)?;
+            println!("imported {} artists for {}", collection.artists.len(), addr);
+            db.import_collection(addr, collection);
+            db.save(db_path)?;
+        },
+    }
+    Ok(())
+}
+
+pub fn dump(filename: &str, peers: Vec<Peer>) {
+    let records: Vec<Record> = peers.into_iter()
+        .map(|peer| Record {
+            peer,
+            reputation: Reputation::new(),
+            collection: Collection::new(vec![]),
+            updated: 0,
+        })
+        .collect();
+    dump_records(filename, &records).expect("file write error");
+}
+
+fn dump_records(filename: &str, records: &[Record]) -> io::Result<()> {
+    atomic::write_atomic(filename, &format::encode(records))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv6Addr;
+    use std::net::{
+        SocketAddr,
+        IpAddr,
+    };
+    use crate::models::{ArtistData, AlbumData, TrackData};
+
+    #[test]
+    fn test_dump_and_load() {
+        let ip1 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 8000);
+        let ip2 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 2)), 8000);
+
+        let p1 = Peer::new(ip1, false, Some("TEST".into()), None, Some("ZYX987".into()));
+        let p2 = Peer::new(ip2, true, None, Some("ABC123".into()), None);
+
+        dump("/tmp/thing1.bin", vec![p1.clone(), p2.clone()]);
+        let db = Db::new_from_file("/tmp/thing1.bin").unwrap();
+        let peers = db.all_peers();
+        assert_eq!(peers, vec![p1, p2]);
+    }
+
+    #[test]
+    fn test_update_collection() {
+        let ip1 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 8000);
+        let ip2 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 2)), 8000);
+
+        let p1 = Peer::new(ip1, false, Some("TEST".into()), None, Some("ZYX987".into()));
+        let p2 = Peer::new(ip2, true, None, Some("ABC123".into()), None);
+
+        dump("/tmp/thing2.bin", vec![p1.clone(), p2.clone()]);
+        let mut db = Db::new_from_file("/tmp/thing2.bin").unwrap();
+        assert_eq!(db.get_collection(&ip1), Collection::new(vec![]));
+
+        let artist_data = ArtistData::new(
+            "test1".to_string(),
+            Some(
+                vec![
+                    AlbumData::new(
+                        Some(
+                            "test2".to_string()),
+                            "test3".to_string(),
+                            0,
+                            Some(vec![TrackData::new("test".to_string(), 12_000, 250)]),
+                        ),
+                    AlbumData::new(Some("test2".to_string()), "test3".to_string(), 0, None),
+                ]
+            ),
+        );
+        let artists_vec = vec![artist_data.clone()];
+        let collection = Collection::new(artists_vec);
+        db.update_collection(&ip1, collection.clone());
+        assert_eq!(db.all_peers().len(), 2);
+        assert_eq!(db.get_collection(&ip1), collection);
+    }
+
+    #[test]
+    fn test_add_tracks() {
+        let ip1 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 8000);
+        let p1 = Peer::new(ip1, false, Some("TEST".into()), None, Some("ZYX987".into()));
+
+        dump("/tmp/thing3.bin", vec![p1.clone(), ]);
+        let mut db = Db::new_from_file("/tmp/thing3.bin").unwrap();
+        let artist_data = ArtistData::new(
+            "first artist".to_string(),
+            Some(
+                vec![
+                    AlbumData::new(
+                        Some("first artist".to_string()),
+                        "first album".to_string(),
+                        0,
+                        None,
+                    ),
+                    AlbumData::new(
+                        Some("first artist".to_string()),
+                        "second album".to_string(),
+                        0,
+                        None,
+                    ),
+                ]
+            ),
+        );
+        let collection = Collection::new(vec![artist_data]);
+        db.update_collection(&ip1, collection.clone());
+        assert_eq!(None, db.get_collection(&ip1).artists[0].albums.as_ref().unwrap()[0].tracks);
+
+        let album_data = AlbumData::new(
+            Some("first artist".to_string()),
+            "first album".to_string(),
+            1,
+            Some(vec![TrackData::new("test".to_string(), 12_000, 250)]),
+        );
+        db.add_tracks(&ip1, album_data);
+        assert_eq!(1, db.get_collection(&ip1).artists[0].albums.as_ref().unwrap()[0].tracks.as_ref().unwrap().len());
+    }
+
+    #[test]
+    fn test_add_peers() {
+        let ip1 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 8000);
+        let p1 = Peer::new(ip1, false, Some("TEST".into()), None, Some("ZYX987".into()));
+
+        dump("/tmp/thing12.bin", vec![]);
+        let mut db = Db::new_from_file("/tmp/thing12.bin").unwrap();
+        db.add_peers(vec![p1.clone(), ]);
+        let album_data = AlbumData::new(
+            Some("first artist".to_string()),
+            "first album".to_string(),
+            1,
+            Some(vec![TrackData::new("test".to_string(), 12_000, 250)]),
+        );
+        db.add_tracks(&ip1, album_data);
+        db.add_peers(vec![p1, ]);
+        assert_eq!(1, db.get_collection(&ip1).artists[0].albums.as_ref().unwrap()[0].tracks.as_ref().unwrap().len());
+        let album_data = AlbumData::new(
+            Some("first artist".to_string()),
+            "second album".to_string(),
+            1,
+            Some(vec![TrackData::new("test".to_string(), 12_000, 250)]),
+        );
+        db.add_tracks(&ip1, album_data);
+        assert_eq!(1, db.get_collection(&ip1).artists[0].albums.as_ref().unwrap()[1].tracks.as_ref().unwrap().len());
+    }
+
+    #[test]
+    fn test_missing_file() {
+        atomic::remove("/tmp/thing5.bin").unwrap();
+        let db = Db::new_from_file("/tmp/thing5.bin").unwrap();
+        assert_eq!(db.all_peers().len(), 0);
+    }
+
+    #[test]
+    fn test_bans() {
+        let ip1 = IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1));
+        let ip2 = IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 2));
+        let mut db = Db::new();
+        db.ban(ip1, None, "test");
+        assert!(db.is_banned(&ip1));
+        assert!(!db.is_banned(&ip2));
+        assert!(!db.penalize(ip2, 1, "test"));
+        assert_eq!(db.bans().len(), 1);
+
+        // bans and offence counts outlive a restart
+        assert!(db.penalize(ip2, 1_000, "test"));
+        db.save("/tmp/thing_bans.bin").unwrap();
+        drop(db);
+        let mut db = Db::new_from_file("/tmp/thing_bans.bin").unwrap();
+        assert!(db.is_banned(&ip1));
+        assert!(db.is_banned(&ip2));
+        assert_eq!(db.bans().len(), 2);
+
+        db.unban(&ip1);
+        assert!(!db.is_banned(&ip1));
+    }
+
+    #[test]
+    fn test_reputation() {
+        let ip1 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 8000);
+        let ip2 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 2)), 8000);
+        let p1 = Peer::new(ip1, false, Some("TEST".into()), None, None);
+        let p2 = Peer::new(ip2, true, None, None, None);
+
+        let mut db = Db::new();
+        db.add_peers(vec![p1.clone(), p2.clone()]);
+        db.record_bad_chunk(&ip1);
+        db.record_answer(&ip2);
+        assert_eq!(db.ranked_peers()[0].0, p2);
+
+        // re-adding a peer keeps its history
+        db.add_peer(p1.clone(), Collection::new(vec![]));
+        assert_eq!(db.all_peers().len(), 2);
+        assert_eq!(db.get_reputation(&ip1).bad_chunks, 1);
+
+        db.save("/tmp/thing4.bin").unwrap();
+        drop(db);
+        let db = Db::new_from_file("/tmp/thing4.bin").unwrap();
+        assert_eq!(db.get_reputation(&ip1).bad_chunks, 1);
+        assert_eq!(db.get_reputation(&ip2).answered, 1);
+        assert_eq!(db.ranked_peers()[0].0, p2);
+    }
+
+    #[test]
+    fn test_download_sources() {
+        let ip1 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 8000);
+        let ip2 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 2)), 8000);
+        let ip3 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 3)), 8000);
+        let mut db = Db::new();
+        db.add_peers(vec![
+            Peer::new(ip1, false, None, None, None),
+            Peer::new(ip2, false, None, None, None),
+            Peer::new(ip3, false, None, None, None),
+        ]);
+        let album = AlbumData::new(Some("artist".to_string()), "album".to_string(), 0, None);
+        db.add_tracks(&ip1, album.clone());
+        db.add_tracks(&ip2, album);
+        db.record_answer(&ip2);
+
+        let sources: Vec<SocketAddr> = db.download_sources("artist", "album")
+            .iter()
+            .map(|p| p.address)
+            .collect();
+        assert_eq!(sources, vec![ip2, ip1]);
+
+        // a peer that stops answering pings drops to the back
+        db.record_timeout(&ip2);
+        assert_eq!(db.download_sources("artist", "album")[0].address, ip1);
+
+        let tracks = Some(vec![TrackData::new("Song".to_string(), 320, 200)]);
+        db.add_tracks(&ip3, AlbumData::new(Some("artist".to_string()), "album".to_string(), 1, tracks));
+        assert_eq!(db.download_sources("Artist", "ALBUM").len(), 3);
+        let holders = db.who_has(&ItemKey::track("artist", "album", "song"));
+        assert_eq!(holders, vec![Peer::new(ip3, false, None, None, None)]);
+
+        // replacing a collection drops what it no longer has
+        db.update_collection(&ip1, Collection::new(vec![]));
+        assert_eq!(db.download_sources("artist", "album").len(), 2);
+    }
+
+    #[test]
+    fn test_latency() {
+        let ip1 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 8000);
+        let ip2 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 2)), 8000);
+        let mut db = Db::new();
+        db.add_peers(vec![
+            Peer::new(ip1, false, None, None, None),
+            Peer::new(ip2, false, None, None, None),
+        ]);
+        let album = AlbumData::new(Some("artist".to_string()), "album".to_string(), 0, None);
+        db.add_tracks(&ip1, album.clone());
+        db.add_tracks(&ip2, album);
+        db.record_rtt(&ip1, 900.0);
+        db.record_rtt(&ip2, 20.0);
+        assert_eq!(db.download_sources("artist", "album")[0].address, ip2);
+
+        // the estimate survives the peer announcing itself again
+        db.add_peer(Peer::new(ip1, false, None, None, None), Collection::new(vec![]));
+        assert_eq!(db.get_latency(&ip1).srtt, Some(900.0));
+    }
+
+    #[test]
+    fn test_peer_moves() {
+        let ip1 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 8000);
+        let ip2 = SocketAddr::new(IpAddr::V4(std::net::Ipv4Addr::new(10, 0, 0, 2)), 8000);
+        let p1 = Peer::new(ip1, true, None, Some("ABC123".into()), None);
+        let moved = Peer::new(ip2, true, None, Some("ABC123".into()), None);
+        let id = p1.id().unwrap();
+
+        let mut db = Db::new();
+        db.add_peer(p1, Collection::new(vec![]));
+        db.record_answer(&ip1);
+        db.add_peer(moved.clone(), Collection::new(vec![]));
+
+        assert_eq!(db.all_peers(), vec![moved.clone()]);
+        assert_eq!(db.addr_of(&id), Some(ip2));
+        assert_eq!(db.peer_by_id(&id), Some(moved.clone()));
+        assert_eq!(db.get_reputation(&ip2).answered, 1);
+
+        // IPv4 addresses survive a round trip through the file
+        db.save("/tmp/thing11.bin").unwrap();
+        drop(db);
+        let db = Db::new_from_file("/tmp/thing11.bin").unwrap();
+        assert_eq!(db.peer_by_id(&id), Some(moved));
+    }
+
+    #[test]
+    fn test_shared_address() {
+        let identity = || Identity::from_pkcs8(&crate::signature::generate_bytes()).unwrap();
+        let (alice, bob) = (identity(), identity());
+        let ip1 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 8000);
+        let artists = |name: &str| Collection::new(vec![ArtistData::new(name.to_string(), None)]);
+
+        // an unsigned record is taken over by the identity at its address
+        let mut db = Db::new();
+        db.add_peer(Peer::new(ip1, false, None, None, None), artists("old"));
+        db.record_answer(&ip1);
+        db.add_peer(Peer::signed(ip1, true, None, &alice), artists("a"));
+        assert_eq!(db.all_peers().len(), 1);
+        assert_eq!(db.get_component::<Reputation, _>(&alice.peer_id()).unwrap().answered, 1);
+        assert!(db.who_has(&ItemKey::artist("old")).is_empty());
+
+        // but a second identity behind the same address is a peer of its own
+        db.add_peer(Peer::signed(ip1, true, None, &bob), artists("b"));
+        assert_eq!(db.all_peers().len(), 2);
+        assert_eq!(db.peer_by_id(&alice.peer_id()).map(|peer| peer.addr()), Some(ip1));
+        assert_eq!(db.get_component::<Reputation, _>(&bob.peer_id()).unwrap().answered, 0);
+        db.maintenance.run(&mut db.world, maintenance::INDEX_REBUILD);
+        assert_eq!(db.who_has(&ItemKey::artist("a"))[0].id(), Some(alice.peer_id()));
+        assert_eq!(db.who_has(&ItemKey::artist("b"))[0].id(), Some(bob.peer_id()));
+    }
+
+    #[test]
+    fn test_exchange_trusted() {
+        let ip1 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 8000);
+        let ip2 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 2)), 8000);
+        let ip3 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 3)), 8000);
+        let identity = || Identity::from_pkcs8(&crate::signature::generate_bytes()).unwrap();
+        let (friend_key, stranger_key, invited_key) = (identity(), identity(), identity());
+        let friend = Peer::signed(ip1, true, None, &friend_key);
+        let stranger = Peer::signed(ip2, true, None, &stranger_key);
+        // as an invite leaves it, trusted but unsigned
+        let invited = Peer::new(ip3, true, None, Some(invited_key.public_key_hex()), None);
+
+        let mut trust = TrustStore::new();
+        trust.add(&friend_key.public_key_hex(), "friend");
+        trust.add(&invited_key.public_key_hex(), "invited");
+        trust.save(&TrustStore::path_for("/tmp/thing6.bin")).unwrap();
+        dump("/tmp/thing6.bin", vec![friend.clone(), stranger.clone(), invited.clone()]);
+
+        let mut db = Db::new_from_file("/tmp/thing6.bin").unwrap();
+        assert_eq!(db.all_peers().len(), 3);
+        let mut shared = db.exchange_peers(10);
+        shared.sort_by_key(|peer| peer.addr());
+        assert_eq!(shared, vec![friend.clone(), stranger.clone()]);
+        assert_eq!(db.exchange_trusted(10), vec![friend.clone()]);
+        assert!(db.is_trusted(&friend.id().unwrap()));
+        assert!(db.is_trusted(&invited.id().unwrap()));
+        assert!(!db.is_trusted(&stranger.id().unwrap()));
+
+        // an imported collection's peer is never shared either
+        db.import_collection(SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 4)), 8000), Collection::new(vec![]));
+        assert_eq!(db.exchange_peers(10).len(), 2);
+    }
+
+    #[test]
+    fn test_manifests() {
+        use crate::identity::Identity;
+        let identity = Identity::from_pkcs8(&crate::signature::generate_bytes()).unwrap();
+        let ip1 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 8000);
+        let relay = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 9)), 8000);
+        let owner = identity.peer_id();
+        let artists = vec![ArtistData::new("artist".to_string(), None)];
+
+        let mut db = Db::new();
+        assert!(db.wants_manifest(&owner));
+        // cached for an owner we have never met
+        assert!(db.update_manifest(Manifest::signed(&identity, 1, vec![]), relay));
+        assert_eq!(db.get_provenance(&owner).unwrap().from, relay);
+        assert!(!db.wants_manifest(&owner));
+        db.manifests.get_mut(&owner).unwrap().1.received = Utc::now() - Duration::seconds(MANIFEST_REFRESH_SECS + 1);
+        assert!(db.wants_manifest(&owner));
+
+        db.add_peer(Peer::signed(ip1, true, None, &identity), Collection::new(vec![]));
+        assert!(db.update_manifest(Manifest::signed(&identity, 2, artists.clone()), ip1));
+        assert_eq!(db.get_collection(&ip1), Collection::new(artists.clone()));
+
+        // older versions never replace newer ones
+        assert!(!db.update_manifest(Manifest::signed(&identity, 1, vec![]), relay));
+        assert_eq!(db.get_manifest(&owner).unwrap().artists, artists);
+        assert_eq!(db.get_provenance(&owner).unwrap().from, ip1);
+    }
+
+    #[test]
+    fn test_key_rotation() {
+        let identity = || Identity::from_pkcs8(&crate::signature::generate_bytes()).unwrap();
+        let (old, new, thief) = (identity(), identity(), identity());
+        let ip1 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 8000);
+
+        let mut trust = TrustStore::new();
+        trust.add(&old.public_key_hex(), "friend");
+        let mut db = Db::new();
+        db.set_trust(trust);
+        db.add_peer(Peer::signed(ip1, true, None, &old), Collection::new(vec![]));
+
+        // the thief rotates first, the owner revokes and names the real successor
+        assert!(db.apply_certificate(KeyCertificate::new(KeyAction::Rotation, &old, Some(&thief))));
+        assert_eq!(db.addr_of(&thief.peer_id()), Some(ip1));
+        assert!(db.is_trusted(&thief.peer_id()));
+        assert!(!db.apply_certificate(KeyCertificate::new(KeyAction::Rotation, &old, Some(&new))));
+        assert!(db.apply_certificate(KeyCertificate::new(KeyAction::Revocation, &old, Some(&new))));
+        assert!(!db.apply_certificate(KeyCertificate::new(KeyAction::Revocation, &old, None)));
+
+        assert!(db.is_revoked(&old.peer_id()));
+        assert!(db.is_revoked(&thief.peer_id()));
+        assert!(!db.is_trusted(&thief.peer_id()));
+        assert!(!db.is_trusted(&new.peer_id()));
+        assert_eq!(db.addr_of(&old.peer_id()), None);
+        assert_eq!(db.addr_of(&new.peer_id()), None);
+
+        // revoked keys can no longer announce themselves
+        db.add_peer(Peer::signed(ip1, true, None, &old), Collection::new(vec![]));
+        assert_eq!(db.addr_of(&old.peer_id()), None);
+
+        db.save("/tmp/thing7.bin").unwrap();
+        drop(db);
+        let db = Db::new_from_file("/tmp/thing7.bin").unwrap();
+        assert_eq!(db.certificates().len(), 2);
+        assert!(db.is_revoked(&thief.peer_id()));
+    }
+
+    #[test]
+    fn test_thief_revokes_first() {
+        let identity = || Identity::from_pkcs8(&crate::signature::generate_bytes()).unwrap();
+        let (old, new, thief) = (identity(), identity(), identity());
+        let ip1 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 8000);
+
+        let mut trust = TrustStore::new();
+        trust.add(&old.public_key_hex(), "friend");
+        let mut db = Db::new();
+        db.set_trust(trust);
+        db.add_peer(Peer::signed(ip1, true, None, &old), Collection::new(vec![]));
+
+        // the thief's revocation names its own key, the owner's comes too late
+        assert!(db.apply_certificate(KeyCertificate::new(KeyAction::Revocation, &old, Some(&thief))));
+        assert!(!db.apply_certificate(KeyCertificate::new(KeyAction::Revocation, &old, Some(&new))));
+        assert!(db.is_revoked(&old.peer_id()));
+        assert!(!db.is_trusted(&old.peer_id()));
+        assert!(!db.is_trusted(&thief.peer_id()));
+        assert!(!db.is_trusted(&new.peer_id()));
+        assert_eq!(db.addr_of(&thief.peer_id()), None);
+
+        // the new key is trusted by hand
+        db.trust.add(&new.public_key_hex(), "friend");
+        assert!(db.is_trusted(&new.peer_id()));
+        assert!(!db.is_trusted(&thief.peer_id()));
+    }
+
+    #[test]
+    fn test_redeem_invite() {
+        let identity = Identity::from_pkcs8(&crate::signature::generate_bytes()).unwrap();
+        let ip1 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 8000);
+        let ip2 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 2)), 8000);
+        let invite = Invite::new(&identity, vec![ip1, ip2], Some("alice".into()), None).unwrap();
+        atomic::remove("/tmp/thing8.bin").unwrap();
+
+        let mut db = Db::new_from_file("/tmp/thing8.bin").unwrap();
+        db.redeem_invite(&invite, "alice").unwrap();
+        assert!(db.is_trusted(&identity.peer_id()));
+        assert_eq!(db.addr_of(&identity.peer_id()), Some(ip1));
+        // one record, found under either address
+        assert_eq!(db.all_peers().len(), 1);
+        assert_eq!(db.get_component::<Peer, _>(&ip2).map(|peer| peer.addr()), Some(ip1));
+
+        // the peer's own signed record keeps the invite's other addresses
+        let record = Peer::signed(ip1, true, Some("alice".into()), &identity);
+        db.add_peers(vec![record]);
+        assert_eq!(db.all_peers().len(), 1);
+        assert_eq!(db.get_component::<Peer, _>(&ip2).map(|peer| peer.addr()), Some(ip1));
+        db.flush().unwrap();
+
+        drop(db);
+        let db = Db::new_from_file("/tmp/thing8.bin").unwrap();
+        assert!(db.is_trusted(&identity.peer_id()));
+        assert_eq!(db.get_component::<Peer, _>(&ip2).map(|peer| peer.other_addresses().to_vec()), Some(vec![ip2]));
+    }
+
+    #[test]
+    fn test_offline_collections() {
+        let ip1 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 8000);
+        let ip2 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 2)), 8000);
+        let collection = Collection::new(vec![ArtistData::new(
+            "artist".to_string(),
+            Some(vec![AlbumData::new(Some("artist".to_string()), "album".to_string(), 1, None)]),
+        )]);
+        let mut db = Db::new();
+        db.add_peers(vec![
+            Peer::new(ip1, false, None, None, None),
+            Peer::new(ip2, false, None, None, None),
+        ]);
+        db.update_collection(&ip1, collection.clone());
+        let updated = db.collection_updated(&ip1);
+        assert!(updated > 0);
+
+        // announcing itself again keeps what we know of its library
+        db.add_peers(vec![Peer::new(ip1, false, None, None, None)]);
+        assert_eq!(db.get_collection(&ip1), collection);
+
+        db.save("/tmp/thing9.bin").unwrap();
+        drop(db);
+        let mut db = Db::new_from_file("/tmp/thing9.bin").unwrap();
+        assert_eq!(db.get_collection(&ip1), collection);
+        assert_eq!(db.collection_updated(&ip1), updated);
+        assert_eq!(db.collection_updated(&ip2), 0);
+        assert_eq!(db.collections().len(), 1);
+        assert_eq!(db.download_sources("artist", "album")[0].address, ip1);
+    }
+
+    #[test]
+    fn test_incremental_save() {
+        let ip1 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 8000);
+        let ip2 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 2)), 8000);
+        atomic::remove("/tmp/thing10.bin").unwrap();
+        let mut db = Db::new_from_file("/tmp/thing10.bin").unwrap();
+        db.add_peers(vec![Peer::new(ip1, false, None, None, None)]);
+        db.flush().unwrap();
+        let first = fs::metadata("/tmp/thing10.bin").unwrap().len();
+
+        // nothing changed, nothing written
+        db.flush().unwrap();
+        assert_eq!(fs::metadata("/tmp/thing10.bin").unwrap().len(), first);
+
+        db.add_peers(vec![Peer::new(ip2, false, None, None, None)]);
+        db.transfer_started(1, ip1, "artist", "album").unwrap();
+        db.transfer_started(2, ip2, "artist", "other").unwrap();
+        db.transfer_finished(2).unwrap();
+        db.flush().unwrap();
+
+        drop(db);
+        let db = Db::new_from_file("/tmp/thing10.bin").unwrap();
+        assert_eq!(db.all_peers().len(), 2);
+        assert_eq!(db.interrupted_transfers(), vec![(ip1, "artist".to_string(), "album".to_string())]);
+        // reported once
+        drop(db);
+        let db = Db::new_from_file("/tmp/thing10.bin").unwrap();
+        assert!(db.interrupted_transfers().is_empty());
+    }
+
+    #[test]
+    fn test_changed_peers() {
+        let ip1 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 8000);
+        let ip2 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 2)), 8000);
+        atomic::remove("/tmp/thing13.bin").unwrap();
+        let mut db = Db::new_from_file("/tmp/thing13.bin").unwrap();
+        db.add_peers(vec![Peer::new(ip1, false, None, None, None), Peer::new(ip2, false, None, None, None)]);
+        db.flush().unwrap();
+        let changed = |db: &mut Db| {
+            let (store, changes) = (db.store.as_ref().unwrap(), db.changes.as_mut().unwrap());
+            changes.changes(&db.world, store, &mut Batch::new()).len()
+        };
+        assert_eq!(changed(&mut db), 0);
+
+        // only the peer whose reputation moved is encoded again
+        db.record_bad_chunk(&ip1);
+        assert_eq!(changed(&mut db), 1);
+        db.flush().unwrap();
+        assert_eq!(changed(&mut db), 0);
+
+        // a newer record for the same key keeps its entries
+        db.add_peer(Peer::new(ip2, false, Some("two".into()), None, None), Collection::new(vec![]));
+        db.flush().unwrap();
+        drop(db);
+        let db = Db::new_from_file("/tmp/thing13.bin").unwrap();
+        assert_eq!(db.all_peers().len(), 2);
+        assert_eq!(db.get_reputation(&ip1).bad_chunks, 1);
+        assert_eq!(db.get_component::<Peer, _>(&ip2).unwrap().name, Some("two".to_string()));
+
+        // the node has the store open
+        assert!(matches!(Db::new_from_file("/tmp/thing13.bin"), Err(DbError::Locked(..))));
+    }
+
+    #[test]
+    fn test_collection_delta() {
+        let ip1 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 8000);
+        let a = ArtistData::new("a".to_string(), None);
+        let b = ArtistData::new("b".to_string(), None);
+        let mut db = Db::new();
+        db.add_peers(vec![Peer::new(ip1, false, None, None, None)]);
+        assert_eq!(db.collection_version(&ip1), 0);
+
+        // a delta from a version we do not hold is refused
+        let delta = CollectionDelta::between(3, &[a.clone()], 4, &[a.clone(), b.clone()]);
+        assert!(!db.update_collection_delta(&ip1, &delta));
+
+        assert!(db.update_collection_delta(&ip1, &CollectionDelta::snapshot(3, vec![a.clone()])));
+        assert!(db.update_collection_delta(&ip1, &delta));
+        assert_eq!(db.get_collection(&ip1).artists, vec![a.clone(), b.clone()]);
+        assert_eq!(db.collection_version(&ip1), 4);
+
+        assert_eq!(db.who_has(&ItemKey::artist("b")).len(), 1);
+
+        // the version survives the peer announcing itself again
+        db.add_peers(vec![Peer::new(ip1, false, None, None, None)]);
+        assert_eq!(db.collection_version(&ip1), 4);
+
+        // removals reach the index too
+        let delta = CollectionDelta::between(4, &[a.clone(), b.clone()], 5, &[a.clone()]);
+        assert!(db.update_collection_delta(&ip1, &delta));
+        assert_eq!(db.get_collection(&ip1).artists, vec![a]);
+        assert!(db.who_has(&ItemKey::artist("b")).is_empty());
+        assert_eq!(db.who_has(&ItemKey::artist("a")).len(), 1);
+    }
+
+    #[test]
+    fn test_import_collection() {
+        let ip1 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 8000);
+        let path = "/tmp/thing_import.bin";
+        let snapshot = "/tmp/thing_import.json";
+        atomic::remove(path).unwrap();
+        let collection = Collection::new(vec![ArtistData::new("artist".to_string(), None)]);
+        export::export(snapshot, &collection).unwrap();
+
+        let command = CollectionCommand::Import { file: snapshot.to_string(), peer: ip1.to_string() };
+        collection_command(path, "/nowhere", &command).unwrap();
+        let mut db = Db::new_from_file(path).unwrap();
+        assert_eq!(db.all_peers().len(), 1);
+        assert_eq!(db.get_collection(&ip1), collection);
+
+        db.import_collection(ip1, Collection::new(vec![]));
+        assert_eq!(db.all_peers().len(), 1);
+        assert_eq!(db.get_collection(&ip1), Collection::new(vec![]));
+    }
+
+    #[test]
+    fn test_connection_stats() {
+        let ip1 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 8000);
+        let ip2 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 2)), 8000);
+        let ip3 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 3)), 8000);
+        let mut db = Db::new();
+        let album = AlbumData::new(Some("artist".to_string()), "album".to_string(), 0, None);
+        for ip in &[ip1, ip2, ip3] {
+            db.add_peer(Peer::new(*ip, false, None, None, None), Collection::new(vec![]));
+            db.add_tracks(ip, album.clone());
+            db.record_seen(ip);
+        }
+        assert!(db.connection_state(&ip1).is_online());
+        assert!(db.last_seen(&ip1) > 0);
+        db.record_rtt(&ip1, 80.0);
+        db.record_rtt(&ip2, 20.0);
+        db.record_disconnected(&ip3);
+        let key = ItemKey::album("artist", "album");
+        assert_eq!(db.fastest_online(&key).iter().map(|p| p.addr()).collect::<Vec<_>>(), vec![ip2, ip1]);
+
+        // stats survive the peer being re-announced
+        db.record_traffic(&ip1, 10, 20);
+        db.add_peers(vec![Peer::new(ip1, false, None, None, None)]);
+        db.record_traffic(&ip1, 1, 2);
+        assert_eq!(db.get_traffic(&ip1), Traffic { sent: 11, received: 22 });
+        assert!(db.connection_state(&ip1).is_online());
+
+        db.transfer_started(1, ip1, "artist", "album").unwrap();
+        db.transfer_started(2, ip2, "artist", "album").unwrap();
+        db.transfer_progress(1, 512);
+        db.transfer_finished(2).unwrap();
+        let transfers = db.active_transfers();
+        assert_eq!(transfers.len(), 1);
+        assert_eq!((transfers[0].peer, transfers[0].bytes), (ip1, 512));
+    }
+
+    #[tokio::test]
+    async fn test_events() {
+        let ip1 = SocketAddr::new(IpAddr::V6(Ipv6Addr::new(0, 0, 0, 0, 0, 0, 0, 1)), 8000);
+        let mut db = Db::new();
+        let mut events = db.events().subscribe();
+        let album = AlbumData::new(Some("artist".to_string()), "album".to_string(), 0, None);
+        // unknown peers change nothing
+        db.add_tracks(&ip1, album.clone());
+
+        db.add_peers(vec![Peer::new(ip1, false, None, None, None)]);
+        db.add_tracks(&ip1, album.clone());
+        assert_eq!(events.recv().await.unwrap(), Event::CollectionUpdated(ip1));
+        assert_eq!(events.recv().await.unwrap(), Event::TracksAdded(ip1, album));
+    }
+}
@@ -0,0 +1,228 @@
+//! What we see of peers while talking to them, and the downloads in
+//! flight. Callers queue a `ConnectionEvent` and the systems here turn
+//! it into components, so questions like "which online peers with this
+//! album answer fastest" are joins over the world.
+
+use std::collections::HashMap;
+use std::net::SocketAddr;
+
+use shrev::EventChannel;
+use specs::prelude::{
+    Component, DenseVecStorage, Entities, Entity, Read, ReadExpect, ReaderId, System, SystemData, World,
+    WriteStorage,
+};
+
+use super::now_secs;
+use crate::ecs::WorldState;
+use crate::models::{Latency, Peer, PeerKey};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ConnectionEvent {
+    /// the peer sent a Ping or Pong, so it is online
+    Seen(PeerKey),
+    Disconnected(PeerKey),
+    Traffic { peer: PeerKey, sent: u64, received: u64 },
+    /// round trip of a ping, millis
+    Rtt(PeerKey, f64),
+    PingTimeout(PeerKey),
+    TransferStarted { id: u64, peer: SocketAddr, artist: String, album: String },
+    TransferProgress { id: u64, bytes: u64 },
+    TransferFinished(u64),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ConnectionState {
+    Offline,
+    /// connected since, unix seconds
+    Online(u64),
+}
+
+impl ConnectionState {
+    pub fn is_online(&self) -> bool {
+        match self {
+            ConnectionState::Online(_) => true,
+            ConnectionState::Offline => false,
+        }
+    }
+}
+
+impl Default for ConnectionState {
+    fn default() -> Self {
+        ConnectionState::Offline
+    }
+}
+
+impl Component for ConnectionState {
+    type Storage = DenseVecStorage<Self>;
+}
+
+/// bytes exchanged with the peer on all connections this run
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Traffic {
+    pub sent: u64,
+    pub received: u64,
+}
+
+impl Component for Traffic {
+    type Storage = DenseVecStorage<Self>;
+}
+
+/// when the peer last pinged or answered, unix seconds, 0 if never
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LastSeen(pub u64);
+
+impl Component for LastSeen {
+    type Storage = DenseVecStorage<Self>;
+}
+
+/// A download in flight, an entity of its own.
+#[derive(Clone, Debug, PartialEq)]
+pub struct ActiveTransfer {
+    pub id: u64,
+    pub peer: SocketAddr,
+    pub artist: String,
+    pub album: String,
+    pub bytes: u64,
+    /// unix seconds
+    pub started: u64,
+}
+
+impl Component for ActiveTransfer {
+    type Storage = DenseVecStorage<Self>;
+}
+
+/// Keeps `ConnectionState`, `LastSeen`, `Traffic` and `Latency` of known
+/// peers up to date. Events about peers we have no entity for are dropped.
+pub struct PeerStatsSystem {
+    reader: ReaderId<ConnectionEvent>,
+}
+
+impl PeerStatsSystem {
+    pub fn new(world: &mut World) -> Self {
+        <Self as System<'_>>::SystemData::setup(world);
+        let reader = world.fetch_mut::<EventChannel<ConnectionEvent>>().register_reader();
+        PeerStatsSystem { reader }
+    }
+}
+
+impl<'a> System<'a> for PeerStatsSystem {
+    type SystemData = (
+        Read<'a, EventChannel<ConnectionEvent>>,
+        ReadExpect<'a, WorldState<Peer>>,
+        WriteStorage<'a, ConnectionState>,
+        WriteStorage<'a, LastSeen>,
+        WriteStorage<'a, Traffic>,
+        WriteStorage<'a, Latency>,
+    );
+
+    fn run(&mut self, (events, peers, mut states, mut seen, mut traffic, mut latencies): Self::SystemData) {
+        for event in events.read(&mut self.reader) {
+            let key = match event {
+                ConnectionEvent::Seen(key)
+                | ConnectionEvent::Disconnected(key)
+                | ConnectionEvent::Traffic { peer: key, .. }
+                | ConnectionEvent::Rtt(key, _)
+                | ConnectionEvent::PingTimeout(key) => key,
+                _ => continue,
+            };
+            let entity = match peers.get_entity(key) {
+                Some(entity) => entity,
+                None => continue,
+            };
+            match event {
+                ConnectionEvent::Seen(_) => {
+                    let now = now_secs();
+                    if let Some(state) = states.get_mut(entity) {
+                        if !state.is_online() {
+                            *state = ConnectionState::Online(now);
+                        }
+                    }
+                    if let Some(seen) = seen.get_mut(entity) {
+                        seen.0 = now;
+                    }
+                },
+                ConnectionEvent::Disconnected(_) => {
+                    if let Some(state) = states.get_mut(entity) {
+                        *state = ConnectionState::Offline;
+                    }
+                },
+                ConnectionEvent::Traffic { sent, received, .. } => {
+                    if let Some(traffic) = traffic.get_mut(entity) {
+                        traffic.sent += sent;
+                        traffic.received += received;
+                    }
+                },
+                ConnectionEvent::Rtt(_, millis) => {
+                    if let Some(latency) = latencies.get_mut(entity) {
+                        latency.sample(*millis);
+                    }
+                },
+                ConnectionEvent::PingTimeout(_) => {
+                    if let Some(latency) = latencies.get_mut(entity) {
+                        latency.timed_out();
+                    }
+                },
+                _ => {},
+            }
+        }
+    }
+}
+
+/// Creates an `ActiveTransfer` entity per download and deletes it once
+/// the download finishes.
+pub struct TransferSystem {
+    reader: ReaderId<ConnectionEvent>,
+    by_id: HashMap<u64, Entity>,
+}
+
+impl TransferSystem {
+    pub fn new(world: &mut World) -> Self {
+        <Self as System<'_>>::SystemData::setup(world);
+        let reader = world.fetch_mut::<EventChannel<ConnectionEvent>>().register_reader();
+        TransferSystem {
+            reader,
+            by_id: HashMap::new(),
+        }
+    }
+}
+
+impl<'a> System<'a> for TransferSystem {
+    type SystemData = (
+        Entities<'a>,
+        Read<'a, EventChannel<ConnectionEvent>>,
+        WriteStorage<'a, ActiveTransfer>,
+    );
+
+    fn run(&mut self, (entities, events, mut transfers): Self::SystemData) {
+        for event in events.read(&mut self.reader) {
+            match event {
+                ConnectionEvent::TransferStarted { id, peer, artist, album } => {
+                    let transfer = ActiveTransfer {
+                        id: *id,
+                        peer: *peer,
+                        artist: artist.clone(),
+                        album: album.clone(),
+                        bytes: 0,
+                        started: now_secs(),
+                    };
+                    let entity = entities.build_entity().with(transfer, &mut transfers).build();
+                    if let Some(old) = self.by_id.insert(*id, entity) {
+                        let _ = entities.delete(old);
+                    }
+                },
+                ConnectionEvent::TransferProgress { id, bytes } => {
+                    let transfer = self.by_id.get(id).and_then(|entity| transfers.get_mut(*entity));
+                    if let Some(transfer) = transfer {
+                        transfer.bytes += bytes;
+                    }
+                },
+                ConnectionEvent::TransferFinished(id) => {
+                    if let Some(entity) = self.by_id.remove(id) {
+                        let _ = entities.delete(entity);
+                    }
+                },
+                _ => {},
+            }
+        }
+    }
+}
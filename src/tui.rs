@@ -1,31 +1,36 @@
-use std::sync::Arc;
-
 use cursive::Cursive;
+use cursive::event::Event;
 use cursive::traits::*;
 use cursive::align::HAlign;
 use cursive::views::{BoxView, Dialog, SelectView};
 
-use tokio::sync::Mutex;
-
 pub use crate::models::{ArtistData, Peer, Service};
 
-pub fn run_tui(state: Arc<Mutex<Service>>) {
+/// Blocks until the user quits or the node starts shutting down.
+pub fn run_tui(service: Service) {
+    let shutdown = service.shutdown.clone();
     let mut siv = Cursive::default();
     siv.add_global_callback('q', |s| s.quit());
+    siv.set_fps(4);
+    siv.add_global_callback(Event::Refresh, move |s| {
+        if shutdown.is_triggered() {
+            s.quit();
+        }
+    });
     siv.add_layer(Dialog::text("Select Option")
         .title("Welcome")
-        .button("Next", move |s| main_menu(s, Arc::clone(&state))));
+        .button("Next", move |s| main_menu(s, service.clone())));
     siv.run();
 }
 
-fn main_menu(s: &mut Cursive, state: Arc<Mutex<Service>>) {
+fn main_menu(s: &mut Cursive, service: Service) {
     s.pop_layer();
     let mut select = SelectView::<String>::new()
         .h_align(HAlign::Center)
         .autojump();
     let content = "peers\nlibrary\n";
     select.add_all_str(content.lines());
-    select.set_on_submit(move |s, m| select_submenu(s, m, Arc::clone(&state)));
+    select.set_on_submit(move |s, m| select_submenu(s, m, service.clone()));
     let box_select = BoxView::with_fixed_size((20, 10), select);
     s.add_layer(Dialog::around(box_select.scrollable())
         .h_align(HAlign::Center)
@@ -33,29 +38,33 @@ fn main_menu(s: &mut Cursive, state: Arc<Mutex<Service>>) {
     );
 }
 
-fn select_submenu(s: &mut Cursive, m: &str, state: Arc<Mutex<Service>>) {
+fn select_submenu(s: &mut Cursive, m: &str, service: Service) {
     if m == "library" {
-        let collection = futures::executor::block_on(get_collection(state));
+        let collection = futures::executor::block_on(get_collection(service));
         show_collection(s, collection);
     } else {
-        let peers = futures::executor::block_on(get_peers(state));
+        let peers = futures::executor::block_on(get_ranked_peers(service));
         show_peers(s, peers);
     }
 }
 
-fn show_peers(s: &mut Cursive, peers: Vec<Peer>) {
+fn show_peers(s: &mut Cursive, peers: Vec<(Peer, f64)>) {
     let mut select = SelectView::new()
         .h_align(HAlign::Center)
         .autojump();
     let mut content = String::new();
-    for peer in &peers {
-        content.push_str(peer.name.as_ref().unwrap_or(&"unk".to_string()));
+    for (peer, score) in &peers {
+        content.push_str(&format!(
+            "{} ({:.0})",
+            peer.name.as_ref().unwrap_or(&"unk".to_string()),
+            score,
+        ));
         content.push_str("\n");
     }
     select.add_all_str(content.lines());
     // select.set_on_submit(move |s, m| show_albums(s, m, collection.clone()));
     s.add_layer(
-        Dialog::around(select.scrollable().fixed_size((20, 10)))
+        Dialog::around(select.scrollable().fixed_size((30, 10)))
             .title("Peers")
             .button("Back", |s| {s.pop_layer();})
     );
@@ -137,12 +146,16 @@ fn show_tracks(s: &mut Cursive, artist_name: &str, album_title: &str, collection
     );
 }
 
-async fn get_collection(state: Arc<Mutex<Service>>) -> Vec<ArtistData> {
-    let s = state.lock().await;
-    s.get_collection(true, None, None)
+async fn get_collection(service: Service) -> Vec<ArtistData> {
+    service.library
+        .call(|library| library.get_collection(true, None, None))
+        .await
+        .unwrap_or_default()
 }
 
-async fn get_peers(state: Arc<Mutex<Service>>) -> Vec<Peer> {
-    let s = state.lock().await;
-    s.get_peers()
+async fn get_ranked_peers(service: Service) -> Vec<(Peer, f64)> {
+    service.database
+        .call(|db| db.ranked_peers())
+        .await
+        .unwrap_or_default()
 }